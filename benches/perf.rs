@@ -1,5 +1,6 @@
-// use rfalse::falselang::vm::{FalseVM, StackElement};
+use rfalse::falselang::interpreter::Engine;
 use rfalse::falselang::vm2::FalseVM;
+use rfalse::falselang::threaded::ThreadedCode;
 use criterion::{criterion_group, criterion_main, Criterion};
 
 fn perf() {
@@ -12,18 +13,40 @@ fn perf() {
 	// vm.load("99b:\n[b;0=[\"No more bottles of beer\"]?b;1=[\"1 more bottle of beer\"]?b;1>[b;.\" bottles of beer\"]?]a:\n[b;0>][a;!\" on the wall\"10,a;!10,\"Take one down, pass it around\"10,b;1-b:a;!\" on the wall\n\"]#");
 	// vm.load("^^^,,,");
 	// vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:       33 f;!  {compute 33th fibonacci number}");
-	vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:       12 f;!  {compute 12th fibonacci number}");
+	vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:       12 f;!  {compute 12th fibonacci number}", 0).unwrap();
 	// vm.load("99 9[1-$][\\$@$@$@$@\\/*=[1-$$[%\\1-$@]?0=[\\$.' ,\\]?]?]#");
 
-	// vm.verbose = true;
-	vm.run();
+	vm.run().unwrap();
 	// assert_eq!(vm.stack, vec![StackElement::Number(3524578)]);
 	// assert_eq!(vm.stack, vec![StackElement::Number(3628800)]);
 	// assert_eq!(vm.stack, vec![StackElement::Number(144)]);
 }
 
+fn perf_threaded() {
+	let mut vm = FalseVM::new();
+	vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:       12 f;!  {compute 12th fibonacci number}", 0).unwrap();
+	ThreadedCode::new(&vm).run(&mut vm).unwrap();
+}
+
+fn perf_cached() {
+	let mut vm = FalseVM::new();
+	vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:       12 f;!  {compute 12th fibonacci number}", 0).unwrap();
+	ThreadedCode::new(&vm).run_cached(&mut vm).unwrap();
+}
+
+fn perf_engine(engine: Engine) {
+	let mut vm = engine.create();
+	vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:       12 f;!  {compute 12th fibonacci number}", 0).unwrap();
+	vm.run().unwrap();
+}
+
 fn perf_benchmark(c: &mut Criterion) {
-	c.bench_function("perf", |b| b.iter(|| perf()));
+	c.bench_function("perf", |b| b.iter(perf));
+	c.bench_function("perf_threaded", |b| b.iter(perf_threaded));
+	c.bench_function("perf_cached", |b| b.iter(perf_cached));
+	for engine in Engine::ALL {
+		c.bench_function(&format!("perf_{}", engine.name()), |b| b.iter(|| perf_engine(engine)));
+	}
 }
 
 criterion_group!(benches, perf_benchmark);
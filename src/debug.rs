@@ -0,0 +1,320 @@
+use rfalse::falselang::debugger::{Breakpoint, Debugger, Pause};
+use rfalse::falselang::error::{line_col, Error};
+use rfalse::falselang::vm;
+use std::io::{self, Read, Write};
+
+use crate::repl::{show, show_stack, Output};
+use crate::{failure, Options};
+
+
+const HELP: &str = "\
+The program is paused before the token shown. An empty line repeats the last command.
+The steps are recorded, so the program can also go back.
+
+s, step         run one token, stop at the start of a lambda that ! ? or # runs
+n, next         run one token, including the lambdas it runs
+o, out          run until the current lambda returns, or its # loop ends
+c, continue     run until a breakpoint or the end
+r, rstep        go back one token
+rc, rcontinue   go back to the last breakpoint
+rw VAR          go back to just before VAR last changed
+rd N            go back to where the stack last held N values
+b               list the breakpoints
+b LINE[:COL]    stop at the first token at or after LINE:COL
+b VAR           stop on entering the lambda stored in VAR
+d N             delete breakpoint N
+stack           show the stack
+vars            show the variables
+e CODE          run CODE on the stack and variables, the program stays where it is,
+                the recording starts again after it
+w, where        show where the program is
+h, help         show this help
+q, quit         leave, so does end of input";
+
+/// `rfalse debug`: a `Debugger` driven by commands read line by line.
+pub struct Session {
+	debugger: Debugger,
+	name: String, // file name for messages
+	output: Output,
+	last: String, // last command, repeated by an empty line
+}
+
+impl Session {
+	pub fn new(options: &Options, name: &str, source: &str, input: Box<dyn Read>, output: Box<dyn Write>) -> Result<Session, Error> {
+		let output = Output::new(output);
+		let mut vm = vm::FalseVM::new();
+		vm.dialect = options.dialect;
+		vm.fuel = options.limits.steps;
+		vm.max_stack = options.limits.stack;
+		vm.input = input;
+		vm.output = Box::new(output.clone());
+		let debugger = Debugger::new(vm, source)?;
+		Ok(Session { debugger, name: name.to_string(), output, last: String::new() })
+	}
+
+	/// Reads commands with `read_line` until it returns 0 or `quit` is entered.
+	pub fn run(&mut self, mut read_line: impl FnMut(&mut String) -> io::Result<usize>) -> io::Result<()> {
+		self.show_position()?;
+		loop {
+			write!(self.output, "(debug) ")?;
+			self.output.flush()?;
+			let mut line = String::new();
+			if read_line(&mut line)? == 0 {
+				break;
+			}
+			self.output.set_line_start();
+			let line = match line.trim() {
+				"" => self.last.clone(),
+				line => line.to_string(),
+			};
+			self.last.clone_from(&line);
+			if !self.command(&line)? {
+				return Ok(());
+			}
+		}
+		self.output.end_line()
+	}
+
+	/// Runs one command, returns false for `quit`.
+	fn command(&mut self, line: &str) -> io::Result<bool> {
+		let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+		let arg = arg.trim();
+		let pause = match name {
+			"s" | "step" => self.debugger.step(),
+			"n" | "next" => self.debugger.step_over(),
+			"o" | "out" => self.debugger.step_out(),
+			"c" | "continue" => self.debugger.continue_(),
+			"r" | "rstep" => self.debugger.step_back(),
+			"rc" | "rcontinue" => self.debugger.reverse_continue(),
+			"rw" => {
+				let mut chars = arg.chars();
+				match (chars.next(), chars.next()) {
+					(Some(v @ 'a'..='z'), None) => self.debugger.back_to_change(v),
+					_ => {
+						writeln!(self.output, "expected a variable")?;
+						return Ok(true);
+					}
+				}
+			}
+			"rd" => match arg.parse() {
+				Ok(depth) => self.debugger.back_to_depth(depth),
+				Err(_) => {
+					writeln!(self.output, "expected a stack depth")?;
+					return Ok(true);
+				}
+			},
+			"b" | "break" => {
+				self.breakpoint(arg)?;
+				return Ok(true);
+			}
+			"d" | "delete" => {
+				match arg.parse::<usize>() {
+					Ok(n) if n < self.debugger.breakpoints.len() => {
+						self.debugger.breakpoints.remove(n);
+					}
+					_ => writeln!(self.output, "no breakpoint {:?}, see b", arg)?,
+				}
+				return Ok(true);
+			}
+			"stack" => {
+				writeln!(self.output, "[{}]", show_stack(&self.debugger.vm.stack))?;
+				return Ok(true);
+			}
+			"vars" => {
+				let mut vars: Vec<_> = self.debugger.vm.variables.iter().collect();
+				vars.sort_by_key(|(name, _)| **name);
+				for (name, value) in vars {
+					writeln!(self.output, "{} = {}", name, show(value))?;
+				}
+				return Ok(true);
+			}
+			"e" | "eval" => {
+				let result = self.debugger.eval(arg);
+				self.output.flush()?;
+				self.output.end_line()?;
+				match result {
+					Ok(()) => writeln!(self.output, "[{}]", show_stack(&self.debugger.vm.stack))?,
+					Err(e) => writeln!(self.output, "{}", failure(e, "eval", arg).message)?,
+				}
+				return Ok(true);
+			}
+			"w" | "where" => {
+				self.show_position()?;
+				return Ok(true);
+			}
+			"h" | "help" => {
+				writeln!(self.output, "{}", HELP)?;
+				return Ok(true);
+			}
+			"q" | "quit" => return Ok(false),
+			"" => return Ok(true),
+			_ => {
+				writeln!(self.output, "unknown command {}, see help", name)?;
+				return Ok(true);
+			}
+		};
+
+		self.output.flush()?;
+		self.output.end_line()?;
+		match pause {
+			Ok(Pause::Step) => {}
+			Ok(Pause::Breakpoint(n)) => writeln!(self.output, "breakpoint {}", n)?,
+			Ok(Pause::End) => {
+				writeln!(self.output, "the program has ended, the stack is [{}]", show_stack(&self.debugger.vm.stack))?;
+				return Ok(true);
+			}
+			Ok(Pause::Start) => writeln!(self.output, "at the start of the recording")?,
+			Err(e) => writeln!(self.output, "{}", failure(e, &self.name, &self.debugger.source).message)?,
+		}
+		self.show_position()?;
+		Ok(true)
+	}
+
+	/// `b` with `arg`: lists the breakpoints, or adds one.
+	fn breakpoint(&mut self, arg: &str) -> io::Result<()> {
+		if arg.is_empty() {
+			for (n, breakpoint) in self.debugger.breakpoints.iter().enumerate() {
+				let place = match breakpoint {
+					Breakpoint::Token { fn_index, cursor } => match self.debugger.span(*fn_index, *cursor) {
+						Some(pos) => format!("at {}", self.place(pos)),
+						None => "in evaluated code".to_string(),
+					},
+					Breakpoint::Lambda(v) => format!("on entering {}", v),
+				};
+				writeln!(self.output, "{}: {}", n, place)?;
+			}
+			return Ok(());
+		}
+
+		let mut chars = arg.chars();
+		if let (Some(v @ 'a'..='z'), None) = (chars.next(), chars.next()) {
+			let n = self.debugger.break_on(v);
+			return writeln!(self.output, "breakpoint {} on entering {}", n, v);
+		}
+		let (line, col) = arg.split_once(':').unwrap_or((arg, "1"));
+		let result = match (line.parse(), col.parse()) {
+			(Ok(line), Ok(col)) => self.debugger.break_at(line, col),
+			_ => Err("expected LINE[:COL] or a variable".to_string()),
+		};
+		match result {
+			Ok(n) => match self.debugger.breakpoints[n] {
+				Breakpoint::Token { fn_index, cursor } => {
+					let pos = self.debugger.span(fn_index, cursor).unwrap_or_default();
+					writeln!(self.output, "breakpoint {} at {}", n, self.place(pos))
+				}
+				Breakpoint::Lambda(_) => unreachable!("break_at adds token breakpoints"),
+			},
+			Err(message) => writeln!(self.output, "{}", message),
+		}
+	}
+
+	/// `line:col` of char offset `pos`.
+	fn place(&self, pos: usize) -> String {
+		let (line, col) = line_col(&self.debugger.source, pos);
+		format!("{}:{}", line, col)
+	}
+
+	/// Prints the source line the program is at, with a mark under the next token.
+	fn show_position(&mut self) -> io::Result<()> {
+		let Some(pos) = self.debugger.position() else {
+			return writeln!(self.output, "in evaluated code");
+		};
+		let (line, col) = line_col(&self.debugger.source, pos);
+		let text = self.debugger.source.split('\n').nth(line - 1).unwrap_or_default().trim_end_matches('\r');
+		let prefix = format!("{}:{}  ", line, col);
+		let indent: String = text.chars().take(col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+		writeln!(self.output, "{}{}", prefix, text)?;
+		writeln!(self.output, "{}{}^", " ".repeat(prefix.len()), indent)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parse_args;
+	use rfalse::falselang::interpreter::Capture;
+	use std::io::BufRead;
+
+	/// Everything the debugger writes for `commands`, with the prompts left out.
+	fn session(code: &str, commands: &str) -> String {
+		let options = parse_args(&["debug".to_string(), "-e".to_string(), code.to_string()]).unwrap().unwrap();
+		let out = Capture::default();
+		let mut session = Session::new(&options, "prog", code, Box::new(io::Cursor::new(b"xy".to_vec())), Box::new(out.clone())).unwrap();
+		let mut lines = io::Cursor::new(commands.as_bytes().to_vec());
+		session.run(|line| lines.read_line(line)).unwrap();
+		let bytes = out.bytes().to_vec();
+		String::from_utf8(bytes).unwrap().replace("(debug) ", "")
+	}
+
+	#[test]
+	fn test_stepping() {
+		assert_eq!(session("1 [2 3]!\n4.", "s\n\ns\nstack\no\nn\nn\nn\n"), concat!(
+			"1:1  1 [2 3]!\n     ^\n",
+			"1:3  1 [2 3]!\n       ^\n",
+			"1:8  1 [2 3]!\n            ^\n",
+			"1:4  1 [2 3]!\n        ^\n",
+			"[1]\n",
+			"2:1  4.\n     ^\n",
+			"2:2  4.\n      ^\n",
+			"4\n2:3  4.\n       ^\n",
+			"the program has ended, the stack is [1 2 3]\n\n",
+		));
+	}
+
+	#[test]
+	fn test_breakpoints() {
+		let code = "[$1=$[\\%1\\]?~[$1-f;!*]?]f:\n3f;!\n.";
+		let out = session(code, "b 2\nb f\nb 9:1\nb x1\nb\nc\nc\nd 0\nd 5\nc\nvars\ne 10*\nc\nq\n");
+		assert_eq!(out, concat!(
+			"1:1  [$1=$[\\%1\\]?~[$1-f;!*]?]f:\n     ^\n",
+			"breakpoint 0 at 2:1\n",
+			"breakpoint 1 on entering f\n",
+			"there is no line 9\n",
+			"expected LINE[:COL] or a variable\n",
+			"0: at 2:1\n1: on entering f\n",
+			"breakpoint 0\n2:1  3f;!\n     ^\n",
+			"breakpoint 1\n1:2  [$1=$[\\%1\\]?~[$1-f;!*]?]f:\n      ^\n",
+			"no breakpoint \"5\", see b\n",
+			"breakpoint 0\n1:2  [$1=$[\\%1\\]?~[$1-f;!*]?]f:\n      ^\n",
+			"f = [fn2]\n",
+			"[3 20]\n",
+			"breakpoint 0\n1:2  [$1=$[\\%1\\]?~[$1-f;!*]?]f:\n      ^\n",
+		));
+	}
+
+	#[test]
+	fn test_going_back() {
+		let code = "0i:[i;3>~][i;1+i:]#\ni;.";
+		let start = "1:1  0i:[i;3>~][i;1+i:]#\n     ^\n";
+		assert_eq!(session(code, "r\nc\nrw i\nvars\nrd 2\nstack\nrw x\nr\nb 1:12\nc\nc\nrc\nvars\nrw\nrd x\n"), [
+			start,
+			"at the start of the recording\n", start,
+			"4\nthe program has ended, the stack is []\n",
+			"1:17  0i:[i;3>~][i;1+i:]#\n                      ^\n",
+			"i = 3\n",
+			"1:15  0i:[i;3>~][i;1+i:]#\n                    ^\n",
+			"[3 1]\n",
+			"at the start of the recording\n", start,
+			"at the start of the recording\n", start,
+			"breakpoint 0 at 1:12\n",
+			"breakpoint 0\n1:12  0i:[i;3>~][i;1+i:]#\n                 ^\n",
+			"breakpoint 0\n1:12  0i:[i;3>~][i;1+i:]#\n                 ^\n",
+			"breakpoint 0\n1:12  0i:[i;3>~][i;1+i:]#\n                 ^\n",
+			"i = 0\n",
+			"expected a variable\n",
+			"expected a stack depth\n\n",
+		].concat());
+	}
+
+	#[test]
+	fn test_errors() {
+		assert_eq!(session("1 0/ 2", "n\nn\nn\nw\nh\nfoo\n"), concat!(
+			"1:1  1 0/ 2\n     ^\n",
+			"1:3  1 0/ 2\n       ^\n",
+			"1:4  1 0/ 2\n        ^\n",
+			"prog: runtime error: division by zero\n1:4  1 0/ 2\n        ^\n",
+			"1:4  1 0/ 2\n        ^\n",
+		).to_string() + HELP + "\nunknown command foo, see help\n\n");
+	}
+}
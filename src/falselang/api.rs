@@ -0,0 +1,371 @@
+use super::error::{line_col, Error};
+use super::interpreter::{Capture, Engine, Interpreter, Limits, StepResult};
+use super::tokenizer::{Dialect, Token, Tokenizer};
+use super::trace::{json_string, json_value};
+use super::vm::{self, StackElement};
+use super::vm2;
+
+
+/// Steps `run` and `Session` allow when `RunOptions::limits` sets none, so that a program that
+/// never ends fails with `fuel` after a few seconds instead of blocking the caller. All of
+/// `examples/` fit, the fibonacci one takes about 150 million.
+pub const DEFAULT_STEPS: u64 = 200_000_000;
+
+/// How `run` runs a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunOptions {
+	pub engine: Engine,
+	pub dialect: Dialect,
+	pub opt_level: u8, // see `vm2::FalseVM::load`, bytecode only
+	pub limits: Limits,
+}
+
+impl Default for RunOptions {
+	fn default() -> Self {
+		RunOptions { engine: Engine::Tree, dialect: Dialect::Rfalse, opt_level: 0, limits: Limits::default() }
+	}
+}
+
+impl RunOptions {
+	/// The step limit, `DEFAULT_STEPS` if `limits` has none.
+	pub fn steps(&self) -> u64 {
+		self.limits.steps.unwrap_or(DEFAULT_STEPS)
+	}
+}
+
+/// An `Error` with the place in the source it belongs to, if there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+	/// `syntax`, `runtime` or `fuel`.
+	pub kind: &'static str,
+	pub message: String,
+	/// Char offset in the source: where the syntax error is or the token that failed.
+	pub pos: Option<usize>,
+}
+
+impl Problem {
+	fn new(error: Error, pos: Option<usize>) -> Problem {
+		match error {
+			Error::Syntax { pos, message, .. } => Problem { kind: "syntax", message, pos: Some(pos) },
+			Error::Runtime(message) => Problem { kind: "runtime", message, pos },
+			Error::OutOfFuel => Problem { kind: "fuel", message: "out of fuel".to_string(), pos },
+		}
+	}
+
+	/// `{"kind":…,"message":…,"pos":…,"line":…,"col":…}`, the last three are `null` without a position.
+	pub fn to_json(&self, source: &str) -> String {
+		format!("{{\"kind\":\"{}\",\"message\":{},{}}}", self.kind, json_string(&self.message), place(source, self.pos))
+	}
+}
+
+/// `"pos":…,"line":…,"col":…` for char offset `pos` in `source`, all `null` without one.
+fn place(source: &str, pos: Option<usize>) -> String {
+	match pos {
+		Some(pos) => {
+			let (line, col) = line_col(source, pos);
+			format!("\"pos\":{},\"line\":{},\"col\":{}", pos, line, col)
+		}
+		None => "\"pos\":null,\"line\":null,\"col\":null".to_string(),
+	}
+}
+
+/// What `run` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunResult {
+	/// Everything the program wrote, invalid UTF-8 is replaced.
+	pub output: String,
+	/// Bottom first.
+	pub stack: Vec<StackElement>,
+	/// The variables that were set. The bytecode engine can't tell 0 from unset and leaves those out.
+	pub variables: Vec<(char, StackElement)>,
+	/// Steps the engine took. The bytecode engine counts a superinstruction as one.
+	pub steps: u64,
+	pub error: Option<Problem>,
+}
+
+impl RunResult {
+	/// `{"output":…,"stack":[…],"variables":{…},"steps":…,"error":…}`, values as in `--trace`, the
+	/// error as in `Problem::to_json` or `null`.
+	pub fn to_json(&self, source: &str) -> String {
+		format!("{{{}}}", self.fields(source))
+	}
+
+	/// The members of `to_json` without the braces, for objects that add more.
+	fn fields(&self, source: &str) -> String {
+		let stack: Vec<String> = self.stack.iter().map(json_value).collect();
+		let variables: Vec<String> = self.variables.iter().map(|(name, x)| format!("\"{}\":{}", name, json_value(x))).collect();
+		let error = self.error.as_ref().map_or("null".to_string(), |e| e.to_json(source));
+		format!(
+			"\"output\":{},\"stack\":[{}],\"variables\":{{{}}},\"steps\":{},\"error\":{}",
+			json_string(&self.output), stack.join(","), variables.join(","), self.steps, error
+		)
+	}
+}
+
+/// One of the engines, for what `Interpreter` doesn't cover.
+enum Machine {
+	Tree(vm::FalseVM),
+	Bytecode(vm2::FalseVM),
+}
+
+impl Machine {
+	/// A VM for `options` that reads `input` and writes to `output`, with fuel for
+	/// `RunOptions::steps`, which also counts the steps.
+	fn new(input: &[u8], output: Capture, options: &RunOptions) -> Machine {
+		let mut machine = match options.engine {
+			Engine::Tree => Machine::Tree(vm::FalseVM::new()),
+			Engine::Bytecode => Machine::Bytecode(vm2::FalseVM::new()),
+		};
+		let vm = machine.vm();
+		vm.set_dialect(options.dialect);
+		vm.set_limits(Limits { steps: Some(options.steps()), ..options.limits });
+		vm.set_io(Box::new(std::io::Cursor::new(input.to_vec())), Box::new(output));
+		machine
+	}
+
+	fn vm(&mut self) -> &mut dyn Interpreter {
+		match self {
+			Machine::Tree(vm) => vm,
+			Machine::Bytecode(vm) => vm,
+		}
+	}
+
+	fn fuel(&self) -> Option<u64> {
+		match self {
+			Machine::Tree(vm) => vm.fuel,
+			Machine::Bytecode(vm) => vm.fuel,
+		}
+	}
+
+	fn stack(&self) -> Vec<StackElement> {
+		match self {
+			Machine::Tree(vm) => vm.stack.clone(),
+			Machine::Bytecode(vm) => vm.data_stack(),
+		}
+	}
+
+	/// Char offset of the token that runs next, or that failed.
+	fn position(&self) -> Option<usize> {
+		match self {
+			Machine::Tree(vm) => vm.spans.get(vm.fn_index).and_then(|spans| spans.get(vm.cursor)).copied(),
+			Machine::Bytecode(vm) => vm.spans.get(&vm.cursor).and_then(|spans| spans.iter().min()).copied(),
+		}
+	}
+
+	/// The variables that were set. The bytecode engine can't tell 0 from unset and leaves those out.
+	fn variables(&self) -> Vec<(char, StackElement)> {
+		match self {
+			Machine::Tree(vm) => {
+				let mut variables: Vec<_> = vm.variables.iter().map(|(name, x)| (*name, x.clone())).collect();
+				variables.sort_by_key(|(name, _)| *name);
+				variables
+			}
+			Machine::Bytecode(vm) => ('a'..='z').filter_map(|name| vm.variable(name).map(|x| (name, x)))
+				.filter(|(_, x)| *x != StackElement::Number(0))
+				.collect(),
+		}
+	}
+}
+
+/// Runs `code` to the end with `input` for `^`. Failing isn't an error here, it is part of the result.
+pub fn run(code: &str, input: &[u8], options: &RunOptions) -> RunResult {
+	let output = Capture::default();
+	let mut machine = Machine::new(input, output.clone(), options);
+	let vm = machine.vm();
+	let result = vm.load(code, options.opt_level).and_then(|_| vm.run());
+	let fuel = options.steps();
+	let output = output.text();
+	RunResult {
+		output,
+		stack: machine.stack(),
+		variables: machine.variables(),
+		steps: fuel - machine.fuel().unwrap_or(fuel),
+		error: result.err().map(|e| Problem::new(e, machine.position())),
+	}
+}
+
+/// A program that runs a few steps at a time, for showing its state while it runs.
+pub struct Session {
+	source: String,
+	machine: Machine,
+	output: Capture,
+	shown: usize, // bytes of output that `state` returned already
+	steps: u64,
+	done: bool,
+	error: Option<Problem>,
+}
+
+impl Session {
+	/// Loads `code`, which fails on a syntax error.
+	pub fn new(code: &str, input: &[u8], options: &RunOptions) -> Result<Session, Problem> {
+		let output = Capture::default();
+		let mut machine = Machine::new(input, output.clone(), options);
+		machine.vm().load(code, options.opt_level).map_err(|e| Problem::new(e, None))?;
+		Ok(Session { source: code.to_string(), machine, output, shown: 0, steps: 0, done: false, error: None })
+	}
+
+	/// Runs up to `n` steps, less if the program ends or fails. Returns whether it can go on.
+	pub fn step(&mut self, n: u64) -> bool {
+		for _ in 0..n {
+			if self.done {
+				break;
+			}
+			match self.machine.vm().step() {
+				Ok(StepResult::OK) => self.steps += 1,
+				Ok(StepResult::End) => self.done = true,
+				Err(e) => {
+					self.error = Some(Problem::new(e, self.machine.position()));
+					self.done = true;
+				}
+			}
+		}
+		!self.done
+	}
+
+	/// `{"output":…,"stack":[…],"variables":{…},"steps":…,"error":…,"done":…,"pos":…,"line":…,"col":…}`
+	/// like `RunResult::to_json`, with only the output since the last call and where the program is.
+	pub fn state(&mut self) -> String {
+		let output = self.output.bytes();
+		let new = &output[self.shown..];
+		// a character may be cut in two, keep its start for next time
+		let len = match std::str::from_utf8(new) {
+			Err(e) if e.error_len().is_none() => e.valid_up_to(),
+			_ => new.len(),
+		};
+		self.shown += len;
+		let result = RunResult {
+			output: String::from_utf8_lossy(&new[..len]).into_owned(),
+			stack: self.machine.stack(),
+			variables: self.machine.variables(),
+			steps: self.steps,
+			error: self.error.clone(),
+		};
+		let pos = self.machine.position().filter(|_| !self.done || self.error.is_some());
+		format!("{{{},\"done\":{},{}}}", result.fields(&self.source), self.done, place(&self.source, pos))
+	}
+}
+
+/// The tokens of `code` as `{"tokens":[{"kind":…,"text":…,"start":…,"end":…},…],"error":…}`, for
+/// highlighting. Offsets are in chars, comments and whitespace are left out, and the tokens go up to
+/// the syntax error if there is one. Kinds are `number`, `stack`, `arithmetic`, `comparison`,
+/// `lambda`, `variable`, `io` and `string`.
+pub fn tokenize(code: &str, dialect: Dialect) -> String {
+	let mut tokenizer = Tokenizer::with_dialect(code, dialect);
+	let chars: Vec<char> = code.chars().collect();
+	let mut tokens = Vec::new();
+	let error = loop {
+		match tokenizer.next_token() {
+			Ok(Some(token)) => {
+				let (start, end) = (tokenizer.start(), tokenizer.pos());
+				let text: String = chars[start..end].iter().collect();
+				tokens.push(format!("{{\"kind\":\"{}\",\"text\":{},\"start\":{},\"end\":{}}}", kind(&token), json_string(&text), start, end));
+			}
+			Ok(None) => break "null".to_string(),
+			Err(e) => break Problem::new(e, None).to_json(code),
+		}
+	};
+	format!("{{\"tokens\":[{}],\"error\":{}}}", tokens.join(","), error)
+}
+
+/// The syntax error in `code` as in `Problem::to_json`, or `null`.
+pub fn check(code: &str, dialect: Dialect) -> String {
+	let mut parser = super::parser::Parser::new(Tokenizer::with_dialect(code, dialect));
+	match parser.parse() {
+		Ok(_) => "null".to_string(),
+		Err(e) => Problem::new(e, None).to_json(code),
+	}
+}
+
+fn kind(token: &Token) -> &'static str {
+	match token {
+		Token::Number(_) => "number",
+		Token::Dup | Token::Drop | Token::Swap | Token::Rot | Token::Pick => "stack",
+		Token::Plus | Token::Minus | Token::Mul | Token::Div | Token::Negate | Token::BitAnd | Token::BitOr | Token::BitNot => "arithmetic",
+		Token::GreaterThan | Token::Equal | Token::LessThan => "comparison",
+		Token::LambdaStart | Token::LambdaEnd | Token::LambdaExecute | Token::LambdaIf | Token::LambdaWhile | Token::LambdaPointer(_) => "lambda",
+		Token::Variable(_) | Token::VarWrite | Token::VarRead => "variable",
+		Token::ReadChar | Token::WriteChar | Token::WriteInt | Token::FlushIO => "io",
+		Token::PrintString(_) => "string",
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_run() {
+		for engine in Engine::ALL {
+			let options = RunOptions { engine, ..RunOptions::default() };
+			let result = run("^^% 3a: \"x\" 1 2", b"q", &options);
+			assert_eq!(result.output, "x", "{:?}", engine);
+			assert_eq!(result.stack, vec![StackElement::Number(113), StackElement::Number(1), StackElement::Number(2)], "{:?}", engine);
+			assert_eq!(result.variables, vec![('a', StackElement::Number(3))], "{:?}", engine);
+			assert_eq!(result.error, None, "{:?}", engine);
+			assert!(result.steps > 0, "{:?}", engine);
+		}
+		let result = run("[1]f: f; 'a", b"", &RunOptions::default());
+		assert_eq!(result.to_json("[1]f: f; 'a"), r#"{"output":"","stack":["[fn0]",97],"variables":{"f":"[fn0]"},"steps":7,"error":null}"#);
+	}
+
+	#[test]
+	fn test_run_errors() {
+		for engine in Engine::ALL {
+			let options = RunOptions { engine, ..RunOptions::default() };
+			let result = run("1 2\n\"ab\" 0/", b"", &options);
+			assert_eq!(result.output, "ab", "{:?}", engine);
+			let error = result.error.unwrap();
+			assert_eq!((error.kind, error.message.as_str(), error.pos), ("runtime", "division by zero", Some(10)), "{:?}", engine);
+			assert_eq!(error.to_json("1 2\n\"ab\" 0/"), r#"{"kind":"runtime","message":"division by zero","pos":10,"line":2,"col":7}"#);
+
+			let options = RunOptions { limits: Limits { steps: Some(10), stack: None }, ..options };
+			let result = run("[1][]#", b"", &options);
+			assert_eq!((result.error.unwrap().kind, result.steps), ("fuel", 10), "{:?}", engine);
+
+			// running out takes too long for a test, but it is there
+			let machine = Machine::new(b"", Capture::default(), &RunOptions { engine, ..RunOptions::default() });
+			assert_eq!(machine.fuel(), Some(DEFAULT_STEPS), "{:?}", engine);
+
+			let result = run("1 [", b"", &options);
+			assert_eq!(result.error, Some(Problem { kind: "syntax", message: "unterminated lambda".to_string(), pos: Some(3) }));
+			assert_eq!((result.stack, result.steps), (vec![], 0));
+		}
+	}
+
+	#[test]
+	fn test_session() {
+		for engine in Engine::ALL {
+			let options = RunOptions { engine, ..RunOptions::default() };
+			let mut session = Session::new("\"ab\" 1\n0/", b"", &options).unwrap();
+			assert!(session.step(1));
+			assert_eq!(session.state(), r#"{"output":"ab","stack":[],"variables":{},"steps":1,"error":null,"done":false,"pos":5,"line":1,"col":6}"#, "{:?}", engine);
+			assert!(!session.step(100));
+			let state = session.state();
+			assert!(state.starts_with(r#"{"output":"","stack":["#), "{:?}: {}", engine, state);
+			assert!(state.ends_with(r#""steps":3,"error":{"kind":"runtime","message":"division by zero","pos":8,"line":2,"col":2},"done":true,"pos":8,"line":2,"col":2}"#), "{:?}: {}", engine, state);
+		}
+		let mut session = Session::new("'é, 1a:", b"", &RunOptions::default()).unwrap();
+		assert!(!session.step(10));
+		assert_eq!(session.state(), r#"{"output":"é","stack":[],"variables":{"a":1},"steps":5,"error":null,"done":true,"pos":null,"line":null,"col":null}"#);
+		assert!(Session::new("[", b"", &RunOptions::default()).is_err());
+	}
+
+	#[test]
+	fn test_tokenize() {
+		assert_eq!(tokenize("12 {c} 'a$\"s\"", Dialect::Rfalse), concat!(
+			r#"{"tokens":[{"kind":"number","text":"12","start":0,"end":2},{"kind":"number","text":"'a","start":7,"end":9},"#,
+			r#"{"kind":"stack","text":"$","start":9,"end":10},{"kind":"string","text":"\"s\"","start":10,"end":13}],"error":null}"#,
+		));
+		assert_eq!(tokenize("1 P", Dialect::Strict), concat!(
+			r#"{"tokens":[{"kind":"number","text":"1","start":0,"end":1}],"#,
+			r#""error":{"kind":"syntax","message":"P is an rfalse extension","pos":2,"line":1,"col":3}}"#,
+		));
+	}
+
+	#[test]
+	fn test_check() {
+		assert_eq!(check("[1]!", Dialect::Rfalse), "null");
+		assert_eq!(check("1\n]", Dialect::Rfalse), r#"{"kind":"syntax","message":"unmatched ]","pos":2,"line":2,"col":1}"#);
+	}
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::vm2::{FalseVM, Instr, CALL_STACK_SIZE, MNEMONICS};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+	pub line: usize,
+	pub message: String,
+}
+
+impl fmt::Display for AsmError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.message)
+	}
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+	Number(i32),
+	Label(String),
+	Text(String),
+}
+
+#[derive(Debug)]
+enum Item {
+	Label(String),
+	Fn(usize),
+	Entry(String),
+	Word(Operand),
+	Instr(Instr, Option<Operand>),
+}
+
+impl Item {
+	fn size(&self) -> usize {
+		match self {
+			Item::Label(_) | Item::Fn(_) | Item::Entry(_) => 0,
+			Item::Word(_) => 1,
+			Item::Instr(_, Some(Operand::Text(s))) => 2 + s.chars().count(),
+			Item::Instr(i, _) => 1 + i.arg_count(),
+		}
+	}
+}
+
+/// Assembles `source` into memory starting at the VM cursor, then points the cursor at the entry point.
+/// On an error the VM is left as it was, except for code the new one overwrote.
+/// This is the counterpart of `disasm::disassemble`, its output can be fed back here.
+///
+/// ```text
+/// ; comment
+/// .fn 0           ; registers fn_pointer[0] and defines label `fn0`
+///     push 1
+///     plus
+///     return
+/// .fn 1
+///     push fn0        ; label operands resolve to addresses
+///     push 'f'
+///     push -5
+/// loop:
+///     writestring "hello\n"
+///     goto loop
+///     .word 42        ; raw memory cell
+/// .entry fn1      ; optional, defaults to the function with the highest index
+/// ```
+pub fn assemble(vm: &mut FalseVM, source: &str) -> Result<(), AsmError> {
+	let mut items: Vec<(usize, Item)> = Vec::new();
+	for (n, line) in source.lines().enumerate() {
+		for item in parse_line(line).map_err(|message| AsmError { line: n + 1, message })? {
+			items.push((n + 1, item));
+		}
+	}
+
+	// first pass: addresses of labels
+	let start = vm.cursor;
+	let mut labels: HashMap<String, usize> = HashMap::new();
+	let mut functions: Vec<(usize, usize)> = Vec::new();
+	let mut addr = start;
+	for (line, item) in items.iter() {
+		let name = match item {
+			Item::Label(name) => name.clone(),
+			Item::Fn(n) => {
+				functions.push((*n, addr));
+				format!("fn{}", n)
+			}
+			_ => {
+				addr += item.size();
+				// leave some room for the data stack, like `FalseVM::load`
+				if addr + CALL_STACK_SIZE > vm.stack_pointer {
+					return Err(AsmError { line: *line, message: "program does not fit into memory".to_string() });
+				}
+				continue;
+			}
+		};
+		if labels.insert(name.clone(), addr).is_some() {
+			return Err(AsmError { line: *line, message: format!("duplicate label {}", name) });
+		}
+	}
+
+	// second pass: emit
+	let resolve = |line: usize, op: &Operand| -> Result<i32, AsmError> {
+		match op {
+			Operand::Number(x) => Ok(*x),
+			Operand::Label(name) => labels.get(name).map(|a| *a as i32)
+				.ok_or_else(|| AsmError { line, message: format!("undefined label {}", name) }),
+			Operand::Text(_) => Err(AsmError { line, message: "unexpected string".to_string() }),
+		}
+	};
+	let code_end = vm.code_end;
+	let entrypoint = functions.iter().max().map(|(_, addr)| *addr).unwrap_or(start);
+	match emit(vm, &items, resolve, entrypoint) {
+		Ok(entrypoint) => {
+			for (n, addr) in functions {
+				vm.fn_pointer.insert(n, addr);
+			}
+			vm.goto(entrypoint);
+			Ok(())
+		}
+		Err(e) => {
+			vm.memory[start..vm.code_end].fill(0);
+			vm.code_end = code_end;
+			vm.cursor = start;
+			vm.fn_refs.retain(|cell| *cell < start);
+			Err(e)
+		}
+	}
+}
+
+/// Writes `items` at the cursor and returns the entry point, `entrypoint` unless there's an `.entry`.
+fn emit(vm: &mut FalseVM, items: &[(usize, Item)], resolve: impl Fn(usize, &Operand) -> Result<i32, AsmError>, mut entrypoint: usize) -> Result<usize, AsmError> {
+	for (line, item) in items.iter() {
+		match item {
+			Item::Label(_) => {}
+			Item::Fn(_) => {}
+			Item::Entry(name) => entrypoint = resolve(*line, &Operand::Label(name.clone()))? as usize,
+			Item::Word(op) => vm.instr_push_raw(resolve(*line, op)?),
+			Item::Instr(Instr::WriteString, Some(Operand::Text(s))) => {
+				vm.instr_push1(Instr::WriteString, s.chars().count() as i32);
+				for c in s.chars() {
+					vm.instr_push_raw(c as i32);
+				}
+			}
+			Item::Instr(i, None) => vm.instr_push(*i),
+			Item::Instr(i, Some(op)) => {
+				vm.instr_push1(*i, resolve(*line, op)?);
+				if i.takes_fn_address() && matches!(op, Operand::Label(_)) {
+					vm.fn_refs.insert(vm.cursor - 1);
+				}
+			}
+		}
+	}
+	Ok(entrypoint)
+}
+
+fn parse_line(line: &str) -> Result<Vec<Item>, String> {
+	let mut items = Vec::new();
+	let mut line = strip_comment(line).trim();
+	if line.is_empty() {
+		return Ok(items);
+	}
+
+	// label definition, optionally followed by an instruction
+	let (head, rest) = split_word(line);
+	if let Some(name) = head.strip_suffix(':') {
+		if !is_identifier(name) {
+			return Err(format!("invalid label {:?}", name));
+		}
+		items.push(Item::Label(name.to_string()));
+		line = rest;
+		if line.is_empty() {
+			return Ok(items);
+		}
+	}
+
+	let (head, rest) = split_word(line);
+	let operand = if rest.is_empty() { None } else { Some(parse_operand(rest)?) };
+	let item = match (head, operand) {
+		(".fn", Some(Operand::Number(n))) if n >= 0 => Item::Fn(n as usize),
+		(".fn", _) => return Err(".fn expects a function index".to_string()),
+		(".entry", Some(Operand::Label(name))) => Item::Entry(name),
+		(".entry", _) => return Err(".entry expects a label".to_string()),
+		(".word", Some(op @ (Operand::Number(_) | Operand::Label(_)))) => Item::Word(op),
+		(".word", _) => return Err(".word expects a number or a label".to_string()),
+		(mnemonic, operand) => {
+			let instr = MNEMONICS.iter().find(|(_, m)| *m == mnemonic.to_lowercase())
+				.map(|(i, _)| *i)
+				.ok_or_else(|| format!("unknown instruction {}", mnemonic))?;
+			match (instr, &operand) {
+				(Instr::WriteString, Some(Operand::Text(_))) => {}
+				(Instr::WriteString, _) => return Err("writestring expects a string".to_string()),
+				(_, Some(Operand::Text(_))) => return Err(format!("{} does not take a string", mnemonic)),
+				(_, None) if instr.arg_count() == 1 => return Err(format!("{} expects an operand", mnemonic)),
+				(_, Some(_)) if instr.arg_count() == 0 => return Err(format!("{} takes no operands", mnemonic)),
+				_ => {}
+			}
+			Item::Instr(instr, operand)
+		}
+	};
+	items.push(item);
+	Ok(items)
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+	match s.find(char::is_whitespace) {
+		Some(i) => (&s[..i], s[i..].trim()),
+		None => (s, ""),
+	}
+}
+
+fn is_identifier(s: &str) -> bool {
+	let mut chars = s.chars();
+	match chars.next() {
+		Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+		_ => false,
+	}
+}
+
+/// Cuts off a `;` comment, ignoring semicolons inside string and char literals.
+fn strip_comment(line: &str) -> &str {
+	let mut quote: Option<char> = None;
+	let mut escaped = false;
+	for (i, c) in line.char_indices() {
+		match quote {
+			Some(_) if escaped => escaped = false,
+			Some(_) if c == '\\' => escaped = true,
+			Some(q) if c == q => quote = None,
+			Some(_) => {}
+			None if c == '"' || c == '\'' => quote = Some(c),
+			None if c == ';' => return &line[..i],
+			None => {}
+		}
+	}
+	line
+}
+
+fn parse_operand(s: &str) -> Result<Operand, String> {
+	if let Some(body) = s.strip_prefix('"') {
+		let body = body.strip_suffix('"').ok_or("unterminated string")?;
+		return Ok(Operand::Text(unescape(body)?));
+	}
+	if let Some(body) = s.strip_prefix('\'') {
+		let body = body.strip_suffix('\'').ok_or("unterminated char")?;
+		let body = unescape(body)?;
+		let mut chars = body.chars();
+		return match (chars.next(), chars.next()) {
+			(Some(c), None) => Ok(Operand::Number(c as i32)),
+			_ => Err(format!("invalid char literal {}", s)),
+		};
+	}
+	if let Ok(x) = s.parse::<i32>() {
+		return Ok(Operand::Number(x));
+	}
+	if is_identifier(s) {
+		return Ok(Operand::Label(s.to_string()));
+	}
+	Err(format!("invalid operand {}", s))
+}
+
+/// Reverses the escaping done by `{:?}` on strings.
+fn unescape(s: &str) -> Result<String, String> {
+	let mut ret = String::new();
+	let mut chars = s.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			ret.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('n') => ret.push('\n'),
+			Some('t') => ret.push('\t'),
+			Some('r') => ret.push('\r'),
+			Some('0') => ret.push('\0'),
+			Some('\\') => ret.push('\\'),
+			Some('"') => ret.push('"'),
+			Some('\'') => ret.push('\''),
+			Some('u') => {
+				let rest: String = chars.by_ref().take_while(|c| *c != '}').collect();
+				let code = rest.strip_prefix('{')
+					.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+					.and_then(char::from_u32)
+					.ok_or_else(|| format!("invalid escape \\u{}", rest))?;
+				ret.push(code);
+			}
+			other => return Err(format!("invalid escape \\{}", other.map(String::from).unwrap_or_default())),
+		}
+	}
+	Ok(ret)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::disasm::disassemble;
+	use crate::falselang::vm2::FIRST_INSTR;
+
+	fn assemble_new(source: &str) -> FalseVM {
+		let mut vm = FalseVM::new();
+		assemble(&mut vm, source).unwrap();
+		vm
+	}
+
+	#[test]
+	fn test_2plus2() {
+		let mut vm = assemble_new("push 2\npush 2\nplus\nhalt");
+		vm.run().unwrap();
+		assert_eq!(vm.stack_size(), 1);
+		assert_eq!(vm.pop(), Ok(4));
+	}
+
+	#[test]
+	fn test_operands() {
+		let vm = assemble_new("start: push 'a'\n push -3 ; comment\n writestring \"a;b\\n\"\n goto start\n .word 7");
+		assert_eq!(vm.memory[FIRST_INSTR..vm.code_end], [
+			Instr::Push as i32, 97,
+			Instr::Push as i32, -3,
+			Instr::WriteString as i32, 4, 'a' as i32, ';' as i32, 'b' as i32, '\n' as i32,
+			Instr::Goto as i32, FIRST_INSTR as i32,
+			7,
+		]);
+	}
+
+	#[test]
+	fn test_functions() {
+		let mut vm = assemble_new(
+			".fn 0\n\
+			 \tpush 1\n\tplus\n\treturn\n\
+			 .fn 1\n\
+			 \tpush 41\n\tpush fn0\n\tcall\n\thalt"
+		);
+		assert_eq!(vm.fn_pointer.get(&0), Some(&FIRST_INSTR));
+		assert_eq!(vm.fn_pointer.get(&1), Some(&(FIRST_INSTR + 4)));
+		assert_eq!(vm.cursor, FIRST_INSTR + 4);
+		vm.run().unwrap();
+		assert_eq!(vm.pop(), Ok(42));
+	}
+
+	#[test]
+	fn test_entry() {
+		let mut vm = assemble_new("push 1\nhalt\nmain: push 2\nhalt\n.entry main");
+		vm.run().unwrap();
+		assert_eq!(vm.pop(), Ok(2));
+	}
+
+	#[test]
+	fn test_errors() {
+		let mut vm = FalseVM::new();
+		assert_eq!(assemble(&mut vm, "push 1\nfoo").unwrap_err().to_string(), "line 2: unknown instruction foo");
+		assert_eq!(assemble(&mut vm, "goto nowhere").unwrap_err().to_string(), "line 1: undefined label nowhere");
+		assert_eq!(assemble(&mut vm, "push").unwrap_err().to_string(), "line 1: push expects an operand");
+		assert_eq!(assemble(&mut vm, "plus 1").unwrap_err().to_string(), "line 1: plus takes no operands");
+		assert_eq!(assemble(&mut vm, "a:\na:").unwrap_err().to_string(), "line 2: duplicate label a");
+		assert_eq!(assemble(&mut vm, "writestring 1").unwrap_err().to_string(), "line 1: writestring expects a string");
+		assert_eq!(vm.code_end, FIRST_INSTR);
+	}
+
+	#[test]
+	fn test_failed() {
+		let mut vm = FalseVM::new();
+		let huge = "push 1\n".repeat(70000);
+		let e = assemble(&mut vm, &huge).unwrap_err();
+		assert_eq!(e.message, "program does not fit into memory");
+		// the lines before it exactly fill the memory left for code
+		assert_eq!(FIRST_INSTR + (e.line - 1) * 2 + CALL_STACK_SIZE, vm.stack_pointer);
+		assert_eq!(assemble(&mut vm, "push 1\ngoto nowhere").unwrap_err().to_string(), "line 2: undefined label nowhere");
+		assert_eq!((vm.cursor, vm.code_end), (FIRST_INSTR, FIRST_INSTR));
+		assert!(vm.memory[FIRST_INSTR..FIRST_INSTR + 4].iter().all(|x| *x == 0));
+
+		assemble(&mut vm, "push 2\npush 2\nplus\nhalt").unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.pop(), Ok(4));
+	}
+
+	#[test]
+	fn test_round_trip() {
+		for name in ["2plus2", "bottles-of-beer", "factorial", "fibonacci", "fibonacci-iter", "hello", "primes", "ptr", "while-in-while", "while"] {
+			let code = std::fs::read_to_string(format!("examples/{}.false", name)).unwrap();
+			let mut vm = FalseVM::new();
+			vm.load(&code, 0).unwrap();
+			let listing = disassemble(&vm);
+
+			let vm2 = assemble_new(&listing);
+			assert_eq!(disassemble(&vm2), listing);
+			assert_eq!(vm2.memory[FIRST_INSTR..vm2.code_end], vm.memory[FIRST_INSTR..vm.code_end]);
+			assert_eq!(vm2.fn_pointer, vm.fn_pointer);
+			assert_eq!(vm2.fn_refs, vm.fn_refs);
+			assert_eq!(vm2.cursor, vm.cursor);
+		}
+	}
+}
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::error::{line_col, Error};
+use super::parser::Parser;
+use super::profiler::names;
+use super::tokenizer::{Dialect, Token, Tokenizer};
+use super::vm::{self, Frame, StackElement, StepResult};
+use super::vm2::{self, Instr};
+
+
+/// How often each part of a program ran, by char offset in the source. Every token, lambda,
+/// `?` and `#` of the source is there, the ones that never ran with 0.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+	pub source: String,
+	/// Times each token ran, by its offset. A lambda's `[` is the token that pushes it.
+	pub tokens: BTreeMap<usize, u64>,
+	/// Times each lambda was entered, by the offset of its `[`.
+	pub lambdas: BTreeMap<usize, u64>,
+	/// How often each `?` ran its lambda and how often it didn't.
+	pub branches: BTreeMap<usize, (u64, u64)>,
+	/// Iterations of each `#`, over all the times the loop ran.
+	pub loops: BTreeMap<usize, u64>,
+	names: BTreeMap<usize, String>, // of the lambdas, see `Profile::names`
+	ends: HashMap<usize, usize>, // offset of each lambda's end, see `Parser::spans`, to that of its `[`
+}
+
+impl Coverage {
+	/// Finds the tokens of `source`, which is what the VM has to be loaded with.
+	pub fn new(source: &str, dialect: Dialect) -> Result<Coverage, Error> {
+		let mut parser = Parser::new(Tokenizer::with_dialect(source, dialect));
+		parser.parse()?;
+		let functions = &parser.lambda_storage;
+		let spans = &parser.spans;
+		let names = names(source, functions, spans);
+		let mut coverage = Coverage { source: source.to_string(), ..Coverage::default() };
+		for (f, code) in functions.iter().enumerate() {
+			for (token, pos) in code.iter().zip(spans[f].iter()) {
+				coverage.tokens.insert(*pos, 0);
+				match token {
+					Token::LambdaIf => { coverage.branches.insert(*pos, (0, 0)); }
+					Token::LambdaWhile => { coverage.loops.insert(*pos, 0); }
+					Token::LambdaPointer(n) => {
+						coverage.lambdas.insert(*pos, 0);
+						coverage.names.insert(*pos, names[*n].clone());
+						coverage.ends.insert(spans[*n][functions[*n].len()], *pos);
+					}
+					_ => {}
+				}
+			}
+		}
+		Ok(coverage)
+	}
+
+	/// Counts the token at `pos`, or entering the lambda that ends at `pos`.
+	fn hit(&mut self, pos: usize) {
+		if let Some(n) = self.tokens.get_mut(&pos) {
+			*n += 1;
+		} else if let Some(start) = self.ends.get(&pos) {
+			*self.lambdas.entry(*start).or_default() += 1;
+		}
+	}
+
+	/// Counts the `?` at `pos` running its lambda, or not.
+	fn branch(&mut self, pos: usize, taken: bool) {
+		if let Some((yes, no)) = self.branches.get_mut(&pos) {
+			*if taken { yes } else { no } += 1;
+		}
+	}
+
+	/// Runs the tree-walking VM to the end.
+	pub fn run_tree(&mut self, vm: &mut vm::FalseVM) -> Result<(), Error> {
+		// lambdas from earlier loads come first
+		let base = vm.functions.len() - self.names.len() - 1;
+		loop {
+			let (fn_index, cursor, depth) = (vm.fn_index, vm.cursor, vm.frames.len());
+			let code = &vm.functions[fn_index];
+			let at_end = cursor >= code.len();
+			// whether `?` runs its lambda, or `#` its body
+			let cond = |n: usize| match vm.stack.len().checked_sub(n).map(|i| &vm.stack[i]) {
+				Some(StackElement::Number(x)) => Some(*x != 0),
+				_ => None,
+			};
+			let taken = match (code.get(cursor), vm.frames.last()) {
+				(Some(Token::LambdaIf), _) => cond(2),
+				(None, Some(Frame::While { in_cond: true, .. })) => cond(1),
+				_ => None,
+			};
+			let frame = vm.frames.last().cloned();
+			if vm.step()? == StepResult::End {
+				return Ok(());
+			}
+			if fn_index < base {
+				continue;
+			}
+			let spans = &vm.spans;
+			match (at_end, frame) {
+				(false, _) => {
+					self.hit(spans[fn_index][cursor]);
+					if let Some(taken) = taken {
+						self.branch(spans[fn_index][cursor], taken);
+					}
+				}
+				(true, Some(Frame::While { in_cond: true, fn_index, cursor, .. })) if taken == Some(true) => {
+					*self.loops.entry(spans[fn_index][cursor - 1]).or_default() += 1;
+				}
+				_ => {}
+			}
+			if vm.cursor == 0 && (vm.frames.len() > depth || at_end) {
+				self.hit(vm.spans[vm.fn_index][vm.functions[vm.fn_index].len()]);
+			}
+		}
+	}
+
+	/// Runs the bytecode VM to the end, using `FalseVM::spans`. Tokens the optimizer removed don't
+	/// count, and neither does entering an empty lambda that `[..]?` or `[..][..]#` compiled inline.
+	/// At opt level 3 the calls of inlined lambdas are gone as well.
+	pub fn run_bytecode(&mut self, vm: &mut vm2::FalseVM) -> Result<(), Error> {
+		loop {
+			let at = vm.cursor;
+			// the condition a conditional instruction is about to test
+			let cond = match Instr::try_from(vm.memory[at]) {
+				Ok(Instr::CallIf | Instr::TailCallIf) => vm.memory.get(vm.stack_pointer + 1),
+				Ok(Instr::CallIfImm | Instr::GotoIf) => vm.memory.get(vm.stack_pointer),
+				_ => None,
+			}.map(|x| *x != 0);
+			if vm.step()? == StepResult::End {
+				return Ok(());
+			}
+			for pos in vm.spans.get(&at).into_iter().flatten() {
+				match (cond, self.loops.get_mut(pos)) {
+					// the instruction that starts the loop, and the one that runs the body
+					(None, Some(_)) => self.hit(*pos),
+					(Some(true), Some(n)) => *n += 1,
+					(Some(false), Some(_)) => {}
+					(Some(taken), None) if self.branches.contains_key(pos) => {
+						self.hit(*pos);
+						self.branch(*pos, taken);
+					}
+					_ => self.hit(*pos),
+				}
+			}
+		}
+	}
+
+	/// Highest count of the tokens on each line, by 1-based line number. Lines without tokens are left out.
+	fn lines(&self) -> BTreeMap<usize, u64> {
+		let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+		for (pos, n) in self.tokens.iter() {
+			let count = lines.entry(line_col(&self.source, *pos).0).or_default();
+			*count = (*count).max(*n);
+		}
+		lines
+	}
+
+	/// The source with the highest count of each line in front of it. Under each line, `^` marks
+	/// tokens that never ran, and what each `?` and `#` did. A summary comes last.
+	pub fn listing(&self) -> String {
+		let lines = self.lines();
+		// notes by line: column and text
+		let mut notes: BTreeMap<usize, Vec<(usize, String)>> = BTreeMap::new();
+		let mut note = |pos: usize, text: String| {
+			let (line, col) = line_col(&self.source, pos);
+			notes.entry(line).or_default().push((col, text));
+		};
+		for (pos, (yes, no)) in self.branches.iter() {
+			note(*pos, format!("taken {}, skipped {}", yes, no));
+		}
+		for (pos, n) in self.loops.iter() {
+			note(*pos, format!("{} iterations", n));
+		}
+		let mut unrun: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+		for (pos, _) in self.tokens.iter().filter(|(_, n)| **n == 0) {
+			let (line, col) = line_col(&self.source, *pos);
+			unrun.entry(line).or_default().push(col);
+		}
+
+		let mut out = String::new();
+		for (i, text) in self.source.lines().enumerate() {
+			let line = i + 1;
+			let count = lines.get(&line).map_or("-".to_string(), |n| n.to_string());
+			out += &format!("{:>8} | {}\n", count, text);
+			// same whitespace as the line, so that tabs line up
+			let indent = |col: usize| -> String {
+				text.chars().take(col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect()
+			};
+			if let Some(cols) = unrun.get(&line) {
+				let mut marks = String::new();
+				for col in cols {
+					let to = indent(*col);
+					marks += &to[marks.len()..];
+					marks.push('^');
+				}
+				out += &format!("{:>8} | {} never ran\n", "", marks);
+			}
+			let mut notes = notes.remove(&line).unwrap_or_default();
+			notes.sort();
+			for (col, text) in notes {
+				out += &format!("{:>8} | {}^ {}\n", "", indent(col), text);
+			}
+		}
+
+		let (branches, taken) = self.branch_counts().iter().fold((0, 0), |(all, hit), count| (all + 1, hit + (*count > 0) as usize));
+		let percent = |hit: usize, all: usize| match all {
+			0 => "n/a".to_string(),
+			_ => format!("{:.1}% ({}/{})", hit as f64 * 100.0 / all as f64, hit, all),
+		};
+		let tokens = self.tokens.values().filter(|n| **n > 0).count();
+		let lambdas = self.lambdas.values().filter(|n| **n > 0).count();
+		out += &format!("tokens {}, lambdas {}, branches {}\n",
+			percent(tokens, self.tokens.len()), percent(lambdas, self.lambdas.len()), percent(taken, branches));
+		out
+	}
+
+	/// Both ways out of each `?` and `#`: running the lambda and not, running the body and leaving the loop.
+	/// A loop is left once for every time it ran.
+	fn branch_counts(&self) -> Vec<u64> {
+		let mut counts: BTreeMap<usize, [u64; 2]> = BTreeMap::new();
+		for (pos, (yes, no)) in self.branches.iter() {
+			counts.insert(*pos, [*yes, *no]);
+		}
+		for (pos, n) in self.loops.iter() {
+			counts.insert(*pos, [*n, self.tokens[pos]]);
+		}
+		counts.into_values().flatten().collect()
+	}
+
+	/// The counts in lcov's tracefile format, for `genhtml` and coverage services. `name` is the
+	/// source file name. Lambdas are functions, each `?` and `#` is a block with two branches as
+	/// in `branch_counts`.
+	pub fn lcov(&self, name: &str) -> String {
+		let line = |pos: usize| line_col(&self.source, pos).0;
+		let mut out = format!("TN:\nSF:{}\n", name);
+		for (pos, name) in self.names.iter() {
+			out += &format!("FN:{},{}\n", line(*pos), name);
+		}
+		for (pos, name) in self.names.iter() {
+			out += &format!("FNDA:{},{}\n", self.lambdas[pos], name);
+		}
+		out += &format!("FNF:{}\nFNH:{}\n", self.lambdas.len(), self.lambdas.values().filter(|n| **n > 0).count());
+
+		let mut points: Vec<usize> = self.branches.keys().chain(self.loops.keys()).copied().collect();
+		points.sort();
+		let counts = self.branch_counts();
+		for (block, pos) in points.iter().enumerate() {
+			for branch in 0..2 {
+				// `-` for a branch point that never ran
+				let count = match self.tokens[pos] {
+					0 => "-".to_string(),
+					_ => counts[block * 2 + branch].to_string(),
+				};
+				out += &format!("BRDA:{},{},{},{}\n", line(*pos), block, branch, count);
+			}
+		}
+		out += &format!("BRF:{}\nBRH:{}\n", counts.len(), counts.iter().filter(|n| **n > 0).count());
+
+		let lines = self.lines();
+		for (line, count) in lines.iter() {
+			out += &format!("DA:{},{}\n", line, count);
+		}
+		out += &format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), lines.values().filter(|n| **n > 0).count());
+		out
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CODE: &str = "0i: [i;3>~][i;1+i:]#\n1[2]? 0[3]?\n[[5]]f: 4 f;!";
+
+	fn tree(code: &str) -> Coverage {
+		let mut vm = vm::FalseVM::new();
+		vm.output = Box::new(std::io::sink());
+		vm.load(code).unwrap();
+		let mut coverage = Coverage::new(code, Dialect::Rfalse).unwrap();
+		coverage.run_tree(&mut vm).unwrap();
+		coverage
+	}
+
+	fn bytecode(code: &str, opt_level: u8) -> Coverage {
+		let mut vm = vm2::FalseVM::new();
+		vm.output = Box::new(std::io::sink());
+		vm.load(code, opt_level).unwrap();
+		let mut coverage = Coverage::new(code, Dialect::Rfalse).unwrap();
+		coverage.run_bytecode(&mut vm).unwrap();
+		coverage
+	}
+
+	#[test]
+	fn test_tree() {
+		let c = tree(CODE);
+		assert_eq!(c.branches.values().copied().collect::<Vec<_>>(), [(1, 0), (0, 1)]);
+		assert_eq!(c.loops.values().copied().collect::<Vec<_>>(), [4]);
+		// the condition runs once more than the body, [5] is pushed but never entered
+		assert_eq!(c.lambdas.values().copied().collect::<Vec<_>>(), [5, 4, 1, 0, 1, 0]);
+		assert_eq!(c.tokens[&0], 1);
+		assert_eq!(c.tokens[&5], 5);
+		assert_eq!(c.tokens[&13], 4);
+		let unrun: Vec<usize> = c.tokens.iter().filter(|(_, n)| **n == 0).map(|(pos, _)| *pos).collect();
+		assert_eq!(unrun, [29, 35]);
+	}
+
+	#[test]
+	fn test_engines_agree() {
+		let c = tree(CODE);
+		for opt_level in 0..=1 {
+			let b = bytecode(CODE, opt_level);
+			assert_eq!((&b.tokens, &b.lambdas, &b.branches, &b.loops), (&c.tokens, &c.lambdas, &c.branches, &c.loops), "{}", opt_level);
+		}
+		// with dynamic lambdas, `?` and `#` are calls
+		let code = "[i;3>~]c: [i;1+i:]b: 0i: c;b;# 1 b;? 0 b;?";
+		let c = tree(code);
+		assert_eq!(c.loops.values().copied().collect::<Vec<_>>(), [4]);
+		for opt_level in 0..=3 {
+			let b = bytecode(code, opt_level);
+			assert_eq!((&b.branches, &b.loops), (&c.branches, &c.loops), "{}", opt_level);
+		}
+	}
+
+	#[test]
+	fn test_listing() {
+		assert_eq!(tree(CODE).listing(), [
+			"       5 | 0i: [i;3>~][i;1+i:]#",
+			"         |                    ^ 4 iterations",
+			"       1 | 1[2]? 0[3]?",
+			"         |         ^ never ran",
+			"         |     ^ taken 1, skipped 0",
+			"         |           ^ taken 0, skipped 1",
+			"       1 | [[5]]f: 4 f;!",
+			"         |   ^ never ran",
+			"tokens 94.1% (32/34), lambdas 66.7% (4/6), branches 66.7% (4/6)",
+			"",
+		].join("\n"));
+		assert_eq!(tree("1 2+").listing().lines().last(), Some("tokens 100.0% (3/3), lambdas n/a, branches n/a"));
+	}
+
+	#[test]
+	fn test_lcov() {
+		let lcov = tree("1[2]?\n[3]f:").lcov("prog.false");
+		assert_eq!(lcov, [
+			"TN:", "SF:prog.false",
+			"FN:1,lambda@1:2", "FN:2,f@2:1",
+			"FNDA:1,lambda@1:2", "FNDA:0,f@2:1",
+			"FNF:2", "FNH:1",
+			"BRDA:1,0,0,1", "BRDA:1,0,1,0",
+			"BRF:2", "BRH:1",
+			"DA:1,1", "DA:2,1",
+			"LF:2", "LH:2",
+			"end_of_record", "",
+		].join("\n"));
+	}
+}
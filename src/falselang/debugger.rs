@@ -0,0 +1,514 @@
+use super::error::Error;
+use super::parser::Parser;
+use super::tokenizer::Tokenizer;
+use super::vm::{FalseVM, Snapshot, StackElement, StepResult};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+
+/// Where `Debugger` pauses the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+	/// Before token `cursor` of function `fn_index`, see `Debugger::break_at`.
+	Token { fn_index: usize, cursor: usize },
+	/// On entering the lambda that is stored in the variable at the time.
+	Lambda(char),
+}
+
+/// Why `Debugger` gave control back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pause {
+	/// The step, step over or step out is done.
+	Step,
+	/// Breakpoint `n` of `Debugger::breakpoints` was reached.
+	Breakpoint(usize),
+	/// The main function has ended.
+	End,
+	/// Going back found nothing and stopped where the recording starts.
+	Start,
+}
+
+/// Runs a program on the tree-walking VM under control: single steps, stepping over or
+/// out of lambdas, breakpoints, and running code where the program is paused.
+///
+/// Every step is recorded, so the program can also go back: one step, to where a variable last
+/// changed, to where the stack last had some depth, or to the last breakpoint. It keeps a
+/// snapshot every `snapshot_every` steps and runs forward from the one before the point it goes
+/// back to. Input that was read once is read from the recording again, and output is only written
+/// the first time, so running the same steps again after going back looks like running them once.
+pub struct Debugger {
+	pub vm: FalseVM,
+	pub source: String,
+	pub breakpoints: Vec<Breakpoint>,
+	/// Steps between snapshots, more makes going back slower and the recording smaller.
+	pub snapshot_every: u64,
+	spans: Vec<Vec<usize>>, // see `Parser::spans`, same layout as `vm.functions`
+	steps: u64, // steps from the start of the program to where it is
+	history: Vec<Checkpoint>, // by step, the first is where the recording starts
+	tape: Rc<RefCell<Tape>>,
+}
+
+/// The state after `step` steps.
+struct Checkpoint {
+	step: u64,
+	state: Snapshot,
+	fuel: Option<u64>,
+	read: usize, // see `Tape`
+	written: usize,
+}
+
+/// The program's I/O as far as it went, shared by `TapeInput` and `TapeOutput`.
+#[derive(Default)]
+struct Tape {
+	input: Vec<u8>, // every byte read so far
+	read: usize, // where the program is in `input`
+	ended: bool, // the input has ended after `input`
+	written: usize, // bytes the program has written up to where it is
+	written_max: usize, // bytes actually written, the rest of a write up to here is dropped
+}
+
+struct TapeInput {
+	tape: Rc<RefCell<Tape>>,
+	inner: Box<dyn Read>,
+}
+
+impl Read for TapeInput {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let mut tape = self.tape.borrow_mut();
+		if tape.read == tape.input.len() && !tape.ended {
+			let n = self.inner.read(buf)?;
+			tape.input.extend_from_slice(&buf[..n]);
+			tape.ended = n == 0 && !buf.is_empty();
+		}
+		let start = tape.read;
+		let n = buf.len().min(tape.input.len() - start);
+		buf[..n].copy_from_slice(&tape.input[start..start + n]);
+		tape.read += n;
+		Ok(n)
+	}
+}
+
+struct TapeOutput {
+	tape: Rc<RefCell<Tape>>,
+	inner: Box<dyn Write>,
+}
+
+impl Write for TapeOutput {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let mut tape = self.tape.borrow_mut();
+		let skip = (tape.written_max - tape.written).min(buf.len());
+		self.inner.write_all(&buf[skip..])?;
+		tape.written += buf.len();
+		tape.written_max = tape.written_max.max(tape.written);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl Debugger {
+	/// Loads `source` into `vm`, paused before the first token.
+	pub fn new(mut vm: FalseVM, source: &str) -> Result<Debugger, Error> {
+		let mut parser = Parser::new(Tokenizer::with_dialect(source, vm.dialect));
+		parser.parse()?;
+		vm.load(source)?;
+		// functions loaded before have no spans
+		let mut spans = vec![Vec::new(); vm.functions.len() - parser.spans.len()];
+		spans.append(&mut parser.spans);
+		let tape = Rc::new(RefCell::new(Tape::default()));
+		vm.input = Box::new(TapeInput { tape: tape.clone(), inner: std::mem::replace(&mut vm.input, Box::new(io::empty())) });
+		vm.output = Box::new(TapeOutput { tape: tape.clone(), inner: std::mem::replace(&mut vm.output, Box::new(io::sink())) });
+		let mut debugger = Debugger {
+			vm,
+			source: source.to_string(),
+			breakpoints: Vec::new(),
+			snapshot_every: 1000,
+			spans,
+			steps: 0,
+			history: Vec::new(),
+			tape,
+		};
+		debugger.checkpoint();
+		Ok(debugger)
+	}
+
+	/// Steps from the start of the program to where it is paused.
+	pub fn steps(&self) -> u64 {
+		self.steps
+	}
+
+	/// Char offset of the token the program is paused at, or of the `]` if the lambda is about
+	/// to return. `None` in code that isn't part of `source`.
+	pub fn position(&self) -> Option<usize> {
+		self.span(self.vm.fn_index, self.vm.cursor)
+	}
+
+	/// Char offset of token `cursor` of function `fn_index`, like `position`.
+	pub fn span(&self, fn_index: usize, cursor: usize) -> Option<usize> {
+		self.spans.get(fn_index)?.get(cursor).copied()
+	}
+
+	/// Adds a breakpoint at the first token at or after 1-based `line` and `column`, which may
+	/// be on a later line. Returns its index in `breakpoints`.
+	pub fn break_at(&mut self, line: usize, column: usize) -> Result<usize, String> {
+		let offset = offset(&self.source, line, column).ok_or_else(|| format!("there is no line {}", line))?;
+		let (fn_index, cursor, _) = self.spans.iter().enumerate()
+			.flat_map(|(f, spans)| spans.iter().enumerate().map(move |(c, pos)| (f, c, *pos)))
+			.filter(|(_, _, pos)| *pos >= offset)
+			.min_by_key(|(_, _, pos)| *pos)
+			.ok_or_else(|| format!("no code at {}:{}", line, column))?;
+		self.breakpoints.push(Breakpoint::Token { fn_index, cursor });
+		Ok(self.breakpoints.len() - 1)
+	}
+
+	/// Adds a breakpoint on entering the lambda in `variable`. Returns its index in `breakpoints`.
+	pub fn break_on(&mut self, variable: char) -> usize {
+		self.breakpoints.push(Breakpoint::Lambda(variable));
+		self.breakpoints.len() - 1
+	}
+
+	/// Steps until `done` says so, a breakpoint is reached or the program ends. A step that fails
+	/// is undone, so the program stays where it was before it.
+	fn resume(&mut self, done: impl Fn(&FalseVM) -> bool) -> Result<Pause, Error> {
+		loop {
+			match self.vm.step() {
+				Ok(StepResult::End) => return Ok(Pause::End),
+				Ok(_) => {
+					self.steps += 1;
+					if self.steps.is_multiple_of(self.snapshot_every.max(1)) && self.history.last().is_some_and(|c| c.step < self.steps) {
+						self.checkpoint();
+					}
+				}
+				Err(e) => {
+					self.goto(self.steps)?;
+					return Err(e);
+				}
+			}
+			if let Some(n) = at_breakpoint(&self.breakpoints, &self.vm) {
+				return Ok(Pause::Breakpoint(n));
+			}
+			if done(&self.vm) {
+				return Ok(Pause::Step);
+			}
+		}
+	}
+
+	/// Executes one token. `!`, `?` and `#` stop at the start of the lambda they run.
+	pub fn step(&mut self) -> Result<Pause, Error> {
+		self.resume(|_| true)
+	}
+
+	/// Executes one token, including all of a lambda that `!`, `?` or `#` runs.
+	pub fn step_over(&mut self) -> Result<Pause, Error> {
+		let depth = self.vm.frames.len();
+		self.resume(|vm| vm.frames.len() <= depth)
+	}
+
+	/// Runs until the current lambda returns. In a `#` condition or body, that is when the loop ends.
+	pub fn step_out(&mut self) -> Result<Pause, Error> {
+		let depth = self.vm.frames.len();
+		self.resume(|vm| vm.frames.len() < depth)
+	}
+
+	/// Runs until a breakpoint or the end.
+	pub fn continue_(&mut self) -> Result<Pause, Error> {
+		self.resume(|_| false)
+	}
+
+	/// Goes back one step.
+	pub fn step_back(&mut self) -> Result<Pause, Error> {
+		if self.steps == self.history[0].step {
+			return Ok(Pause::Start);
+		}
+		self.goto(self.steps - 1)?;
+		Ok(Pause::Step)
+	}
+
+	/// Goes back to just before the step that last changed `variable`.
+	pub fn back_to_change(&mut self, variable: char) -> Result<Pause, Error> {
+		let found = self.back_until(|vm| vm.variables.get(&variable).cloned(), |before, after| before != after)?;
+		Ok(if found.is_some() { Pause::Step } else { Pause::Start })
+	}
+
+	/// Goes back to the last point where the stack held `depth` values.
+	pub fn back_to_depth(&mut self, depth: usize) -> Result<Pause, Error> {
+		let found = self.back_until(|vm| vm.stack.len(), |before, _| *before == depth)?;
+		Ok(if found.is_some() { Pause::Step } else { Pause::Start })
+	}
+
+	/// Goes back to the last point where the program was at a breakpoint.
+	pub fn reverse_continue(&mut self) -> Result<Pause, Error> {
+		let breakpoints = self.breakpoints.clone();
+		let found = self.back_until(|vm| at_breakpoint(&breakpoints, vm), |before, _| before.is_some())?;
+		Ok(match found {
+			Some(Some(n)) => Pause::Breakpoint(n),
+			_ => Pause::Start,
+		})
+	}
+
+	/// Goes back to the last point before this one where `stop` holds for `key` there and
+	/// after the next step, and returns the key there. Without one, goes to where the recording
+	/// starts and returns `None`.
+	fn back_until<K>(&mut self, key: impl Fn(&FalseVM) -> K, stop: impl Fn(&K, &K) -> bool) -> Result<Option<K>, Error> {
+		let mut end = self.steps;
+		for n in (0..self.history.len()).rev() {
+			if self.history[n].step >= end {
+				continue;
+			}
+			self.restore(n);
+			let mut found = None;
+			let mut before = key(&self.vm);
+			while self.steps < end && self.vm.step()? != StepResult::End {
+				self.steps += 1;
+				let after = key(&self.vm);
+				if stop(&before, &after) {
+					found = Some(self.steps - 1);
+				}
+				before = after;
+			}
+			if let Some(step) = found {
+				self.goto(step)?;
+				return Ok(Some(key(&self.vm)));
+			}
+			end = self.history[n].step;
+		}
+		self.restore(0);
+		Ok(None)
+	}
+
+	/// Goes to where the program was after `step` steps, which has to be recorded already.
+	fn goto(&mut self, step: u64) -> Result<(), Error> {
+		self.restore(self.history.partition_point(|c| c.step <= step) - 1);
+		while self.steps < step && self.vm.step()? != StepResult::End {
+			self.steps += 1;
+		}
+		Ok(())
+	}
+
+	fn restore(&mut self, n: usize) {
+		let checkpoint = &self.history[n];
+		self.vm.restore(&checkpoint.state);
+		self.vm.fuel = checkpoint.fuel;
+		let mut tape = self.tape.borrow_mut();
+		tape.read = checkpoint.read;
+		tape.written = checkpoint.written;
+		self.steps = checkpoint.step;
+	}
+
+	fn checkpoint(&mut self) {
+		let tape = self.tape.borrow();
+		self.history.push(Checkpoint { step: self.steps, state: self.vm.snapshot(), fuel: self.vm.fuel, read: tape.read, written: tape.written });
+	}
+
+	/// Runs `code` on the paused program's stack and variables, then puts the program back where
+	/// it was. Breakpoints don't apply to it. What it changes can't be undone, so the recording
+	/// starts again here.
+	pub fn eval(&mut self, code: &str) -> Result<(), Error> {
+		let (fn_index, cursor) = (self.vm.fn_index, self.vm.cursor);
+		let frames = std::mem::take(&mut self.vm.frames);
+		{
+			// after going back, the output from here on is new
+			let mut tape = self.tape.borrow_mut();
+			tape.written_max = tape.written;
+		}
+		let result = self.vm.load(code).and_then(|_| self.vm.run());
+		self.vm.fn_index = fn_index;
+		self.vm.cursor = cursor;
+		self.vm.frames = frames;
+		self.history.clear();
+		self.checkpoint();
+		result
+	}
+}
+
+/// Index of the first of `breakpoints` that `vm` is at.
+fn at_breakpoint(breakpoints: &[Breakpoint], vm: &FalseVM) -> Option<usize> {
+	breakpoints.iter().position(|b| match b {
+		Breakpoint::Token { fn_index, cursor } => vm.fn_index == *fn_index && vm.cursor == *cursor,
+		Breakpoint::Lambda(v) => vm.cursor == 0 && !vm.frames.is_empty()
+			&& vm.variables.get(v) == Some(&StackElement::Lambda(vm.fn_index)),
+	})
+}
+
+/// Char offset of 1-based `line` and `column`, the column may be past the end of the line.
+fn offset(source: &str, line: usize, column: usize) -> Option<usize> {
+	let mut start = 0;
+	for (n, text) in source.split('\n').enumerate() {
+		if n + 1 == line {
+			return Some(start + column.saturating_sub(1).min(text.chars().count()));
+		}
+		start += text.chars().count() + 1;
+	}
+	None
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::error::line_col;
+	use crate::falselang::interpreter::Capture;
+
+	const FACTORIAL: &str = "[$1=$[\\%1\\]?~[$1-f;!*]?]f:\n3f;!\n.";
+
+	fn debugger(code: &str) -> Debugger {
+		let mut vm = FalseVM::new();
+		vm.output = Box::new(std::io::sink());
+		Debugger::new(vm, code).unwrap()
+	}
+
+	fn line_col_now(d: &Debugger) -> (usize, usize) {
+		line_col(&d.source, d.position().unwrap())
+	}
+
+	#[test]
+	fn test_step() {
+		let mut d = debugger("1 [2 3]! 4");
+		assert_eq!(d.position(), Some(0));
+		assert_eq!(d.step(), Ok(Pause::Step));
+		assert_eq!(d.step(), Ok(Pause::Step));
+		assert_eq!(d.step(), Ok(Pause::Step));
+		// inside the lambda
+		assert_eq!(d.position(), Some(3));
+		assert_eq!(d.step_out(), Ok(Pause::Step));
+		assert_eq!(d.position(), Some(9));
+		assert_eq!(d.vm.stack.len(), 3);
+		assert_eq!(d.step(), Ok(Pause::Step));
+		assert_eq!(d.step(), Ok(Pause::End));
+		assert_eq!(d.step(), Ok(Pause::End));
+	}
+
+	#[test]
+	fn test_step_over() {
+		let mut d = debugger("1 [2 3]! 0[5]? 0i:[i;3>~][i;1+i:]# 4");
+		let mut positions = vec![];
+		while d.step_over() == Ok(Pause::Step) {
+			positions.push(d.position().unwrap());
+		}
+		// [5]? doesn't run, the loop runs 4 times
+		assert_eq!(positions, vec![2, 7, 9, 10, 13, 15, 16, 17, 18, 25, 33, 35, 36]);
+		assert_eq!(d.vm.variables[&'i'], StackElement::Number(4));
+	}
+
+	#[test]
+	fn test_breakpoints() {
+		let mut d = debugger(FACTORIAL);
+		assert_eq!(d.break_at(2, 1), Ok(0));
+		assert_eq!(d.break_at(1, 15), Ok(1));
+		assert!(d.break_at(4, 1).is_err());
+		assert_eq!(d.break_at(1, 40), Ok(2));
+		assert_eq!(d.breakpoints[2], d.breakpoints[0]);
+		d.breakpoints.pop();
+		assert_eq!(d.continue_(), Ok(Pause::Breakpoint(0)));
+		assert_eq!(line_col_now(&d), (2, 1));
+		for n in [3, 2] {
+			assert_eq!(d.continue_(), Ok(Pause::Breakpoint(1)));
+			assert_eq!(line_col_now(&d), (1, 15));
+			assert_eq!(d.vm.stack.last(), Some(&StackElement::Number(n)));
+		}
+		d.breakpoints.clear();
+		assert_eq!(d.break_on('f'), 0);
+		assert_eq!(d.continue_(), Ok(Pause::Breakpoint(0)));
+		assert_eq!(line_col_now(&d), (1, 2));
+		assert_eq!(d.vm.stack.last(), Some(&StackElement::Number(1)));
+		d.breakpoints.clear();
+		assert_eq!(d.continue_(), Ok(Pause::End));
+		assert_eq!(d.vm.stack, vec![]);
+	}
+
+	#[test]
+	fn test_eval() {
+		let mut d = debugger("5a: [a;1+a:]f: 1 2 f;!");
+		d.break_on('f');
+		assert_eq!(d.continue_(), Ok(Pause::Breakpoint(0)));
+		d.eval("+ 10a:").unwrap();
+		assert!(d.eval("Q").is_err());
+		assert_eq!(d.vm.stack, vec![StackElement::Number(3)]);
+		assert_eq!(d.position(), Some(5));
+		assert_eq!(d.continue_(), Ok(Pause::End));
+		assert_eq!(d.vm.variables[&'a'], StackElement::Number(11));
+	}
+
+	#[test]
+	fn test_step_back() {
+		for every in [1, 3, 1000] {
+			let mut d = debugger(FACTORIAL);
+			d.snapshot_every = every;
+			assert_eq!(d.step_back(), Ok(Pause::Start));
+			let mut states = vec![d.vm.snapshot()];
+			while d.step() == Ok(Pause::Step) {
+				states.push(d.vm.snapshot());
+			}
+			assert_eq!(d.steps() as usize, states.len() - 1);
+			while d.step_back() == Ok(Pause::Step) {
+				assert_eq!(d.vm.snapshot(), states[d.steps() as usize]);
+			}
+			assert_eq!(d.steps(), 0);
+			assert_eq!(d.continue_(), Ok(Pause::End));
+			assert_eq!(d.vm.stack, vec![]);
+		}
+	}
+
+	#[test]
+	fn test_back_to() {
+		let mut d = debugger("0i:[i;4>~][i;1+i: 1 2 3%%%]# i;");
+		d.snapshot_every = 4;
+		assert_eq!(d.continue_(), Ok(Pause::End));
+		// before the i: that stores 5, then 4, ... and the first one
+		for n in (0..6).rev() {
+			assert_eq!(d.back_to_change('i'), Ok(Pause::Step));
+			assert_eq!(d.vm.stack.last(), Some(&StackElement::Variable('i')));
+			assert_eq!(d.vm.variables.get(&'i').cloned(), (n > 0).then(|| StackElement::Number(n - 1)));
+		}
+		assert_eq!(d.back_to_change('i'), Ok(Pause::Start));
+		assert_eq!(d.steps(), 0);
+
+		assert_eq!(d.continue_(), Ok(Pause::End));
+		assert_eq!(d.back_to_depth(3), Ok(Pause::Step));
+		assert_eq!(d.vm.stack, vec![StackElement::Number(1), StackElement::Number(2), StackElement::Number(3)]);
+		assert_eq!(d.vm.variables[&'i'], StackElement::Number(5));
+		assert_eq!(d.back_to_depth(9), Ok(Pause::Start));
+
+		d.break_on('x');
+		assert_eq!(d.break_at(1, 14), Ok(1));
+		assert_eq!(d.continue_(), Ok(Pause::Breakpoint(1)));
+		assert_eq!(d.continue_(), Ok(Pause::Breakpoint(1)));
+		assert_eq!(d.vm.variables[&'i'], StackElement::Number(1));
+		assert_eq!(d.reverse_continue(), Ok(Pause::Breakpoint(1)));
+		assert_eq!(d.vm.variables[&'i'], StackElement::Number(0));
+		assert_eq!(d.reverse_continue(), Ok(Pause::Start));
+	}
+
+	#[test]
+	fn test_replay_io() {
+		let out = Capture::default();
+		let mut vm = FalseVM::new();
+		vm.input = Box::new(io::Cursor::new(b"ab".to_vec()));
+		vm.output = Box::new(out.clone());
+		let mut d = Debugger::new(vm, "^^^%. 1.").unwrap();
+		d.snapshot_every = 2;
+		assert_eq!(d.continue_(), Ok(Pause::End));
+		assert_eq!(d.back_to_depth(0), Ok(Pause::Step));
+		assert_eq!(d.steps(), 0);
+		assert_eq!(d.continue_(), Ok(Pause::End));
+		// read again from the recording, written only once
+		assert_eq!(d.vm.stack, vec![StackElement::Number(97)]);
+		assert_eq!(&*out.bytes(), b"981");
+		d.step_back().unwrap();
+		d.eval("'x,").unwrap();
+		assert_eq!(d.step_back(), Ok(Pause::Start));
+		assert_eq!(d.continue_(), Ok(Pause::End));
+		assert_eq!(&*out.bytes(), b"981x1");
+	}
+
+	#[test]
+	fn test_offset() {
+		assert_eq!(offset("ab\ncd", 1, 1), Some(0));
+		assert_eq!(offset("ab\ncd", 2, 2), Some(4));
+		assert_eq!(offset("ab\ncd", 1, 9), Some(2));
+		assert_eq!(offset("ab\ncd", 3, 1), None);
+	}
+}
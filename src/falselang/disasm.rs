@@ -0,0 +1,230 @@
+use std::collections::{HashMap, HashSet};
+
+use super::vm2::{FalseVM, Instr, FIRST_INSTR};
+
+
+/// A single instruction decoded from vm2 memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded {
+	pub addr: usize,
+	/// `Err` holds a raw cell that is not a valid opcode.
+	pub instr: Result<Instr, i32>,
+	/// Operands. For `WriteString` these are the characters, the length cell is implied.
+	pub args: Vec<i32>,
+}
+
+impl Decoded {
+	/// Number of memory cells taken by this instruction, including operands.
+	pub fn size(&self) -> usize {
+		match self.instr {
+			Ok(Instr::WriteString) => 2 + self.args.len(),
+			Ok(_) => 1 + self.args.len(),
+			Err(_) => 1,
+		}
+	}
+}
+
+/// Walks memory from `start` to `end`, using each opcode's operand count to find the next one.
+pub fn decode(vm: &FalseVM, start: usize, end: usize) -> Vec<Decoded> {
+	let mut ret = Vec::new();
+	let mut addr = start;
+	while addr < end {
+		let cell = vm.memory[addr];
+		let instr = match Instr::try_from(cell) {
+			Ok(i) if addr + i.arg_count() < end => i,
+			_ => {
+				ret.push(Decoded { addr, instr: Err(cell), args: vec![] });
+				addr += 1;
+				continue;
+			}
+		};
+		let mut args: Vec<i32> = vm.memory[addr + 1..addr + 1 + instr.arg_count()].to_vec();
+		if instr == Instr::WriteString {
+			let len = args[0];
+			if len < 0 || addr + 2 + len as usize > end {
+				ret.push(Decoded { addr, instr: Err(cell), args: vec![] });
+				addr += 1;
+				continue;
+			}
+			args = vm.memory[addr + 2..addr + 2 + len as usize].to_vec();
+		}
+		let d = Decoded { addr, instr: Ok(instr), args };
+		addr += d.size();
+		ret.push(d);
+	}
+	ret
+}
+
+/// Produces a listing of the compiled code. Function starts are marked with `.fn N`
+/// (taken from `fn_pointer`), jump targets get `L<addr>` labels.
+pub fn disassemble(vm: &FalseVM) -> String {
+	let code = decode(vm, FIRST_INSTR, vm.code_end);
+
+	let mut labels: HashMap<usize, String> = HashMap::new();
+	let mut fn_starts: HashMap<usize, usize> = HashMap::new();
+	for (n, addr) in vm.fn_pointer.iter() {
+		fn_starts.insert(*addr, *n);
+		labels.insert(*addr, format!("fn{}", n));
+	}
+	let starts: HashSet<usize> = code.iter().map(|d| d.addr).collect();
+	for d in code.iter() {
+		let target = match d.instr {
+			Ok(Instr::Goto) | Ok(Instr::GotoIf) => d.args[0],
+			Ok(_) if vm.fn_refs.contains(&(d.addr + 1)) => d.args[0],
+			_ => continue,
+		};
+		if target >= 0 && starts.contains(&(target as usize)) {
+			labels.entry(target as usize).or_insert_with(|| format!("L{}", target));
+		}
+	}
+	let resolve = |x: i32| -> String {
+		match usize::try_from(x).ok().and_then(|a| labels.get(&a)) {
+			Some(label) => label.clone(),
+			None => x.to_string(),
+		}
+	};
+
+	let mut out = String::new();
+	for (i, d) in code.iter().enumerate() {
+		if let Some(n) = fn_starts.get(&d.addr) {
+			out.push_str(&with_comment(format!(".fn {}", n), &d.addr.to_string()));
+		} else if let Some(label) = labels.get(&d.addr) {
+			out.push_str(&with_comment(format!("{}:", label), &d.addr.to_string()));
+		}
+
+		let instr = match d.instr {
+			Ok(x) => x,
+			Err(cell) => {
+				out.push_str(&format!("\t.word {}\n", cell));
+				continue;
+			}
+		};
+		let line = match instr {
+			Instr::Goto | Instr::GotoIf => format!("\t{} {}", instr.mnemonic(), resolve(d.args[0])),
+			_ if vm.fn_refs.contains(&(d.addr + 1)) => format!("\t{} {}", instr.mnemonic(), resolve(d.args[0])),
+			Instr::WriteString => {
+				let s: String = d.args.iter().map(|c| char::from_u32(*c as u32).unwrap_or('\u{fffd}')).collect();
+				format!("\twritestring {:?}", s)
+			}
+			_ => {
+				let mut line = format!("\t{}", instr.mnemonic());
+				for arg in d.args.iter() {
+					line.push_str(&format!(" {}", arg));
+				}
+				line
+			}
+		};
+
+		// name the variable for `push N` that is followed by a variable access
+		let next = code.get(i + 1).map(|n| n.instr);
+		let is_var_access = next == Some(Ok(Instr::VarRead)) || next == Some(Ok(Instr::VarWrite));
+		let is_var = (instr == Instr::Push && is_var_access) || instr == Instr::LoadVar || instr == Instr::StoreVar;
+		if is_var && (0..26).contains(&d.args[0]) {
+			let var = (b'a' + d.args[0] as u8) as char;
+			out.push_str(&with_comment(line, &var.to_string()));
+		} else {
+			out.push_str(&line);
+			out.push('\n');
+		}
+	}
+	out
+}
+
+fn with_comment(line: String, comment: &str) -> String {
+	// tabs are counted as 8 columns
+	let width = line.replace('\t', "        ").chars().count();
+	format!("{}{}; {}\n", line, " ".repeat(32usize.saturating_sub(width).max(1)), comment)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_decode() {
+		let mut vm = FalseVM::new();
+		vm.load("2 2+\"hi\"", 0).unwrap();
+		let code = decode(&vm, FIRST_INSTR, vm.code_end);
+		assert_eq!(code, vec![
+			Decoded { addr: FIRST_INSTR, instr: Ok(Instr::Push), args: vec![2] },
+			Decoded { addr: FIRST_INSTR + 2, instr: Ok(Instr::Push), args: vec![2] },
+			Decoded { addr: FIRST_INSTR + 4, instr: Ok(Instr::Plus), args: vec![] },
+			Decoded { addr: FIRST_INSTR + 5, instr: Ok(Instr::WriteString), args: vec!['h' as i32, 'i' as i32] },
+			Decoded { addr: FIRST_INSTR + 9, instr: Ok(Instr::Halt), args: vec![] },
+		]);
+	}
+
+	#[test]
+	fn test_invalid_opcode() {
+		let mut vm = FalseVM::new();
+		vm.instr_push_raw(42);
+		vm.instr_push(Instr::Halt);
+		assert_eq!(disassemble(&vm), "\t.word 42\n\thalt\n");
+	}
+
+	#[test]
+	fn test_2plus2() {
+		let mut vm = FalseVM::new();
+		vm.load("2 2+", 0).unwrap();
+		assert_eq!(disassemble(&vm), [
+			".fn 0                           ; 666",
+			"\tpush 2",
+			"\tpush 2",
+			"\tplus",
+			"\thalt",
+			"",
+		].join("\n"));
+	}
+
+	#[test]
+	fn test_lambda_labels() {
+		let mut vm = FalseVM::new();
+		vm.load("[1+]f: 2f;!\"a\nb\"", 0).unwrap();
+		assert_eq!(disassemble(&vm), [
+			".fn 0                           ; 666",
+			"\tpush 1",
+			"\tplus",
+			"\treturn",
+			".fn 1                           ; 670",
+			"\tpush fn0",
+			"\tpush 5                  ; f",
+			"\tvarwrite",
+			"\tpush 2",
+			"\tpush 5                  ; f",
+			"\tvarread",
+			"\tcall",
+			"\twritestring \"a\\nb\"",
+			"\thalt",
+			"",
+		].join("\n"));
+	}
+
+	#[test]
+	fn test_while_labels() {
+		let mut vm = FalseVM::new();
+		vm.load("[1]c:[2]b: c;b;#", 0).unwrap();
+		let listing = disassemble(&vm);
+		assert!(listing.contains("L690:                           ; 690\n\tpickfromcallstack 0\n"));
+		assert!(listing.contains("\tgotoif L690\n"));
+	}
+
+	#[test]
+	fn test_inlined_labels() {
+		let mut vm = FalseVM::new();
+		vm.load("1[2 3+]?", 0).unwrap();
+		assert!(disassemble(&vm).ends_with([
+			".fn 1                           ; 672",
+			"\tpush 1",
+			"\tgotoif L678",
+			"\tgoto L683",
+			"L678:                           ; 678",
+			"\tpush 2",
+			"\tpush 3",
+			"\tplus",
+			"L683:                           ; 683",
+			"\thalt",
+			"",
+		].join("\n").as_str()));
+	}
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+
+
+/// Everything that can go wrong when loading or running a FALSE program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// The program text can't be parsed, `pos` is the char offset in the source. `incomplete`
+	/// is set when more text at the end could fix it, like a `[` or `{` that isn't closed yet.
+	Syntax { pos: usize, message: String, incomplete: bool },
+	/// The program did something invalid while running, like popping an empty stack.
+	Runtime(String),
+	/// The VM ran out of `fuel` before the program ended.
+	OutOfFuel,
+}
+
+impl Error {
+	#[cold]
+	pub fn syntax(pos: usize, message: &str) -> Error {
+		Error::Syntax { pos, message: message.to_string(), incomplete: false }
+	}
+
+	/// A syntax error at the end of the source, see `is_incomplete`.
+	#[cold]
+	pub fn incomplete(pos: usize, message: &str) -> Error {
+		Error::Syntax { pos, message: message.to_string(), incomplete: true }
+	}
+
+	#[cold]
+	pub fn runtime(message: &str) -> Error {
+		Error::Runtime(message.to_string())
+	}
+
+	/// Whether more text at the end of the source could fix this error, like a `[` or `{`
+	/// that isn't closed yet.
+	pub fn is_incomplete(&self) -> bool {
+		match self {
+			Error::Syntax { incomplete, .. } => *incomplete,
+			_ => false,
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Syntax { pos, message, .. } => write!(f, "syntax error at {}: {}", pos, message),
+			Error::Runtime(message) => write!(f, "runtime error: {}", message),
+			Error::OutOfFuel => write!(f, "out of fuel"),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+/// 1-based line and column of char offset `pos` in `code`, for showing `Error::Syntax` positions.
+pub fn line_col(code: &str, pos: usize) -> (usize, usize) {
+	let before: Vec<char> = code.chars().take(pos).collect();
+	let line = before.iter().filter(|c| **c == '\n').count() + 1;
+	let col = before.iter().rev().take_while(|c| **c != '\n').count() + 1;
+	(line, col)
+}
+
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self {
+		Error::Runtime(format!("I/O error: {}", e))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_line_col() {
+		assert_eq!(line_col("abc", 0), (1, 1));
+		assert_eq!(line_col("abc\nde", 5), (2, 2));
+		assert_eq!(line_col("ø\nø", 3), (2, 2));
+	}
+}
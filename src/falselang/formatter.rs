@@ -0,0 +1,111 @@
+use super::tokenizer::Token;
+
+
+/// Turns parser output back into FALSE source. `functions` is laid out like `Parser::lambda_storage`,
+/// main is the last one. Comments are gone by then, and `'c` comes out as the character code.
+///
+/// Tokens are written without spaces, except between two numbers. In main, each `[...]v:`
+/// gets its own line. Parsing the result gives the same `functions` back.
+pub fn format(functions: &[Vec<Token>]) -> String {
+	let mut out = String::new();
+	if let Some(main) = functions.len().checked_sub(1) {
+		write_fn(functions, main, true, &mut out);
+	}
+	out
+}
+
+fn write_fn(functions: &[Vec<Token>], n: usize, is_main: bool, out: &mut String) {
+	let code = &functions[n];
+	for (i, token) in code.iter().enumerate() {
+		if matches!(token, Token::Number(_)) && i > 0 && matches!(code[i - 1], Token::Number(_)) {
+			out.push(' ');
+		}
+		match token {
+			Token::LambdaPointer(m) => {
+				out.push('[');
+				write_fn(functions, *m, false, out);
+				out.push(']');
+			}
+			_ => out.push_str(&token_text(token)),
+		}
+		let assigns_lambda = i >= 2 && *token == Token::VarWrite
+			&& matches!(code[i - 2..i], [Token::LambdaPointer(_), Token::Variable(_)]);
+		if is_main && assigns_lambda && i + 1 < code.len() {
+			out.push('\n');
+		}
+	}
+}
+
+/// Source text of a single token. Uses the spellings that both dialects accept, and writes
+/// negative numbers as `n_`, which reads back as two tokens.
+pub fn token_text(token: &Token) -> String {
+	match token {
+		Token::Number(n) if *n < 0 => format!("{}_", n.unsigned_abs()),
+		Token::Number(n) => n.to_string(),
+		Token::Dup => "$".to_string(),
+		Token::Drop => "%".to_string(),
+		Token::Swap => "\\".to_string(),
+		Token::Rot => "@".to_string(),
+		Token::Pick => "ø".to_string(),
+		Token::Plus => "+".to_string(),
+		Token::Minus => "-".to_string(),
+		Token::Mul => "*".to_string(),
+		Token::Div => "/".to_string(),
+		Token::Negate => "_".to_string(),
+		Token::BitAnd => "&".to_string(),
+		Token::BitOr => "|".to_string(),
+		Token::BitNot => "~".to_string(),
+		Token::GreaterThan => ">".to_string(),
+		Token::Equal => "=".to_string(),
+		Token::LessThan => "<".to_string(),
+		Token::LambdaStart => "[".to_string(),
+		Token::LambdaEnd => "]".to_string(),
+		Token::LambdaExecute => "!".to_string(),
+		Token::LambdaIf => "?".to_string(),
+		Token::LambdaWhile => "#".to_string(),
+		Token::Variable(v) => v.to_string(),
+		Token::VarWrite => ":".to_string(),
+		Token::VarRead => ";".to_string(),
+		Token::ReadChar => "^".to_string(),
+		Token::WriteChar => ",".to_string(),
+		Token::PrintString(s) => format!("\"{}\"", s),
+		Token::WriteInt => ".".to_string(),
+		Token::FlushIO => "ß".to_string(),
+		Token::LambdaPointer(n) => format!("[fn{}]", n),
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::generator::Generator;
+	use crate::falselang::parser::Parser;
+	use crate::falselang::tokenizer::Tokenizer;
+
+	fn parse(code: &str) -> Vec<Vec<Token>> {
+		let mut parser = Parser::new(Tokenizer::new(code));
+		parser.parse().unwrap();
+		parser.lambda_storage
+	}
+
+	#[test]
+	fn test_format() {
+		assert_eq!(format(&parse("1 2 +  3 'a  { comment }\"hi\" P B")), "1 2+3 97\"hi\"øß");
+		assert_eq!(format(&parse("[$1=$[\\%1\\]?~[$1-f;!*]?]f: 6f;!.")), "[$1=$[\\%1\\]?~[$1-f;!*]?]f:\n6f;!.");
+		assert_eq!(format(&parse("")), "");
+		assert_eq!(format(&[vec![Token::Number(-5)]]), "5_");
+	}
+
+	#[test]
+	fn test_round_trip() {
+		for seed in 0..200 {
+			let functions = Generator::new(seed).program();
+			let code = format(&functions);
+			assert_eq!(parse(&code), functions, "seed {}: {}", seed, code);
+		}
+		let code = std::fs::read_to_string("examples/bottles-of-beer.false").unwrap();
+		let functions = parse(&code);
+		assert_eq!(parse(&format(&functions)), functions);
+	}
+}
@@ -0,0 +1,95 @@
+//! Entry points for the fuzz targets in `fuzz/`. They live in the library so that
+//! `tests/fuzz_regressions.rs` can replay the saved crashers without libFuzzer.
+//!
+//! Errors are fine, the programs are random after all. Panics, hangs and running
+//! out of memory are bugs.
+
+use std::io::{self, Write};
+
+use super::parser::Parser;
+use super::threaded::ThreadedCode;
+use super::tokenizer::{Dialect, Tokenizer};
+use super::{vm, vm2};
+
+
+/// Steps each VM may take on a single input.
+pub const FUEL: u64 = 100_000;
+
+/// Output is checked by the differential tests, here it only has to be bounded.
+struct Limited(usize);
+
+impl Write for Limited {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.0 < buf.len() {
+			return Err(io::Error::other("output limit reached"));
+		}
+		self.0 -= buf.len();
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// The first line is the program, everything after it is its input.
+fn split(data: &[u8]) -> (String, Vec<u8>) {
+	let n = data.iter().position(|b| *b == b'\n').unwrap_or(data.len());
+	let input = data.get(n + 1..).unwrap_or_default().to_vec();
+	(String::from_utf8_lossy(&data[..n]).into_owned(), input)
+}
+
+pub fn fuzz_tokenizer(data: &[u8]) {
+	let code = String::from_utf8_lossy(data);
+	for dialect in Dialect::ALL {
+		let mut tokenizer = Tokenizer::with_dialect(&code, dialect);
+		let mut last = 0;
+		while let Ok(Some(_)) = tokenizer.next_token() {
+			assert!(tokenizer.pos() > last, "tokenizer did not advance at {}", last);
+			last = tokenizer.pos();
+		}
+	}
+}
+
+pub fn fuzz_parser(data: &[u8]) {
+	let code = String::from_utf8_lossy(data);
+	for dialect in Dialect::ALL {
+		let tokens = Tokenizer::with_dialect(&code, dialect).all();
+		let mut parser = Parser::new(Tokenizer::with_dialect(&code, dialect));
+		if parser.parse().is_ok() {
+			assert!(tokens.is_ok(), "parser accepted what the tokenizer rejects");
+		}
+	}
+}
+
+/// Runs the tree-walking VM on the first line of `data`.
+pub fn fuzz_vm(data: &[u8]) {
+	let (code, input) = split(data);
+	let mut vm = vm::FalseVM::new();
+	vm.input = Box::new(io::Cursor::new(input));
+	vm.output = Box::new(Limited(1 << 16));
+	vm.fuel = Some(FUEL);
+	if vm.load(&code).is_ok() {
+		let _ = vm.run();
+	}
+}
+
+/// Runs the bytecode VM on the first line of `data`, at every opt level and with every engine.
+pub fn fuzz_vm2(data: &[u8]) {
+	let (code, input) = split(data);
+	for opt_level in 0..=3 {
+		for engine in 0..3 {
+			let mut vm = vm2::FalseVM::new();
+			vm.input = Box::new(io::Cursor::new(input.clone()));
+			vm.output = Box::new(Limited(1 << 16));
+			vm.fuel = Some(FUEL);
+			if vm.load(&code, opt_level).is_err() {
+				return;
+			}
+			let _ = match engine {
+				0 => vm.run(),
+				1 => ThreadedCode::new(&vm).run(&mut vm),
+				_ => ThreadedCode::new(&vm).run_cached(&mut vm),
+			};
+		}
+	}
+}
@@ -0,0 +1,560 @@
+//! Random well-formed FALSE programs for property tests.
+//!
+//! The generator keeps track of what is on the stack while it emits tokens, so the programs never
+//! underflow, never mix up numbers, lambdas and variable references (which vm1 would reject),
+//! never divide by zero, never read a variable before it's written, and always end. Numbers are
+//! kept small enough to give the same results on vm1 (`i64`) and vm2 (`i32`).
+//!
+//! The output is laid out like `Parser::lambda_storage`, `formatter::format` turns it into source.
+
+use super::tokenizer::{Dialect, Token};
+
+
+/// Numbers on the stack and in variables stay within `-LIMIT..=LIMIT`, so that the product of
+/// two of them still fits into an `i32`. Bigger results are masked with `LIMIT&`.
+const LIMIT: i64 = 32767;
+
+/// splitmix64, so that programs only depend on the seed.
+pub struct Rng(u64);
+
+impl Rng {
+	pub fn new(seed: u64) -> Rng {
+		Rng(seed)
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+
+	/// Uniform in `0..n`, `n` must not be 0.
+	pub fn below(&mut self, n: usize) -> usize {
+		(self.next_u64() % n as u64) as usize
+	}
+
+	pub fn chance(&mut self, percent: usize) -> bool {
+		self.below(100) < percent
+	}
+}
+
+/// Stack effect of a lambda, it takes `inputs` numbers and leaves `outputs` numbers.
+/// `cost` is an upper bound of the steps it takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lambda {
+	inputs: usize,
+	outputs: usize,
+	cost: usize,
+}
+
+/// What the generated code has put onto the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+	/// A number between `-bound` and `bound`.
+	Number(i64),
+	Lambda(Lambda),
+	Variable(char),
+}
+
+/// What a variable holds, for the whole program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+	Number,
+	/// Only written by the loop it counts.
+	Counter,
+	/// Written once in main, so lambdas can't end up calling themselves.
+	Lambda(Lambda),
+}
+
+pub struct Generator {
+	pub rng: Rng,
+	/// `<` is only used with `Dialect::Rfalse`.
+	pub dialect: Dialect,
+	/// Most tokens main is made of, lambdas get fewer the deeper they are nested.
+	pub max_len: usize,
+	/// Deepest lambda nesting.
+	pub max_depth: usize,
+	/// Most values a lambda keeps on the stack.
+	pub max_stack: usize,
+	/// Most iterations of a `#` loop.
+	pub max_loop: usize,
+	/// Roughly the most steps a program takes.
+	pub max_cost: usize,
+
+	functions: Vec<Vec<Token>>,
+	kinds: [Option<Kind>; 26],
+	/// Variables that are written on every path to the code being generated.
+	assigned: [bool; 26],
+	/// Counters of the loops the code being generated is in.
+	counting: Vec<char>,
+}
+
+impl Generator {
+	pub fn new(seed: u64) -> Generator {
+		Generator {
+			rng: Rng::new(seed),
+			dialect: Dialect::Rfalse,
+			max_len: 60,
+			max_depth: 3,
+			max_stack: 8,
+			max_loop: 4,
+			max_cost: 20_000,
+			functions: Vec::new(),
+			kinds: [None; 26],
+			assigned: [false; 26],
+			counting: Vec::new(),
+		}
+	}
+
+	/// Generates the next program, main is the last function.
+	pub fn program(&mut self) -> Vec<Vec<Token>> {
+		self.functions.clear();
+		self.kinds = [None; 26];
+		self.assigned = [false; 26];
+		self.counting.clear();
+
+		let mut main = Vec::new();
+		let mut stack = Vec::new();
+		// give the inliner something to do
+		for _ in 0..self.rng.below(4) {
+			self.define(self.max_cost / 8, &mut main);
+		}
+		let len = self.rng.below(self.max_len) + 1;
+		self.block(&mut stack, 0, len, self.max_cost, &mut main);
+		self.settle(&mut stack, None, &mut main);
+		self.functions.push(main);
+		std::mem::take(&mut self.functions)
+	}
+
+	/// Emits `len` random operations, each one taking at most `budget` steps. Returns the cost.
+	fn block(&mut self, stack: &mut Vec<Value>, depth: usize, len: usize, budget: usize, out: &mut Vec<Token>) -> usize {
+		let mut cost = 0;
+		for _ in 0..len {
+			let start = out.len();
+			let mut op_cost = 0;
+			while !self.op(stack, depth, budget.saturating_sub(cost), &mut op_cost, out) {}
+			cost += op_cost + out.len() - start;
+		}
+		cost
+	}
+
+	/// Tries a random operation, returns false if it doesn't fit the current stack.
+	/// `cost` gets the steps taken by called lambdas, `out` already counts one per token.
+	fn op(&mut self, stack: &mut Vec<Value>, depth: usize, budget: usize, cost: &mut usize, out: &mut Vec<Token>) -> bool {
+		let len = stack.len();
+		let room = len < self.max_stack;
+		let top = stack.last().copied();
+		let numbers = stack.iter().rev().take_while(|v| matches!(v, Value::Number(_))).count();
+		let nested = depth < self.max_depth && budget > 50;
+
+		match self.rng.below(24) {
+			0..=2 if room => {
+				let n = match self.rng.below(10) {
+					0 => self.rng.below(LIMIT as usize + 1),
+					1..=3 => self.rng.below(1000),
+					_ => self.rng.below(10),
+				} as i64;
+				out.push(Token::Number(n));
+				stack.push(Value::Number(n));
+			}
+			3 if room && top.is_some() => {
+				out.push(Token::Dup);
+				stack.push(stack[len - 1]);
+			}
+			4 if top.is_some() => {
+				out.push(Token::Drop);
+				stack.pop();
+			}
+			5 if len >= 2 => {
+				out.push(Token::Swap);
+				stack.swap(len - 1, len - 2);
+			}
+			6 if len >= 3 => {
+				out.push(Token::Rot);
+				let x = stack.remove(len - 3);
+				stack.push(x);
+			}
+			7 if room && top.is_some() => {
+				let n = self.rng.below(len);
+				out.extend([Token::Number(n as i64), Token::Pick]);
+				stack.push(stack[len - 1 - n]);
+			}
+			8..=10 if numbers >= 2 => self.binop(stack, out),
+			11 if numbers >= 1 => {
+				let Some(Value::Number(a)) = stack.pop() else { unreachable!() };
+				if self.rng.chance(50) {
+					out.push(Token::Negate);
+					stack.push(Value::Number(a));
+				} else {
+					out.push(Token::BitNot);
+					stack.push(Value::Number(a + 1));
+				}
+				self.fit(stack, out);
+			}
+			12 => return self.variable(stack, out),
+			13 => return self.io(stack, out),
+			14 if nested => {
+				// [...]!
+				let inputs = self.rng.below(numbers.min(3) + 1);
+				let outputs = self.rng.below(3);
+				if len - inputs + outputs > self.max_stack {
+					return false;
+				}
+				let args = stack.split_off(len - inputs);
+				let lambda = self.lambda(args, outputs, depth, budget, out);
+				out.push(Token::LambdaExecute);
+				stack.extend([Value::Number(LIMIT)].repeat(outputs));
+				*cost += lambda.cost;
+			}
+			15 if nested && numbers >= 1 => {
+				// condition[...]?
+				let inputs = self.rng.below(numbers.min(3));
+				stack.pop();
+				let args = stack.split_off(len - 1 - inputs);
+				let lambda = self.lambda(args, inputs, depth, budget, out);
+				out.push(Token::LambdaIf);
+				stack.extend([Value::Number(LIMIT)].repeat(inputs));
+				*cost += lambda.cost;
+			}
+			16 if nested && len < self.max_stack => return self.loop_(stack, numbers, depth, budget, cost, out),
+			17 if room && nested => {
+				// a lambda that's called later
+				let inputs = self.rng.below(3);
+				let outputs = self.rng.below(3);
+				let lambda = self.lambda(vec![Value::Number(LIMIT); inputs], outputs, depth, budget / 4, out);
+				stack.push(Value::Lambda(lambda));
+			}
+			18 | 19 => return self.call(stack, budget, cost, out),
+			20 if depth == 0 => self.define(budget / 4, out),
+			_ => return false,
+		}
+		true
+	}
+
+	/// `+`, `-`, `*`, `/`, `&`, `|` and the comparisons on the two numbers on top of the stack.
+	fn binop(&mut self, stack: &mut Vec<Value>, out: &mut Vec<Token>) {
+		let comparisons = if self.dialect == Dialect::Rfalse { 3 } else { 2 };
+		let choice = self.rng.below(6 + comparisons);
+		if choice == 3 {
+			// can't divide by zero after `1|`
+			out.extend([Token::Number(1), Token::BitOr]);
+		}
+		let (Some(Value::Number(b)), Some(Value::Number(a))) = (stack.pop(), stack.pop()) else { unreachable!() };
+		let (token, bound) = match choice {
+			0 => (Token::Plus, a + b),
+			1 => (Token::Minus, a + b),
+			2 => (Token::Mul, a * b),
+			3 => (Token::Div, a),
+			4 => (Token::BitAnd, (a.max(b) as u64 + 1).next_power_of_two() as i64),
+			5 => (Token::BitOr, (a.max(b) as u64 + 1).next_power_of_two() as i64),
+			6 => (Token::GreaterThan, 1),
+			7 => (Token::Equal, 1),
+			_ => (Token::LessThan, 1),
+		};
+		out.push(token);
+		stack.push(Value::Number(bound));
+		self.fit(stack, out);
+	}
+
+	/// Masks the number on top of the stack if it may be out of `-LIMIT..=LIMIT`.
+	fn fit(&mut self, stack: &mut [Value], out: &mut Vec<Token>) {
+		if let Some(Value::Number(bound)) = stack.last_mut() {
+			if *bound > LIMIT {
+				out.extend([Token::Number(LIMIT), Token::BitAnd]);
+				*bound = LIMIT;
+			}
+		}
+	}
+
+	/// Reads and writes of variables, directly or through a reference on the stack.
+	fn variable(&mut self, stack: &mut Vec<Value>, out: &mut Vec<Token>) -> bool {
+		let v = (b'a' + self.rng.below(26) as u8) as char;
+		let i = v as usize - 'a' as usize;
+		let room = stack.len() < self.max_stack;
+		let writable = matches!(self.kinds[i], None | Some(Kind::Number));
+		let top = stack.last().copied();
+		let second = stack.len().checked_sub(2).map(|n| stack[n]);
+
+		match (self.rng.below(3), top) {
+			// v;
+			(0, _) if room && self.assigned[i] => {
+				out.extend([Token::Variable(v), Token::VarRead]);
+				let value = self.read(v);
+				stack.push(value);
+			}
+			// v:
+			(1, Some(Value::Number(_))) if writable => {
+				out.extend([Token::Variable(v), Token::VarWrite]);
+				stack.pop();
+				self.write(v);
+			}
+			// through a reference pushed earlier
+			(_, Some(Value::Variable(r))) => {
+				let r_index = r as usize - 'a' as usize;
+				if matches!(second, Some(Value::Number(_))) && matches!(self.kinds[r_index], None | Some(Kind::Number)) {
+					out.push(Token::VarWrite);
+					stack.truncate(stack.len() - 2);
+					self.write(r);
+				} else if self.assigned[r_index] {
+					out.push(Token::VarRead);
+					stack.pop();
+					let value = self.read(r);
+					stack.push(value);
+				} else {
+					return false;
+				}
+			}
+			(2, _) if room => {
+				out.push(Token::Variable(v));
+				stack.push(Value::Variable(v));
+			}
+			_ => return false,
+		}
+		true
+	}
+
+	fn read(&self, v: char) -> Value {
+		match self.kinds[v as usize - 'a' as usize] {
+			Some(Kind::Lambda(lambda)) => Value::Lambda(lambda),
+			Some(Kind::Counter) => Value::Number(self.max_loop as i64),
+			_ => Value::Number(LIMIT),
+		}
+	}
+
+	fn write(&mut self, v: char) {
+		let i = v as usize - 'a' as usize;
+		self.kinds[i] = Some(Kind::Number);
+		self.assigned[i] = true;
+	}
+
+	fn io(&mut self, stack: &mut Vec<Value>, out: &mut Vec<Token>) -> bool {
+		let room = stack.len() < self.max_stack;
+		let number_on_top = matches!(stack.last(), Some(Value::Number(_)));
+		match self.rng.below(5) {
+			0 if room => {
+				out.push(Token::ReadChar);
+				stack.push(Value::Number(255));
+			}
+			1 if number_on_top => {
+				// vm1 only writes valid chars, vm2 only the low byte
+				out.extend([Token::Number(127), Token::BitAnd, Token::WriteChar]);
+				stack.pop();
+			}
+			2 if number_on_top => {
+				out.push(Token::WriteInt);
+				stack.pop();
+			}
+			3 => {
+				const CHARS: &[u8] = b"abcxyz ABC.,:;!?{}[]01\n";
+				let n = self.rng.below(6);
+				let s = (0..n).map(|_| CHARS[self.rng.below(CHARS.len())] as char).collect();
+				out.push(Token::PrintString(s));
+			}
+			4 => out.push(Token::FlushIO),
+			_ => return false,
+		}
+		true
+	}
+
+	/// `[...]` for a lambda that takes `inputs` and leaves `outputs` numbers.
+	fn lambda(&mut self, inputs: Vec<Value>, outputs: usize, depth: usize, budget: usize, out: &mut Vec<Token>) -> Lambda {
+		let saved = self.assigned;
+		let lambda_inputs = inputs.len();
+		let mut stack = inputs;
+		let mut body = Vec::new();
+		let len = self.rng.below((self.max_len >> (depth + 1)).max(1)) + 1;
+		let mut cost = self.block(&mut stack, depth + 1, len, budget, &mut body);
+		self.settle(&mut stack, Some(outputs), &mut body);
+		cost += body.len() + 1;
+		self.assigned = saved;
+
+		self.functions.push(body);
+		out.push(Token::LambdaPointer(self.functions.len() - 1));
+		Lambda { inputs: lambda_inputs, outputs, cost }
+	}
+
+	/// `[...]v:` in main.
+	fn define(&mut self, budget: usize, out: &mut Vec<Token>) {
+		let Some(i) = (0..26).map(|_| self.rng.below(26)).find(|i| self.kinds[*i].is_none()) else { return };
+		let v = (b'a' + i as u8) as char;
+		let inputs = self.rng.below(3);
+		let outputs = self.rng.below(3);
+		// keep the body from using it, it can't be read before it's assigned anyway
+		self.kinds[i] = Some(Kind::Lambda(Lambda { inputs, outputs, cost: 0 }));
+		let lambda = self.lambda(vec![Value::Number(LIMIT); inputs], outputs, 0, budget, out);
+		out.extend([Token::Variable(v), Token::VarWrite]);
+		self.kinds[i] = Some(Kind::Lambda(lambda));
+		self.assigned[i] = true;
+	}
+
+	/// `v;!`, `!`, `v;?` or `?` for a lambda in a variable or on the stack.
+	fn call(&mut self, stack: &mut Vec<Value>, budget: usize, cost: &mut usize, out: &mut Vec<Token>) -> bool {
+		let lambda = match stack.last() {
+			Some(Value::Lambda(lambda)) => *lambda,
+			_ => {
+				let i = self.rng.below(26);
+				match self.kinds[i] {
+					Some(Kind::Lambda(lambda)) if self.assigned[i] && stack.len() < self.max_stack => {
+						out.extend([Token::Variable((b'a' + i as u8) as char), Token::VarRead]);
+						stack.push(Value::Lambda(lambda));
+						lambda
+					}
+					_ => return false,
+				}
+			}
+		};
+		stack.pop();
+		let numbers = stack.iter().rev().take_while(|v| matches!(v, Value::Number(_))).count();
+		let fits = stack.len() - lambda.inputs.min(stack.len()) + lambda.outputs <= self.max_stack;
+		if lambda.cost > budget || numbers < lambda.inputs || !fits {
+			// leave it on the stack for later
+			stack.push(Value::Lambda(lambda));
+			return true;
+		}
+		// the condition goes between the arguments and the lambda
+		if lambda.inputs == lambda.outputs && numbers > lambda.inputs && self.rng.chance(40) {
+			out.push(Token::LambdaIf);
+			stack.pop();
+		} else {
+			out.push(Token::LambdaExecute);
+		}
+		stack.truncate(stack.len() - lambda.inputs);
+		stack.extend([Value::Number(LIMIT)].repeat(lambda.outputs));
+		*cost += lambda.cost;
+		true
+	}
+
+	/// `n i: [i;0>][... i;1-i:]#`, or the same with the counter decremented in the condition.
+	fn loop_(&mut self, stack: &mut Vec<Value>, numbers: usize, depth: usize, budget: usize, cost: &mut usize, out: &mut Vec<Token>) -> bool {
+		let iterations = self.max_loop + 1;
+		let body_budget = budget / iterations / 2;
+		if body_budget < 20 {
+			return false;
+		}
+		let Some(i) = (0..26).map(|_| self.rng.below(26)).find(|i| {
+			let v = (b'a' + *i as u8) as char;
+			matches!(self.kinds[*i], None | Some(Kind::Counter)) && !self.counting.contains(&v)
+		}) else {
+			return false;
+		};
+		let v = (b'a' + i as u8) as char;
+		self.kinds[i] = Some(Kind::Counter);
+		self.assigned[i] = true;
+		let n = self.rng.below(self.max_loop) as i64;
+		out.extend([Token::Number(n), Token::Variable(v), Token::VarWrite]);
+
+		let decrement = [Token::Variable(v), Token::VarRead, Token::Number(1), Token::Minus];
+		let in_condition = self.rng.chance(50);
+		let condition = if in_condition {
+			// n+1 iterations
+			[&decrement[..], &[Token::Dup, Token::Variable(v), Token::VarWrite, Token::Number(1), Token::Negate, Token::GreaterThan]].concat()
+		} else {
+			vec![Token::Variable(v), Token::VarRead, Token::Number(0), Token::GreaterThan]
+		};
+		self.functions.push(condition);
+		out.push(Token::LambdaPointer(self.functions.len() - 1));
+
+		self.counting.push(v);
+		let inputs = self.rng.below(numbers.min(3) + 1);
+		let len = stack.len();
+		let args = stack.split_off(len - inputs);
+		let saved = self.assigned;
+		let mut body_stack = args;
+		let mut body = Vec::new();
+		let body_len = self.rng.below((self.max_len >> (depth + 1)).max(1)) + 1;
+		let body_cost = self.block(&mut body_stack, depth + 1, body_len, body_budget, &mut body);
+		self.settle(&mut body_stack, Some(inputs), &mut body);
+		if !in_condition {
+			body.extend(decrement);
+			body.extend([Token::Variable(v), Token::VarWrite]);
+		}
+		self.assigned = saved;
+		self.counting.pop();
+		*cost += iterations * (body_cost + body.len() + 12);
+		self.functions.push(body);
+		out.push(Token::LambdaPointer(self.functions.len() - 1));
+
+		out.push(Token::LambdaWhile);
+		stack.extend([Value::Number(LIMIT)].repeat(inputs));
+		true
+	}
+
+	/// Drops values until only numbers are left, and if `count` is given, makes it that many.
+	fn settle(&mut self, stack: &mut Vec<Value>, count: Option<usize>, out: &mut Vec<Token>) {
+		let limit = count.unwrap_or(usize::MAX);
+		while stack.len() > limit || stack.iter().any(|v| !matches!(v, Value::Number(_))) {
+			out.push(Token::Drop);
+			stack.pop();
+		}
+		while stack.len() < limit && count.is_some() {
+			let n = self.rng.below(10) as i64;
+			out.push(Token::Number(n));
+			stack.push(Value::Number(n));
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::formatter::format;
+	use crate::falselang::vm;
+
+	#[test]
+	fn test_deterministic() {
+		assert_eq!(Generator::new(7).program(), Generator::new(7).program());
+		assert_ne!(Generator::new(7).program(), Generator::new(8).program());
+		let mut generator = Generator::new(7);
+		assert_ne!(generator.program(), generator.program());
+	}
+
+	#[test]
+	fn test_runs() {
+		// no type errors, no underflow, no endless loops
+		for seed in 0..300 {
+			let code = format(&Generator::new(seed).program());
+			let mut vm = vm::FalseVM::new();
+			vm.input = Box::new(std::io::Cursor::new(b"abc".to_vec()));
+			vm.output = Box::new(std::io::sink());
+			vm.fuel = Some(1_000_000);
+			vm.load(&code).unwrap();
+			if let Err(e) = vm.run() {
+				panic!("seed {}: {}\n{}", seed, e, code);
+			}
+		}
+	}
+
+	#[test]
+	fn test_every_token() {
+		let mut seen = Vec::new();
+		let mut generator = Generator::new(1);
+		for _ in 0..100 {
+			for token in generator.program().into_iter().flatten() {
+				let name = format!("{:?}", token);
+				let name = name.split('(').next().unwrap().to_string();
+				if !seen.contains(&name) {
+					seen.push(name);
+				}
+			}
+		}
+		seen.sort();
+		assert_eq!(seen, [
+			"BitAnd", "BitNot", "BitOr", "Div", "Drop", "Dup", "Equal", "FlushIO", "GreaterThan",
+			"LambdaExecute", "LambdaIf", "LambdaPointer", "LambdaWhile", "LessThan", "Minus", "Mul",
+			"Negate", "Number", "Pick", "Plus", "PrintString", "ReadChar", "Rot", "Swap", "VarRead",
+			"VarWrite", "Variable", "WriteChar", "WriteInt",
+		]);
+	}
+
+	#[test]
+	fn test_strict() {
+		let mut generator = Generator::new(3);
+		generator.dialect = Dialect::Strict;
+		for _ in 0..50 {
+			assert!(!generator.program().into_iter().flatten().any(|t| t == Token::LessThan));
+		}
+	}
+}
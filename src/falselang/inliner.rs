@@ -0,0 +1,278 @@
+use std::collections::{HashMap, HashSet};
+
+use super::tokenizer::Token;
+
+
+/// Replaces reads of variables that hold the same literal lambda for the whole run.
+///
+/// A variable qualifies when it is assigned exactly once, by `[...]v:` in the main function,
+/// before main executes anything, and it is never used other than by `v;`. Then `v;!` is
+/// replaced by the lambda body if the body is at most `max_size` tokens, counted after its own
+/// calls are inlined, and can't reach itself. Otherwise it's replaced by a direct call. Other
+/// reads of small lambdas become literal lambdas, so `v;?` and `c;b;#` get compiled as jumps.
+/// `functions` is the parser output, main is the last one. `spans` are the parser's and are
+/// kept in step, inlined tokens keep their own offsets.
+pub fn inline_lambdas(functions: &mut [Vec<Token>], spans: &mut [Vec<usize>], max_size: usize) {
+	let consts = find_consts(functions);
+	if consts.is_empty() {
+		return;
+	}
+	let mut sizes = HashMap::new();
+	for n in consts.values() {
+		expanded_size(functions, &consts, max_size, *n, &mut sizes);
+	}
+	let inlinable: HashSet<usize> = sizes.into_iter().filter_map(|(n, size)| size.map(|_| n)).collect();
+
+	let original = (functions.to_vec(), spans.to_vec());
+	for (n, function) in functions.iter_mut().enumerate() {
+		let mut out = (Vec::with_capacity(function.len()), Vec::with_capacity(function.len() + 1));
+		expand(&original, &consts, &inlinable, n, &mut out);
+		// where the lambda ends
+		out.1.push(original.1[n][function.len()]);
+		(*function, spans[n]) = out;
+	}
+}
+
+/// Variables that hold the same lambda for the whole run, mapped to the lambda's fn index.
+fn find_consts(functions: &[Vec<Token>]) -> HashMap<char, usize> {
+	let mut writes: HashMap<char, usize> = HashMap::new();
+	let mut escaped: HashSet<char> = HashSet::new();
+	for function in functions {
+		for (i, token) in function.iter().enumerate() {
+			match (token, function.get(i + 1)) {
+				(Token::Variable(v), Some(Token::VarWrite)) => *writes.entry(*v).or_insert(0) += 1,
+				(Token::Variable(_), Some(Token::VarRead)) => {}
+				(Token::Variable(v), _) => { escaped.insert(*v); }
+				// a computed variable reference can write to anything
+				(Token::VarWrite, _) if i == 0 || !matches!(function[i - 1], Token::Variable(_)) => return HashMap::new(),
+				_ => {}
+			}
+		}
+	}
+
+	let main = &functions[functions.len() - 1];
+	let mut consts = HashMap::new();
+	for (i, window) in main.windows(3).enumerate() {
+		let (n, v) = match window {
+			[Token::LambdaPointer(n), Token::Variable(v), Token::VarWrite] => (*n, *v),
+			_ => continue,
+		};
+		if writes.get(&v) != Some(&1) || escaped.contains(&v) {
+			continue;
+		}
+		// nothing may run or read the variable before it is assigned
+		let before = &main[..i];
+		let runs_code = before.iter().any(|t| matches!(t, Token::LambdaExecute | Token::LambdaIf | Token::LambdaWhile));
+		if !runs_code && !before.contains(&Token::Variable(v)) {
+			consts.insert(v, n);
+		}
+	}
+	consts
+}
+
+/// Whether running lambda `from` can get to lambda `to`, through nested lambdas and constant variables.
+fn reaches(functions: &[Vec<Token>], consts: &HashMap<char, usize>, from: usize, to: usize) -> bool {
+	let mut seen = HashSet::new();
+	let mut queue = vec![from];
+	while let Some(n) = queue.pop() {
+		for token in functions[n].iter() {
+			let next = match token {
+				Token::LambdaPointer(m) => *m,
+				Token::Variable(v) => match consts.get(v) {
+					Some(m) => *m,
+					None => continue,
+				},
+				_ => continue,
+			};
+			if next == to {
+				return true;
+			}
+			if seen.insert(next) {
+				queue.push(next);
+			}
+		}
+	}
+	false
+}
+
+/// Number of tokens lambda `n` has once its calls are inlined, `None` if it can't be inlined.
+/// Results go to `sizes`. Only non-recursive lambdas are entered, so this terminates.
+fn expanded_size(functions: &[Vec<Token>], consts: &HashMap<char, usize>, max_size: usize, n: usize, sizes: &mut HashMap<usize, Option<usize>>) -> Option<usize> {
+	if let Some(size) = sizes.get(&n) {
+		return *size;
+	}
+	if reaches(functions, consts, n, n) {
+		sizes.insert(n, None);
+		return None;
+	}
+	let code = &functions[n];
+	let mut size = 0;
+	let mut i = 0;
+	while i < code.len() {
+		if let [Token::Variable(v), Token::VarRead, ..] = code[i..] {
+			if let Some(m) = consts.get(&v) {
+				let is_call = code.get(i + 2) == Some(&Token::LambdaExecute);
+				match expanded_size(functions, consts, max_size, *m, sizes) {
+					Some(inner) if is_call => {
+						size += inner;
+						i += 3;
+						continue;
+					}
+					Some(_) => {
+						size += 1;
+						i += 2;
+						continue;
+					}
+					None => {}
+				}
+			}
+		}
+		size += 1;
+		i += 1;
+	}
+	let size = Some(size).filter(|size| *size <= max_size);
+	sizes.insert(n, size);
+	size
+}
+
+/// Appends lambda `n` with its calls inlined to `out`, tokens and their spans.
+fn expand(original: &(Vec<Vec<Token>>, Vec<Vec<usize>>), consts: &HashMap<char, usize>, inlinable: &HashSet<usize>, n: usize, out: &mut (Vec<Token>, Vec<usize>)) {
+	let (code, spans) = (&original.0[n], &original.1[n]);
+	let mut i = 0;
+	while i < code.len() {
+		if let [Token::Variable(v), Token::VarRead, ..] = code[i..] {
+			if let Some(m) = consts.get(&v) {
+				let is_call = code.get(i + 2) == Some(&Token::LambdaExecute);
+				if is_call && inlinable.contains(m) {
+					expand(original, consts, inlinable, *m, out);
+					i += 3;
+					continue;
+				}
+				if is_call || inlinable.contains(m) {
+					out.0.push(Token::LambdaPointer(*m));
+					out.1.push(spans[i]);
+					i += 2;
+					continue;
+				}
+			}
+		}
+		out.0.push(code[i].clone());
+		out.1.push(spans[i]);
+		i += 1;
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::parser::Parser;
+	use crate::falselang::tokenizer::Tokenizer;
+
+	fn inlined(code: &str, max_size: usize) -> Vec<Vec<Token>> {
+		let mut parser = Parser::new(Tokenizer::new(code));
+		parser.parse().unwrap();
+		let mut functions = parser.lambda_storage;
+		inline_lambdas(&mut functions, &mut parser.spans, max_size);
+		for (function, spans) in functions.iter().zip(parser.spans.iter()) {
+			assert_eq!(spans.len(), function.len() + 1);
+		}
+		functions
+	}
+
+	fn parsed(code: &str) -> Vec<Vec<Token>> {
+		let mut parser = Parser::new(Tokenizer::new(code));
+		parser.parse().unwrap();
+		parser.lambda_storage
+	}
+
+	#[test]
+	fn test_inline() {
+		assert_eq!(inlined("[1+]f: 2f;! f;", 8)[1], vec![
+			Token::LambdaPointer(0), Token::Variable('f'), Token::VarWrite,
+			Token::Number(2), Token::Number(1), Token::Plus,
+			Token::LambdaPointer(0),
+		]);
+	}
+
+	#[test]
+	fn test_spans() {
+		let mut parser = Parser::new(Tokenizer::new("[1+]f: 2f;! f;"));
+		parser.parse().unwrap();
+		inline_lambdas(&mut parser.lambda_storage, &mut parser.spans, 8);
+		// the body keeps the offsets it has in f, `f;` becomes a lambda at the `f`
+		assert_eq!(parser.spans[1], vec![0, 4, 5, 7, 1, 2, 12, 14]);
+	}
+
+	#[test]
+	fn test_inline_nested() {
+		// g's body is expanded before it's spliced into main
+		assert_eq!(inlined("[1+]f: [f;!f;!]g: 2g;!", 8)[2][6..], [
+			Token::Number(2), Token::Number(1), Token::Plus, Token::Number(1), Token::Plus,
+		]);
+	}
+
+	#[test]
+	fn test_too_big() {
+		// direct call instead
+		assert_eq!(inlined("[1+]f: 2f;!", 1)[1][3..], [Token::Number(2), Token::LambdaPointer(0), Token::LambdaExecute]);
+		// other reads are left alone
+		assert_eq!(inlined("[1+]f: 2f;", 1)[1][3..], [Token::Number(2), Token::Variable('f'), Token::VarRead]);
+	}
+
+	#[test]
+	fn test_nested_too_big() {
+		// each lambda calls the previous one twice, expanding all of them would take 2^25 tokens
+		let mut code = "[1+]a: ".to_string();
+		for (prev, v) in ('a'..='y').zip('b'..='z') {
+			code += &format!("[{0};!{0};!]{1}: ", prev, v);
+		}
+		code += "0z;!";
+		let functions = inlined(&code, 16);
+		// d is 16 tokens, so e gets it spliced in twice, but e itself is too big for f
+		assert_eq!(functions[4], (0..16).flat_map(|_| [Token::Number(1), Token::Plus]).collect::<Vec<_>>());
+		assert_eq!(functions[5], vec![Token::LambdaPointer(4), Token::LambdaExecute, Token::LambdaPointer(4), Token::LambdaExecute]);
+		assert!(functions[..26].iter().all(|f| f.len() <= 16 * 2));
+	}
+
+	#[test]
+	fn test_if_while() {
+		assert_eq!(inlined("[$0>]c: [1-]b: 5c;b;# 1b;?", 8)[2][6..], [
+			Token::Number(5), Token::LambdaPointer(0), Token::LambdaPointer(1), Token::LambdaWhile,
+			Token::Number(1), Token::LambdaPointer(1), Token::LambdaIf,
+		]);
+	}
+
+	#[test]
+	fn test_recursion() {
+		let code = "[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;!";
+		let functions = inlined(code, 100);
+		assert_eq!(functions[0], vec![
+			Token::Number(1), Token::Minus, Token::Dup, Token::LambdaPointer(1), Token::LambdaExecute,
+			Token::Swap, Token::Number(1), Token::Minus, Token::LambdaPointer(1), Token::LambdaExecute, Token::Plus,
+		]);
+		assert_eq!(functions[1], parsed(code)[1]);
+		assert_eq!(functions[2][3..], [Token::Number(12), Token::LambdaPointer(1), Token::LambdaExecute]);
+
+		// mutual recursion
+		let functions = inlined("[g;!]f: [f;!]g: f;!", 100);
+		assert_eq!(functions[0], vec![Token::LambdaPointer(1), Token::LambdaExecute]);
+		assert_eq!(functions[1], vec![Token::LambdaPointer(0), Token::LambdaExecute]);
+	}
+
+	#[test]
+	fn test_not_const() {
+		for code in [
+			"[1+]f: [2+]f: 3f;!", // reassigned
+			"[1+]f: [[2+]f:]! 3f;!", // reassigned in a lambda
+			"3f: [1+]f: f;!", // assigned a number first
+			"f;! [1+]f: 3f;!", // read before it is assigned
+			"[f;!]! [1+]f: 3f;!", // called before it is assigned
+			"[1+]f: [2+]5: 3f;!", // computed variable reference
+			"[1+]f: f. 3f;!", // reference that escapes
+			"[[1+]f:]! 3f;!", // assigned outside of main
+		] {
+			assert_eq!(inlined(code, 100), parsed(code), "{:?}", code);
+		}
+	}
+}
@@ -0,0 +1,402 @@
+use std::cell::{Ref, RefCell};
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use super::error::Error;
+use super::threaded::ThreadedCode;
+use super::tokenizer::Dialect;
+use super::vm::{self, StackElement};
+use super::vm2;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+	OK,
+	End,
+}
+
+/// Common interface of the tree-walking VM (`vm`) and the bytecode VM (`vm2`).
+pub trait Interpreter {
+	/// Compiles `code` to run next. `opt_level` only matters to the bytecode VM, see `vm2::FalseVM::load`.
+	fn load(&mut self, code: &str, opt_level: u8) -> Result<(), Error>;
+	/// Runs until the end of the main function. This may use a faster engine than `step`
+	/// (vm2 runs `ThreadedCode::run_cached`), which ends in the same state and uses the same
+	/// fuel, one per instruction, as calling `step` until the end or the first error. Operands
+	/// the failing instruction popped stay popped either way.
+	fn run(&mut self) -> Result<(), Error>;
+	/// Executes a single instruction.
+	fn step(&mut self) -> Result<StepResult, Error>;
+	/// Data stack contents, bottom first.
+	fn data_stack(&self) -> Vec<StackElement>;
+	/// Number of values on the data stack.
+	fn stack_depth(&self) -> usize;
+	/// Entries on the call stack: lambdas and loops that are still running. Engines count
+	/// differently, vm2 uses a return address per call and more than one entry per loop.
+	fn call_depth(&self) -> usize;
+	/// Value of variable `a`..`z`, `None` if it can't be told.
+	fn variable(&self, name: char) -> Option<StackElement>;
+	/// Replaces the streams used by `^`, `,`, `.`, strings and `ß`.
+	fn set_io(&mut self, input: Box<dyn Read>, output: Box<dyn Write>);
+	/// Language flavor for the following `load` calls.
+	fn set_dialect(&mut self, dialect: Dialect);
+	/// Limits the steps and the data stack, `run` and `step` fail with `Error::OutOfFuel` or a
+	/// stack overflow past them.
+	fn set_limits(&mut self, limits: Limits);
+}
+
+/// Resources a program may use, `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+	/// Steps before running fails with `Error::OutOfFuel`.
+	pub steps: Option<u64>,
+	/// Values on the data stack before running fails with a stack overflow.
+	pub stack: Option<usize>,
+}
+
+/// Parses `steps=N,stack=N`, either one may be left out.
+impl FromStr for Limits {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut limits = Limits::default();
+		for item in s.split(',').filter(|item| !item.is_empty()) {
+			let (name, value) = item.split_once('=').ok_or_else(|| format!("expected name=value, got {:?}", item))?;
+			let invalid = |_| format!("invalid {} limit: {:?}", name, value);
+			match name {
+				"steps" => limits.steps = Some(value.parse().map_err(invalid)?),
+				"stack" => limits.stack = Some(value.parse().map_err(invalid)?),
+				_ => return Err(format!("unknown limit: {}", name)),
+			}
+		}
+		Ok(limits)
+	}
+}
+
+/// Output for `Interpreter::set_io` that can still be read after the VM took it, clones share
+/// what was written.
+#[derive(Debug, Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+	/// What was written so far.
+	pub fn bytes(&self) -> Ref<'_, [u8]> {
+		Ref::map(self.0.borrow(), |v| v.as_slice())
+	}
+
+	/// What was written so far, with invalid UTF-8 replaced.
+	pub fn text(&self) -> String {
+		String::from_utf8_lossy(&self.0.borrow()).into_owned()
+	}
+}
+
+impl Write for Capture {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.borrow_mut().write(buf)
+	}
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Reads one byte for `^`, -1 on end of input. Both engines read through this.
+pub fn read_char(input: &mut dyn Read) -> i32 {
+	let mut buf = [0u8; 1];
+	match input.read(&mut buf) {
+		Ok(1) => buf[0] as i32,
+		_ => -1,
+	}
+}
+
+impl Interpreter for vm::FalseVM {
+	fn load(&mut self, code: &str, _opt_level: u8) -> Result<(), Error> {
+		self.load(code)
+	}
+
+	fn run(&mut self) -> Result<(), Error> {
+		self.run()
+	}
+
+	fn step(&mut self) -> Result<StepResult, Error> {
+		self.step()
+	}
+
+	fn data_stack(&self) -> Vec<StackElement> {
+		self.stack.clone()
+	}
+
+	fn stack_depth(&self) -> usize {
+		self.stack.len()
+	}
+
+	fn call_depth(&self) -> usize {
+		self.frames.len()
+	}
+
+	fn variable(&self, name: char) -> Option<StackElement> {
+		self.variables.get(&name).cloned()
+	}
+
+	fn set_io(&mut self, input: Box<dyn Read>, output: Box<dyn Write>) {
+		self.input = input;
+		self.output = output;
+	}
+
+	fn set_dialect(&mut self, dialect: Dialect) {
+		self.dialect = dialect;
+	}
+
+	fn set_limits(&mut self, limits: Limits) {
+		self.fuel = limits.steps;
+		self.max_stack = limits.stack;
+	}
+}
+
+/// Values are plain numbers in vm2, lambdas show up as their addresses.
+impl Interpreter for vm2::FalseVM {
+	fn load(&mut self, code: &str, opt_level: u8) -> Result<(), Error> {
+		self.load(code, opt_level)
+	}
+
+	fn run(&mut self) -> Result<(), Error> {
+		ThreadedCode::new(self).run_cached(self)
+	}
+
+	fn step(&mut self) -> Result<StepResult, Error> {
+		self.step()
+	}
+
+	fn data_stack(&self) -> Vec<StackElement> {
+		self.memory[self.stack_pointer..].iter().rev().map(|x| StackElement::Number(*x as i64)).collect()
+	}
+
+	fn stack_depth(&self) -> usize {
+		self.stack_size()
+	}
+
+	fn call_depth(&self) -> usize {
+		self.callstack_pointer + 1 - vm2::CALL_STACK_START
+	}
+
+	fn variable(&self, name: char) -> Option<StackElement> {
+		if !name.is_ascii_lowercase() {
+			return None;
+		}
+		let n = vm2::FIRST_VAR + (name as u8 - b'a') as usize;
+		Some(StackElement::Number(self.memory[n] as i64))
+	}
+
+	fn set_io(&mut self, input: Box<dyn Read>, output: Box<dyn Write>) {
+		self.input = input;
+		self.output = output;
+	}
+
+	fn set_dialect(&mut self, dialect: Dialect) {
+		self.dialect = dialect;
+	}
+
+	fn set_limits(&mut self, limits: Limits) {
+		self.fuel = limits.steps;
+		self.max_stack = limits.stack;
+	}
+}
+
+/// What a run used, see `run_with_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+	pub steps: u64,
+	pub max_stack: usize,
+	pub max_calls: usize,
+}
+
+/// Runs `vm` one step at a time and records what it uses in `stats`, which is also filled in
+/// when running fails. Much slower than `Interpreter::run`.
+pub fn run_with_stats(vm: &mut dyn Interpreter, stats: &mut Stats) -> Result<(), Error> {
+	loop {
+		stats.max_stack = stats.max_stack.max(vm.stack_depth());
+		stats.max_calls = stats.max_calls.max(vm.call_depth());
+		match vm.step()? {
+			StepResult::OK => stats.steps += 1,
+			StepResult::End => return Ok(()),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+	Tree, // vm::FalseVM
+	Bytecode, // vm2::FalseVM
+}
+
+impl Engine {
+	pub const ALL: [Engine; 2] = [Engine::Tree, Engine::Bytecode];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Engine::Tree => "tree",
+			Engine::Bytecode => "bytecode",
+		}
+	}
+
+	pub fn create(&self) -> Box<dyn Interpreter> {
+		match self {
+			Engine::Tree => Box::new(vm::FalseVM::new()),
+			Engine::Bytecode => Box::new(vm2::FalseVM::new()),
+		}
+	}
+}
+
+impl FromStr for Engine {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Engine::ALL.iter().find(|e| e.name() == s).copied().ok_or_else(|| format!("unknown engine: {}", s))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn run(engine: Engine, code: &str, input: &str) -> (String, Box<dyn Interpreter>) {
+		let buf = Capture::default();
+		let mut vm = engine.create();
+		vm.set_io(Box::new(std::io::Cursor::new(input.as_bytes().to_vec())), Box::new(buf.clone()));
+		vm.load(code, 0).unwrap();
+		vm.run().unwrap();
+		let out = String::from_utf8(buf.bytes().to_vec()).unwrap();
+		(out, vm)
+	}
+
+	#[test]
+	fn test_engines() {
+		for engine in Engine::ALL {
+			let (out, vm) = run(engine, "[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;! 3a: \"fib\" 5.", "");
+			assert_eq!(out, "fib5", "{:?}", engine);
+			assert_eq!(vm.data_stack(), vec![StackElement::Number(144)], "{:?}", engine);
+			assert_eq!(vm.variable('a'), Some(StackElement::Number(3)), "{:?}", engine);
+		}
+	}
+
+	#[test]
+	fn test_io() {
+		for engine in Engine::ALL {
+			let (out, vm) = run(engine, "^^,, ^ 2 3< 3 2< ß", "ab");
+			assert_eq!(out, "ba", "{:?}", engine);
+			assert_eq!(vm.data_stack(), vec![StackElement::Number(-1), StackElement::Number(-1), StackElement::Number(0)], "{:?}", engine);
+		}
+	}
+
+	#[test]
+	fn test_step() {
+		for engine in Engine::ALL {
+			let mut vm = engine.create();
+			vm.load("[1+]f: 2f;!", 0).unwrap();
+			let mut steps = 0;
+			while vm.step().unwrap() == StepResult::OK {
+				steps += 1;
+			}
+			assert!(steps > 4, "{:?}", engine);
+			assert_eq!(vm.data_stack(), vec![StackElement::Number(3)], "{:?}", engine);
+		}
+	}
+
+	#[test]
+	fn test_errors() {
+		for engine in Engine::ALL {
+			let mut vm = engine.create();
+			assert_eq!(vm.load("1 \"abc", 0), Err(Error::incomplete(2, "unterminated string")), "{:?}", engine);
+			vm.load("1 2+ +", 0).unwrap();
+			assert_eq!(vm.run(), Err(Error::runtime("stack underflow")), "{:?}", engine);
+			vm.load("5 0/", 0).unwrap();
+			assert_eq!(vm.run(), Err(Error::runtime("division by zero")), "{:?}", engine);
+		}
+	}
+
+	/// Runs `code` to the end or the first error, with `run` or by stepping.
+	fn finish(vm: &mut dyn Interpreter, code: &str, opt_level: u8, run: bool) -> (Result<(), Error>, Vec<StackElement>, Vec<Option<StackElement>>, usize) {
+		vm.set_limits(Limits { steps: Some(10000), stack: None });
+		vm.load(code, opt_level).unwrap();
+		let result = if run {
+			vm.run()
+		} else {
+			loop {
+				match vm.step() {
+					Ok(StepResult::OK) => {}
+					result => break result.map(|_| ()),
+				}
+			}
+		};
+		(result, vm.data_stack(), ('a'..='z').map(|v| vm.variable(v)).collect(), vm.call_depth())
+	}
+
+	#[test]
+	fn test_run_same_as_step() {
+		let programs = ["5 0/", "7 1 2 0/", "1 2+ +", "3a: 1\\", "[f;! 1]f: f;!", "2b: [$ 3 = [0 0/]? 1+ $b:]f: 0 f;! f;! f;! f;!", "1 [3c: 50;!]!", "9 [$0>][1-]#%1 2[[0 0/]!]?"];
+		for code in programs {
+			let (mut a, mut b) = (vm::FalseVM::new(), vm::FalseVM::new());
+			assert_eq!(finish(&mut a, code, 0, true), finish(&mut b, code, 0, false), "{:?}", code);
+			assert_eq!((a.cursor, a.fn_index, a.fuel), (b.cursor, b.fn_index, b.fuel), "{:?}", code);
+			for opt_level in 0..=3 {
+				let (mut a, mut b) = (vm2::FalseVM::new(), vm2::FalseVM::new());
+				let (run, step) = (finish(&mut a, code, opt_level, true), finish(&mut b, code, opt_level, false));
+				assert!(run.0.is_err(), "{:?} at level {}", code, opt_level);
+				assert_eq!(run, step, "{:?} at level {}", code, opt_level);
+				assert_eq!((a.cursor, a.fuel), (b.cursor, b.fuel), "{:?} at level {}", code, opt_level);
+			}
+		}
+	}
+
+	#[test]
+	fn test_fuel() {
+		for engine in Engine::ALL {
+			let mut vm = engine.create();
+			vm.set_limits(Limits { steps: Some(10000), stack: None });
+			vm.load("[1_][]#", 0).unwrap();
+			assert_eq!(vm.run(), Err(Error::OutOfFuel), "{:?}", engine);
+			assert_eq!(vm.step(), Err(Error::OutOfFuel), "{:?}", engine);
+		}
+	}
+
+	#[test]
+	fn test_limits() {
+		assert_eq!("steps=100,stack=5".parse(), Ok(Limits { steps: Some(100), stack: Some(5) }));
+		assert_eq!("stack=5".parse(), Ok(Limits { steps: None, stack: Some(5) }));
+		assert!("steps=-1".parse::<Limits>().is_err());
+		assert!("depth=3".parse::<Limits>().is_err());
+		for engine in Engine::ALL {
+			let mut vm = engine.create();
+			vm.set_limits("stack=10".parse().unwrap());
+			vm.load("[1][1]#", 0).unwrap();
+			assert_eq!(vm.run(), Err(Error::runtime("stack overflow")), "{:?}", engine);
+			assert_eq!(vm.data_stack().len(), 10, "{:?}", engine);
+		}
+	}
+
+	#[test]
+	fn test_stats() {
+		for engine in Engine::ALL {
+			let mut vm = engine.create();
+			vm.load("[[1 2 3]!%%%]f: f;! 4", 0).unwrap();
+			let mut stats = Stats::default();
+			run_with_stats(vm.as_mut(), &mut stats).unwrap();
+			assert_eq!(stats.max_stack, 3, "{:?}", engine);
+			assert_eq!(stats.max_calls, 2, "{:?}", engine);
+			assert!(stats.steps >= 10, "{:?}", engine);
+			assert_eq!((vm.stack_depth(), vm.call_depth()), (1, 0), "{:?}", engine);
+
+			let mut stats = Stats::default();
+			vm.load("1 2 0/", 0).unwrap();
+			assert!(run_with_stats(vm.as_mut(), &mut stats).is_err());
+			assert_eq!(stats.max_stack, 4, "{:?}", engine);
+		}
+	}
+
+	#[test]
+	fn test_engine_names() {
+		assert_eq!("tree".parse(), Ok(Engine::Tree));
+		assert_eq!("bytecode".parse(), Ok(Engine::Bytecode));
+		assert!("vm3".parse::<Engine>().is_err());
+	}
+}
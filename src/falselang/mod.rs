@@ -1,4 +1,19 @@
+pub mod error;
 pub mod tokenizer;
 pub mod parser;
 pub mod vm;
 pub mod vm2;
+pub mod disasm;
+pub mod asm;
+pub mod optimizer;
+pub mod inliner;
+pub mod interpreter;
+pub mod threaded;
+pub mod fuzz;
+pub mod formatter;
+pub mod generator;
+pub mod debugger;
+pub mod profiler;
+pub mod coverage;
+pub mod trace;
+pub mod api;
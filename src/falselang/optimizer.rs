@@ -0,0 +1,526 @@
+use std::collections::{HashMap, HashSet};
+
+use super::disasm::decode;
+use super::vm2::{FalseVM, Instr};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Arg {
+	None,
+	Num(i32),
+	Addr(usize), // original address, relocated on emit
+	Text(Vec<i32>),
+}
+
+/// Number of ops consumed by a rewrite rule and what they are replaced with.
+type Rewrite = Option<(usize, Vec<Op>)>;
+
+#[derive(Debug, Clone)]
+struct Op {
+	labels: Vec<usize>, // original addresses that jump here
+	instr: Result<Instr, i32>,
+	arg: Arg,
+	spans: Vec<usize>, // see `FalseVM::spans`
+}
+
+impl Op {
+	fn new(instr: Instr, arg: Arg) -> Op {
+		Op { labels: vec![], instr: Ok(instr), arg, spans: vec![] }
+	}
+
+	fn size(&self) -> usize {
+		match (&self.instr, &self.arg) {
+			(Err(_), _) => 1,
+			(Ok(_), Arg::None) => 1,
+			(Ok(_), Arg::Text(s)) => 2 + s.len(),
+			(Ok(_), _) => 2,
+		}
+	}
+
+	fn push_num(&self) -> Option<i32> {
+		match (&self.instr, &self.arg) {
+			(Ok(Instr::Push), Arg::Num(x)) => Some(*x),
+			_ => None,
+		}
+	}
+
+	fn is(&self, instr: Instr) -> bool {
+		self.instr == Ok(instr)
+	}
+
+	/// How many values the op pops and pushes, None if that isn't known from the op alone.
+	fn stack_effect(&self) -> Option<(usize, usize)> {
+		match self.instr.ok()? {
+			Instr::Push | Instr::ReadChar | Instr::PickFromCallStack | Instr::LoadVar => Some((0, 1)),
+			Instr::Dup | Instr::DupGtImm => Some((1, 2)),
+			Instr::Drop | Instr::WriteChar | Instr::WriteInt | Instr::MoveToCallStack | Instr::StoreVar | Instr::GotoIf => Some((1, 0)),
+			Instr::Swap => Some((2, 2)),
+			Instr::Rot => Some((3, 3)),
+			Instr::Pick | Instr::Negate | Instr::BitNot | Instr::VarRead | Instr::AddImm | Instr::GtImm | Instr::EqImm => Some((1, 1)),
+			Instr::Plus | Instr::Minus | Instr::Mul | Instr::Div | Instr::BitAnd | Instr::BitOr | Instr::Gt | Instr::Eq | Instr::LessThan => Some((2, 1)),
+			Instr::VarWrite => Some((2, 0)),
+			Instr::Noop | Instr::WriteString | Instr::FlushIO | Instr::DropFromCallStack => Some((0, 0)),
+			_ => None,
+		}
+	}
+}
+
+/// Rewrites the compiled code from `start` on in place. Jump targets, function pointers and
+/// lambda addresses are relocated, so the program behaves exactly as before. Code before
+/// `start` is left alone, lambdas from earlier loads may still be on the stack or in variables.
+pub fn optimize(vm: &mut FalseVM, start: usize, opt_level: u8) {
+	let mut ops = lift(vm, start);
+	peephole(&mut ops, simplify);
+	if opt_level >= 2 {
+		peephole(&mut ops, fuse);
+	}
+	lower(vm, start, ops);
+}
+
+/// Turns the memory image into a list of ops with symbolic addresses.
+fn lift(vm: &FalseVM, start: usize) -> Vec<Op> {
+	let code = decode(vm, start, vm.code_end);
+
+	let mut targets: HashSet<usize> = vm.fn_pointer.values().copied().collect();
+	targets.insert(vm.cursor);
+	for d in code.iter() {
+		match d.instr {
+			Ok(Instr::Goto) | Ok(Instr::GotoIf) => { targets.insert(d.args[0] as usize); }
+			Ok(_) if vm.fn_refs.contains(&(d.addr + 1)) => { targets.insert(d.args[0] as usize); }
+			_ => {}
+		}
+	}
+
+	code.into_iter().map(|d| {
+		let arg = match d.instr {
+			Ok(Instr::Goto) | Ok(Instr::GotoIf) => Arg::Addr(d.args[0] as usize),
+			Ok(_) if vm.fn_refs.contains(&(d.addr + 1)) => Arg::Addr(d.args[0] as usize),
+			Ok(Instr::WriteString) => Arg::Text(d.args),
+			_ => match d.args.first() {
+				Some(x) => Arg::Num(*x),
+				None => Arg::None,
+			},
+		};
+		let labels = if targets.contains(&d.addr) { vec![d.addr] } else { vec![] };
+		let spans = vm.spans.get(&d.addr).cloned().unwrap_or_default();
+		Op { labels, instr: d.instr, arg, spans }
+	}).collect()
+}
+
+/// Writes ops back to memory, relocating every address.
+fn lower(vm: &mut FalseVM, start: usize, ops: Vec<Op>) {
+	let mut relocations: HashMap<usize, usize> = HashMap::new();
+	let mut addr = start;
+	for op in ops.iter() {
+		for label in op.labels.iter() {
+			relocations.insert(*label, addr);
+		}
+		addr += op.size();
+	}
+	let relocate = |a: usize| *relocations.get(&a).unwrap_or(&a);
+
+	for cell in vm.memory[start..vm.code_end].iter_mut() {
+		*cell = 0;
+	}
+	vm.fn_refs.retain(|cell| *cell < start);
+	vm.spans.retain(|addr, _| *addr < start);
+	vm.code_end = start;
+	let entrypoint = relocate(vm.cursor);
+	vm.goto(start);
+	for op in ops {
+		if !op.spans.is_empty() {
+			vm.spans.insert(vm.cursor, op.spans);
+		}
+		match (op.instr, op.arg) {
+			(Err(cell), _) => vm.instr_push_raw(cell),
+			(Ok(i), Arg::None) => vm.instr_push(i),
+			(Ok(i), Arg::Num(x)) => vm.instr_push1(i, x),
+			(Ok(i), Arg::Addr(a)) => {
+				vm.instr_push1(i, relocate(a) as i32);
+				if i.takes_fn_address() {
+					vm.fn_refs.insert(vm.cursor - 1);
+				}
+			}
+			(Ok(i), Arg::Text(s)) => {
+				vm.instr_push1(i, s.len() as i32);
+				for c in s {
+					vm.instr_push_raw(c);
+				}
+			}
+		}
+	}
+	for addr in vm.fn_pointer.values_mut() {
+		*addr = relocate(*addr);
+	}
+	vm.goto(entrypoint);
+}
+
+/// Applies `rule` until nothing changes. The rule sees the ops from the current position
+/// up to the next jump target and how many values are surely on the stack before them,
+/// returns the number of ops consumed and their replacement.
+fn peephole(ops: &mut Vec<Op>, rule: fn(&[Op], usize) -> Rewrite) {
+	// depth[k] is what is known about the stack before ops[k], nothing at a jump target
+	let mut depth = vec![0];
+	let mut i = 0;
+	while i < ops.len() {
+		let window = ops[i + 1..].iter().take_while(|op| op.labels.is_empty()).count() + 1;
+		match rule(&ops[i..i + window], depth[i]) {
+			Some((n, mut replacement)) => {
+				let mut labels: Vec<usize> = ops[i..i + n].iter().flat_map(|op| op.labels.clone()).collect();
+				// the replacement stands for all the tokens, if nothing is left they don't show as run
+				let spans: Vec<usize> = ops[i..i + n].iter().flat_map(|op| op.spans.clone()).collect();
+				for (k, op) in replacement.iter_mut().enumerate() {
+					op.spans = if k == 0 { spans.clone() } else { vec![] };
+				}
+				let _: Vec<Op> = ops.splice(i..i + n, replacement).collect();
+				// labels move to whatever comes next, there is always a Return or Halt at the end
+				labels.append(&mut ops[i].labels);
+				ops[i].labels = labels;
+				// a rewrite may complete a pattern that starts earlier
+				i = i.saturating_sub(2);
+				depth.truncate(i + 1);
+			}
+			None => {
+				// an op that ran had what it pops on the stack
+				let known = match ops[i].stack_effect() {
+					Some((pops, pushes)) if window > 1 => depth[i].max(pops) - pops + pushes,
+					_ => 0,
+				};
+				depth.push(known);
+				i += 1;
+			}
+		}
+	}
+}
+
+/// Folds constants, drops no-op pairs and reduces strength. Ops are only dropped when
+/// `depth` shows that they can't underflow the stack, so that the error stays.
+fn simplify(ops: &[Op], depth: usize) -> Rewrite {
+	if let [a, b, op, ..] = ops {
+		if let (Some(a), Some(b)) = (a.push_num(), b.push_num()) {
+			if let Some(x) = fold_binary(op.instr, a, b) {
+				return Some((3, vec![Op::new(Instr::Push, Arg::Num(x))]));
+			}
+		}
+	}
+
+	if let [a, b, ..] = ops {
+		if let Some(x) = a.push_num() {
+			// constant folding of unary ops
+			let folded = match b.instr {
+				Ok(Instr::Negate) => x.checked_neg(),
+				Ok(Instr::BitNot) => Some(!x),
+				_ => None,
+			};
+			if let Some(x) = folded {
+				return Some((2, vec![Op::new(Instr::Push, Arg::Num(x))]));
+			}
+			// identity elements
+			let identity = matches!((x, b.instr),
+				(0, Ok(Instr::Plus)) | (0, Ok(Instr::Minus)) | (0, Ok(Instr::BitOr)) |
+				(1, Ok(Instr::Mul)) | (1, Ok(Instr::Div)) | (-1, Ok(Instr::BitAnd)));
+			if identity && depth >= 1 {
+				return Some((2, vec![]));
+			}
+			// strength reduction
+			match (x, b.instr) {
+				(-1, Ok(Instr::Mul)) => return Some((2, vec![Op::new(Instr::Negate, Arg::None)])),
+				(2, Ok(Instr::Mul)) => return Some((2, vec![Op::new(Instr::Dup, Arg::None), Op::new(Instr::Plus, Arg::None)])),
+				_ => {}
+			}
+		}
+		// pushing something only to drop it
+		if a.is(Instr::Push) && b.is(Instr::Drop) {
+			return Some((2, vec![]));
+		}
+		// pairs that cancel out
+		let no_op = (a.is(Instr::Dup) && b.is(Instr::Drop) && depth >= 1)
+			|| (a.is(Instr::Swap) && b.is(Instr::Swap) && depth >= 2)
+			|| (a.is(Instr::Negate) && b.is(Instr::Negate) && depth >= 1)
+			|| (a.is(Instr::BitNot) && b.is(Instr::BitNot) && depth >= 1);
+		if no_op {
+			return Some((2, vec![]));
+		}
+		// operand order doesn't matter for commutative ops
+		let commutative = [Instr::Plus, Instr::Mul, Instr::BitAnd, Instr::BitOr, Instr::Eq];
+		if a.is(Instr::Swap) && commutative.iter().any(|i| b.is(*i)) {
+			return Some((2, vec![b.clone()]));
+		}
+	}
+
+	None
+}
+
+/// Replaces common sequences with superinstructions.
+fn fuse(ops: &[Op], _depth: usize) -> Rewrite {
+	if let [a, b, c, ..] = ops {
+		if let (true, Some(n), true) = (a.is(Instr::Dup), b.push_num(), c.is(Instr::Gt)) {
+			return Some((3, vec![Op::new(Instr::DupGtImm, Arg::Num(n))]));
+		}
+	}
+
+	if let [a, b, ..] = ops {
+		if a.is(Instr::Push) {
+			// these accept lambda addresses as well as numbers
+			let fused = match b.instr {
+				Ok(Instr::VarRead) => Some(Instr::LoadVar),
+				Ok(Instr::VarWrite) => Some(Instr::StoreVar),
+				Ok(Instr::Call) => Some(Instr::CallImm),
+				Ok(Instr::CallIf) => Some(Instr::CallIfImm),
+				Ok(Instr::TailCall) => Some(Instr::Goto),
+				Ok(Instr::TailCallIf) => Some(Instr::GotoIf),
+				_ => None,
+			};
+			if let Some(i) = fused {
+				return Some((2, vec![Op::new(i, a.arg.clone())]));
+			}
+		}
+		if let Some(n) = a.push_num() {
+			let fused = match b.instr {
+				Ok(Instr::Plus) => Some((Instr::AddImm, n)),
+				Ok(Instr::Minus) if n != i32::MIN => Some((Instr::AddImm, -n)),
+				Ok(Instr::Gt) => Some((Instr::GtImm, n)),
+				Ok(Instr::Eq) => Some((Instr::EqImm, n)),
+				_ => None,
+			};
+			if let Some((i, n)) = fused {
+				return Some((2, vec![Op::new(i, Arg::Num(n))]));
+			}
+		}
+	}
+
+	None
+}
+
+/// Computes `a op b` the way vm2 would, `None` if it can't or shouldn't be done at compile time.
+fn fold_binary(op: Result<Instr, i32>, a: i32, b: i32) -> Option<i32> {
+	match op {
+		Ok(Instr::Plus) => a.checked_add(b),
+		Ok(Instr::Minus) => a.checked_sub(b),
+		Ok(Instr::Mul) => a.checked_mul(b),
+		Ok(Instr::Div) => a.checked_div(b),
+		Ok(Instr::BitAnd) => Some(a & b),
+		Ok(Instr::BitOr) => Some(a | b),
+		Ok(Instr::Gt) => Some(if a > b { !0 } else { 0 }),
+		Ok(Instr::LessThan) => Some(if a < b { !0 } else { 0 }),
+		Ok(Instr::Eq) => Some(if a == b { !0 } else { 0 }),
+		_ => None,
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::disasm::disassemble;
+	use crate::falselang::error::Error;
+	use crate::falselang::vm2::FIRST_INSTR;
+	use crate::falselang::interpreter::Capture;
+
+	/// Runs the program, returns how that went, stdout, stack (top first), variables and code size.
+	/// Lambda addresses differ between optimization levels, so they are shown as function indexes.
+	fn run(code: &str, opt_level: u8) -> (Result<(), Error>, String, Vec<String>, Vec<String>, usize) {
+		let buf = Capture::default();
+		let mut vm = FalseVM::new();
+		vm.output = Box::new(buf.clone());
+		vm.load(code, opt_level).unwrap();
+		let size = vm.code_end - FIRST_INSTR;
+		let result = vm.run();
+		let describe = |x: i32| match vm.fn_pointer.iter().find(|(_, addr)| **addr as i32 == x) {
+			Some((n, _)) => format!("fn{}", n),
+			None => x.to_string(),
+		};
+		let vars = vm.memory[0..26].iter().map(|x| describe(*x)).collect();
+		let stack = vm.memory[vm.stack_pointer..].iter().map(|x| describe(*x)).collect();
+		let out = String::from_utf8(buf.bytes().to_vec()).unwrap();
+		(result, out, stack, vars, size)
+	}
+
+	fn listing_at(code: &str, opt_level: u8) -> String {
+		let mut vm = FalseVM::new();
+		vm.load(code, opt_level).unwrap();
+		disassemble(&vm)
+	}
+
+	fn listing(code: &str) -> String {
+		listing_at(code, 1)
+	}
+
+	#[test]
+	fn test_fold() {
+		assert_eq!(listing("2 2+"), ".fn 0                           ; 666\n\tpush 4\n\thalt\n");
+		assert_eq!(listing("1 2 3 4+++"), ".fn 0                           ; 666\n\tpush 10\n\thalt\n");
+		assert_eq!(listing("5_ 3~"), ".fn 0                           ; 666\n\tpush -5\n\tpush -4\n\thalt\n");
+		assert_eq!(listing("3 2>"), ".fn 0                           ; 666\n\tpush -1\n\thalt\n");
+	}
+
+	#[test]
+	fn test_no_fold() {
+		// division by zero must still happen at runtime
+		assert_eq!(listing("1 0/"), ".fn 0                           ; 666\n\tpush 1\n\tpush 0\n\tdiv\n\thalt\n");
+		// lambda addresses are not numbers
+		assert!(listing("[1][2]+").contains("\tpush fn0\n\tpush fn1\n\tplus\n"));
+	}
+
+	#[test]
+	fn test_no_ops() {
+		assert_eq!(listing("1 2$%\\\\%"), ".fn 0                           ; 666\n\tpush 1\n\thalt\n");
+		assert_eq!(listing("a;0+1*"), ".fn 0                           ; 666\n\tpush 0                  ; a\n\tvarread\n\thalt\n");
+		assert_eq!(listing("a;2*"), ".fn 0                           ; 666\n\tpush 0                  ; a\n\tvarread\n\tdup\n\tplus\n\thalt\n");
+		assert_eq!(listing("a;b;\\+"), ".fn 0                           ; 666\n\tpush 0                  ; a\n\tvarread\n\tpush 1                  ; b\n\tvarread\n\tplus\n\thalt\n");
+		assert_eq!(listing("1 2%"), ".fn 0                           ; 666\n\tpush 1\n\thalt\n");
+	}
+
+	#[test]
+	fn test_keep_underflow() {
+		// with nothing below, these ops are what fails
+		assert_eq!(listing("0+ 1."), ".fn 0                           ; 666\n\tpush 0\n\tplus\n\tpush 1\n\twriteint\n\thalt\n");
+		assert_eq!(listing("$%"), ".fn 0                           ; 666\n\tdup\n\tdrop\n\thalt\n");
+		assert_eq!(listing("1\\\\"), ".fn 0                           ; 666\n\tpush 1\n\tswap\n\tswap\n\thalt\n");
+		// nothing is known about the stack at the start of a lambda or after a call
+		assert!(listing("[1*]f: 2 f;!").starts_with(".fn 0                           ; 666\n\tpush 1\n\tmul\n\treturn\n"));
+		assert!(listing("[2]f: f;! 1*").contains("\tcall\n\tpush 1\n\tmul\n"));
+	}
+
+	#[test]
+	fn test_relocation() {
+		let dynamic = listing("[1 1+]c:[2 2+]b:c;b;#");
+		assert!(dynamic.starts_with(".fn 0                           ; 666\n\tpush 2\n\treturn\n.fn 1                           ; 669\n\tpush 4\n\treturn\n"));
+		assert!(dynamic.contains("\tpush fn0\n\tpush 2                  ; c\n"));
+		assert!(dynamic.contains("\tpush fn1\n\tpush 1                  ; b\n"));
+		assert!(dynamic.contains("\tgotoif L690\n"));
+
+		assert!(listing("0[$ 2 2+ >][1 1++]#").ends_with([
+			".fn 2                           ; 675",
+			"\tpush 0",
+			"\tgoto L682",
+			"L679:                           ; 679",
+			"\tpush 2",
+			"\tplus",
+			"L682:                           ; 682",
+			"\tdup",
+			"\tpush 4",
+			"\tgt",
+			"\tgotoif L679",
+			"\thalt",
+			"",
+		].join("\n").as_str()));
+	}
+
+	#[test]
+	fn test_fuse() {
+		assert_eq!(listing_at("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;!", 2), [
+			".fn 0                           ; 666",
+			"\taddimm -1",
+			"\tdup",
+			"\tloadvar 5               ; f",
+			"\tcall",
+			"\tswap",
+			"\taddimm -1",
+			"\tloadvar 5               ; f",
+			"\tcall",
+			"\tplus",
+			"\treturn",
+			".fn 1                           ; 680",
+			"\tdupgtimm 1",
+			"\tgotoif L686",
+			"\tgoto L699",
+			"L686:                           ; 686",
+			"\taddimm -1",
+			"\tdup",
+			"\tloadvar 5               ; f",
+			"\tcall",
+			"\tswap",
+			"\taddimm -1",
+			"\tloadvar 5               ; f",
+			"\tcall",
+			"\tplus",
+			"L699:                           ; 699",
+			"\treturn",
+			".fn 2                           ; 700",
+			"\tpush fn1",
+			"\tstorevar 5              ; f",
+			"\tpush 12",
+			"\tloadvar 5               ; f",
+			"\tcall",
+			"\thalt",
+			"",
+		].join("\n"));
+		assert!(listing_at("[[1]!]!", 2).contains(".fn 1                           ; 669\n\tgoto fn0\n\treturn\n"));
+		assert!(listing_at("[1]b:[a;b;?]!", 2).contains("\tloadvar 0               ; a\n\tloadvar 1               ; b\n\ttailcallif\n\treturn\n"));
+		assert!(listing_at("[1]c:c;! [2]! a;1=", 2).contains("\tloadvar 2               ; c\n\tcall\n\tcallimm fn1\n\tloadvar 0               ; a\n\teqimm 1\n"));
+	}
+
+	#[test]
+	fn test_fuse_round_trip() {
+		let listing = listing_at("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;! [1]! 3 2>", 2);
+		let mut vm = FalseVM::new();
+		crate::falselang::asm::assemble(&mut vm, &listing).unwrap();
+		assert_eq!(disassemble(&vm), listing);
+	}
+
+	#[test]
+	fn test_differential() {
+		let programs = [
+			"2 2+",
+			"1 2 3 4 5",
+			"'a    'b         'c'd",
+			"2 4$ 1 2 3% 1 2 \\ 0 1 2 3 @",
+			"7 8 9 2ø 1920_ 3 1& 3 1| 5~",
+			"1 2- 3 4* 9 2/ 7_ 2/ 2 3> 3 2> 4 4= 4 5=",
+			"1$%2\\\\3__4~~5 0+6 0-7 1*8 1/9 0|10 1_&11 1_*12 2*13 2 1\\-",
+			"1[777]?  0[333]?  2 2+ 4=[777]?",
+			"50 f: 1 f; +",
+			"[1 +]f: 50 f;!",
+			"[$1=$[\\%1\\]?~[$1-f;!*]?]f:    6 f;!",
+			"[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;!",
+			"10 [$5 >][1 -]#",
+			"0[$3 1+>][1 1++]#",
+			"0i:[i;10 2*>][i;1+i:i;$*.\" \"]#",
+			"\"hello\" 10, 2 3+. 'x,",
+			"3[$0>][$.1 0+-]#%",
+			"[$0>]c: [1-]b: [b;!]d: [2*]e: 5[c;!][d;!$e;!.]#",
+			"[$0>[1-g;!]?]f: [$.f;!]g: 5f;! [3]h: h;1h;?",
+			"50 9[1-$][\\$@$@$@$@\\/*=[1-$$[%\\1-$@]?0=[\\$.' ,\\]?]?]#",
+			// stack underflows
+			"0+ 1.",
+			"5 0- 1* 1/ 1_& 0|",
+			"0- 1.",
+			"1* 1/",
+			"1_&",
+			"$%",
+			"1\\\\",
+			"__ ~~",
+			"[0+]f: f;! 1.",
+			"1 [$%]f: f;!",
+		];
+		let examples = ["2plus2", "bottles-of-beer", "factorial", "hello", "ptr", "while-in-while", "while"];
+		let examples = examples.iter().map(|name| std::fs::read_to_string(format!("examples/{}.false", name)).unwrap());
+
+		for code in programs.iter().map(|s| s.to_string()).chain(examples) {
+			check_levels(&code);
+		}
+	}
+
+	#[test]
+	fn test_differential_primes() {
+		// primes up to 199 instead of 1999, the whole example takes a while in debug builds
+		let code = std::fs::read_to_string("examples/primes.false").unwrap();
+		assert!(code.starts_with("1999 "));
+		check_levels(&code.replacen("1999", "199", 1));
+	}
+
+	/// Runs `code` at every opt level and compares with level 0.
+	fn check_levels(code: &str) {
+		let (result0, out0, stack0, vars0, size0) = run(code, 0);
+		for opt_level in [1, 2, 3] {
+			let (result, out, stack, vars, size) = run(code, opt_level);
+			assert_eq!(result0, result, "result differs for {:?} at level {}", code, opt_level);
+			assert_eq!(out0, out, "output differs for {:?} at level {}", code, opt_level);
+			assert_eq!(stack0, stack, "stack differs for {:?} at level {}", code, opt_level);
+			assert_eq!(vars0, vars, "variables differ for {:?} at level {}", code, opt_level);
+			// inlining trades size for speed
+			assert!(size <= size0 || opt_level == 3, "code grew for {:?} at level {}", code, opt_level);
+		}
+	}
+}
@@ -1,8 +1,16 @@
+use super::error::Error;
 use super::tokenizer::{Token, Tokenizer};
 
+
+/// Deepest allowed lambda nesting, `read_lambda` recurses once per level.
+pub const MAX_NESTING: usize = 1000;
+
 pub struct Parser {
 	pub tokenizer: Tokenizer,
 	pub lambda_storage: Vec<Vec<Token>>,
+	/// Char offset of each token in `lambda_storage`, and one more entry for where each lambda
+	/// ends: its `]`, or the end of the source for main. `[...]` is at its `[`.
+	pub spans: Vec<Vec<usize>>,
 }
 
 impl Parser {
@@ -10,30 +18,51 @@ impl Parser {
 		Parser {
 			tokenizer: t,
 			lambda_storage: Vec::new(),
+			spans: Vec::new(),
 		}
 	}
 
-	fn read_lambda(&mut self) -> Vec<Token> {
+	/// Reads up to the `]` that ends the lambda, or to the end for main. Returns the tokens and their spans.
+	fn read_lambda(&mut self, depth: usize) -> Result<(Vec<Token>, Vec<usize>), Error> {
+		if depth > MAX_NESTING {
+			return Err(Error::syntax(self.tokenizer.pos(), "lambdas nested too deep"));
+		}
 		let mut tokens = Vec::new();
-		while let Some(t) = self.tokenizer.next_token() {
+		let mut spans = Vec::new();
+		loop {
+			let t = match self.tokenizer.next_token()? {
+				Some(t) => t,
+				None if depth == 0 => break,
+				None => return Err(Error::incomplete(self.tokenizer.pos(), "unterminated lambda")),
+			};
+			let start = self.tokenizer.start();
 			if t == Token::LambdaEnd {
-				break;
+				if depth == 0 {
+					return Err(Error::syntax(start, "unmatched ]"));
+				}
+				spans.push(start);
+				return Ok((tokens, spans));
 			}
 			if t == Token::LambdaStart {
-				let lambda = self.read_lambda();
+				let (lambda, lambda_spans) = self.read_lambda(depth + 1)?;
 				self.lambda_storage.push(lambda);
+				self.spans.push(lambda_spans);
 				tokens.push(Token::LambdaPointer(self.lambda_storage.len() - 1));
+				spans.push(start);
 				continue;
 			}
 			tokens.push(t);
+			spans.push(start);
 		}
-		tokens
+		spans.push(self.tokenizer.pos());
+		Ok((tokens, spans))
 	}
 
-	pub fn parse(&mut self) -> Token {
-		let tokens = self.read_lambda();
+	pub fn parse(&mut self) -> Result<Token, Error> {
+		let (tokens, spans) = self.read_lambda(0)?;
 		self.lambda_storage.push(tokens);
-		Token::LambdaPointer(self.lambda_storage.len() - 1)
+		self.spans.push(spans);
+		Ok(Token::LambdaPointer(self.lambda_storage.len() - 1))
 	}
 }
 
@@ -45,19 +74,45 @@ mod tests {
 	#[test]
 	fn test_next() {
 		let mut parser = Parser::new(Tokenizer::new("[2 2+]"));
-		assert_eq!(parser.parse(), Token::LambdaPointer(1));
+		assert_eq!(parser.parse(), Ok(Token::LambdaPointer(1)));
 		assert_eq!(parser.lambda_storage, vec![
 			vec![Token::Number(2), Token::Number(2), Token::Plus],
 			vec![Token::LambdaPointer(0)],
 		]);
 	}
 
+	#[test]
+	fn test_spans() {
+		let mut parser = Parser::new(Tokenizer::new("1 {c} [$ ]\n 'a\"s\"!"));
+		parser.parse().unwrap();
+		assert_eq!(parser.spans, vec![vec![7, 9], vec![0, 6, 12, 14, 17, 18]]);
+	}
+
 	#[test]
 	fn test_empty() {
 		let mut parser = Parser::new(Tokenizer::new(""));
-		assert_eq!(parser.parse(), Token::LambdaPointer(0));
+		assert_eq!(parser.parse(), Ok(Token::LambdaPointer(0)));
 		assert_eq!(parser.lambda_storage, vec![
 			vec![],
 		]);
 	}
+
+	#[test]
+	fn test_nesting() {
+		let code = "[".repeat(MAX_NESTING) + &"]".repeat(MAX_NESTING);
+		assert!(Parser::new(Tokenizer::new(&code)).parse().is_ok());
+		let code = "[".repeat(MAX_NESTING + 1);
+		assert_eq!(Parser::new(Tokenizer::new(&code)).parse(), Err(Error::syntax(MAX_NESTING + 1, "lambdas nested too deep")));
+	}
+
+	#[test]
+	fn test_error() {
+		let mut parser = Parser::new(Tokenizer::new("[1 [\"abc]]"));
+		assert_eq!(parser.parse(), Err(Error::incomplete(4, "unterminated string")));
+		let mut parser = Parser::new(Tokenizer::new("[1 [2]"));
+		assert_eq!(parser.parse(), Err(Error::incomplete(6, "unterminated lambda")));
+		// found by fuzzing, everything after the ] used to be ignored
+		let mut parser = Parser::new(Tokenizer::new("1] \"abc"));
+		assert_eq!(parser.parse(), Err(Error::syntax(1, "unmatched ]")));
+	}
 }
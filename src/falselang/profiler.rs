@@ -0,0 +1,276 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use super::error::{line_col, Error};
+use super::parser::Parser;
+use super::tokenizer::{Dialect, Token, Tokenizer};
+use super::vm::{self, Frame, StepResult};
+use super::vm2::{self, Instr};
+
+
+/// Instructions executed and wall time spent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cost {
+	pub steps: u64,
+	pub time: Duration,
+}
+
+impl Cost {
+	fn add(&mut self, other: &Cost) {
+		self.steps += other.steps;
+		self.time += other.time;
+	}
+}
+
+/// Cost of one lambda, see `Profile::lambdas`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LambdaCost {
+	pub lambda: usize,
+	/// Spent in the lambda itself.
+	pub own: Cost,
+	/// Spent in the lambda and everything it called.
+	pub total: Cost,
+}
+
+/// Where a run spent its time, by chain of lambda calls.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+	/// Name of each lambda, laid out like `Parser::lambda_storage`: `main`, the variable the
+	/// lambda is assigned to where there is one, and the position of its `[`.
+	pub names: Vec<String>,
+	/// Cost per call chain. A chain lists lambdas from main down to the one that ran.
+	pub stacks: HashMap<Vec<usize>, Cost>,
+}
+
+impl Profile {
+	/// Own and total cost of every lambda that ran, most own steps first.
+	pub fn lambdas(&self) -> Vec<LambdaCost> {
+		let mut lambdas: BTreeMap<usize, LambdaCost> = BTreeMap::new();
+		for (chain, cost) in self.stacks.iter() {
+			for (i, lambda) in chain.iter().enumerate() {
+				let entry = lambdas.entry(*lambda).or_insert(LambdaCost { lambda: *lambda, own: Cost::default(), total: Cost::default() });
+				// recursion shows up more than once in a chain, but only counts once
+				if !chain[..i].contains(lambda) {
+					entry.total.add(cost);
+				}
+				if i == chain.len() - 1 {
+					entry.own.add(cost);
+				}
+			}
+		}
+		let mut lambdas: Vec<LambdaCost> = lambdas.into_values().collect();
+		lambdas.sort_by_key(|l| std::cmp::Reverse(l.own.steps));
+		lambdas
+	}
+
+	/// Table of `lambdas`, one line each.
+	pub fn report(&self) -> String {
+		let all: u64 = self.stacks.values().map(|cost| cost.steps).sum();
+		let mut out = format!("{:>6}  {:>12}  {:>12}  {:>10}  {:>10}  lambda\n", "own%", "own steps", "total steps", "own time", "total time");
+		for l in self.lambdas() {
+			let percent = l.own.steps as f64 * 100.0 / all.max(1) as f64;
+			out += &format!("{:>5.1}%  {:>12}  {:>12}  {:>10}  {:>10}  {}\n", percent, l.own.steps, l.total.steps,
+				format!("{:.1?}", l.own.time), format!("{:.1?}", l.total.time), self.names[l.lambda]);
+		}
+		out
+	}
+
+	/// Call chains in the folded format of `flamegraph.pl` and `inferno`: `main;f;g 123` per line,
+	/// weighted by steps, or by microseconds if `time` is set.
+	pub fn folded(&self, time: bool) -> String {
+		let mut lines: Vec<String> = self.stacks.iter().filter_map(|(chain, cost)| {
+			let weight = if time { cost.time.as_micros() as u64 } else { cost.steps };
+			let names: Vec<&str> = chain.iter().map(|n| self.names[*n].as_str()).collect();
+			(weight > 0).then(|| format!("{} {}", names.join(";"), weight))
+		}).collect();
+		lines.sort();
+		lines.iter().map(|line| line.to_string() + "\n").collect()
+	}
+}
+
+/// Runs a program one step at a time and records a `Profile`.
+pub struct Profiler {
+	pub profile: Profile,
+	chain: Vec<usize>,
+	cost: Cost, // of `chain`, not yet in `profile`
+	since: Instant,
+}
+
+impl Profiler {
+	/// Names the lambdas of `source`, which is what the VM has to be loaded with.
+	pub fn new(source: &str, dialect: Dialect) -> Result<Profiler, Error> {
+		let mut parser = Parser::new(Tokenizer::with_dialect(source, dialect));
+		parser.parse()?;
+		let profile = Profile { names: names(source, &parser.lambda_storage, &parser.spans), stacks: HashMap::new() };
+		Ok(Profiler { profile, chain: Vec::new(), cost: Cost::default(), since: Instant::now() })
+	}
+
+	/// Charges what was spent since the last switch to the current chain and starts on `chain`.
+	fn switch(&mut self, chain: Vec<usize>) {
+		let now = Instant::now();
+		self.cost.time = now - self.since;
+		if !self.chain.is_empty() {
+			self.profile.stacks.entry(std::mem::replace(&mut self.chain, chain)).or_default().add(&self.cost);
+		} else {
+			self.chain = chain;
+		}
+		self.cost = Cost::default();
+		self.since = now;
+	}
+
+	/// Runs the tree-walking VM to the end.
+	pub fn run_tree(&mut self, vm: &mut vm::FalseVM) -> Result<(), Error> {
+		// lambdas from earlier loads come first
+		let base = vm.functions.len() - self.profile.names.len();
+		let chain = |vm: &vm::FalseVM| -> Vec<usize> {
+			vm.frames.iter().map(|frame| match frame {
+				Frame::Return { fn_index, .. } | Frame::While { fn_index, .. } => *fn_index - base,
+			}).chain([vm.fn_index - base]).collect()
+		};
+		self.switch(chain(vm));
+		let mut at = (vm.frames.len(), vm.fn_index);
+		let result = loop {
+			match vm.step() {
+				Ok(StepResult::OK) => self.cost.steps += 1,
+				Ok(StepResult::End) => break Ok(()),
+				Err(e) => break Err(e),
+			}
+			if (vm.frames.len(), vm.fn_index) != at {
+				at = (vm.frames.len(), vm.fn_index);
+				self.switch(chain(vm));
+			}
+		};
+		self.switch(Vec::new());
+		result
+	}
+
+	/// Runs the bytecode VM to the end. Code that `load` inlined counts for the lambda it was inlined into.
+	pub fn run_bytecode(&mut self, vm: &mut vm2::FalseVM) -> Result<(), Error> {
+		let starts: BTreeMap<usize, usize> = vm.fn_pointer.iter().map(|(n, addr)| (*addr, *n)).collect();
+		let lambda_at = |addr: usize| starts.range(..=addr).next_back().map_or(0, |(_, n)| *n);
+		let mut chain = vec![lambda_at(vm.cursor)];
+		self.switch(chain.clone());
+		let result = loop {
+			let instr = Instr::try_from(vm.memory[vm.cursor]).ok();
+			let depth = vm.callstack_pointer;
+			match vm.step() {
+				Ok(StepResult::OK) => self.cost.steps += 1,
+				Ok(StepResult::End) => break Ok(()),
+				Err(e) => break Err(e),
+			}
+			match instr {
+				Some(Instr::Call | Instr::CallIf | Instr::CallImm | Instr::CallIfImm) if vm.callstack_pointer > depth => {
+					chain.push(lambda_at(vm.cursor));
+				}
+				Some(Instr::TailCall | Instr::TailCallIf) if starts.contains_key(&vm.cursor) => {
+					chain.pop();
+					chain.push(lambda_at(vm.cursor));
+				}
+				Some(Instr::Return) if chain.len() > 1 => {
+					chain.pop();
+				}
+				_ => continue,
+			}
+			self.switch(chain.clone());
+		};
+		self.switch(Vec::new());
+		result
+	}
+}
+
+/// See `Profile::names`.
+pub(crate) fn names(source: &str, functions: &[Vec<Token>], spans: &[Vec<usize>]) -> Vec<String> {
+	let mut names = vec!["main".to_string(); functions.len()];
+	for (f, code) in functions.iter().enumerate() {
+		for (i, token) in code.iter().enumerate() {
+			if let Token::LambdaPointer(n) = token {
+				let name = match code.get(i + 1..i + 3) {
+					Some([Token::Variable(v), Token::VarWrite]) => v.to_string(),
+					_ => "lambda".to_string(),
+				};
+				let (line, col) = line_col(source, spans[f][i]);
+				names[*n] = format!("{}@{}:{}", name, line, col);
+			}
+		}
+	}
+	names
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CODE: &str = "[1-]d:\n[$[d;!f;!]?]f:\n3f;! [1 2%]! [0][1]#";
+
+	fn tree(code: &str) -> Profile {
+		let mut vm = vm::FalseVM::new();
+		vm.load(code).unwrap();
+		let mut profiler = Profiler::new(code, Dialect::Rfalse).unwrap();
+		profiler.run_tree(&mut vm).unwrap();
+		profiler.profile
+	}
+
+	fn bytecode(code: &str, opt_level: u8) -> Profile {
+		let mut vm = vm2::FalseVM::new();
+		vm.load(code, opt_level).unwrap();
+		let mut profiler = Profiler::new(code, Dialect::Rfalse).unwrap();
+		profiler.run_bytecode(&mut vm).unwrap();
+		profiler.profile
+	}
+
+	/// Steps per folded chain.
+	fn steps(profile: &Profile) -> Vec<(String, u64)> {
+		profile.folded(false).lines().map(|line| {
+			let (chain, steps) = line.rsplit_once(' ').unwrap();
+			(chain.to_string(), steps.parse().unwrap())
+		}).collect()
+	}
+
+	#[test]
+	fn test_names() {
+		let profile = tree(CODE);
+		assert_eq!(profile.names, ["d@1:1", "lambda@2:3", "f@2:1", "lambda@3:6", "lambda@3:14", "lambda@3:17", "main"]);
+	}
+
+	#[test]
+	fn test_tree() {
+		let profile = tree(CODE);
+		let chains: Vec<String> = steps(&profile).into_iter().map(|(chain, _)| chain).collect();
+		assert_eq!(chains, [
+			"main", "main;f@2:1", "main;f@2:1;lambda@2:3", "main;f@2:1;lambda@2:3;d@1:1", "main;f@2:1;lambda@2:3;f@2:1",
+			"main;f@2:1;lambda@2:3;f@2:1;lambda@2:3", "main;f@2:1;lambda@2:3;f@2:1;lambda@2:3;d@1:1",
+			"main;f@2:1;lambda@2:3;f@2:1;lambda@2:3;f@2:1", "main;f@2:1;lambda@2:3;f@2:1;lambda@2:3;f@2:1;lambda@2:3",
+			"main;f@2:1;lambda@2:3;f@2:1;lambda@2:3;f@2:1;lambda@2:3;d@1:1",
+			"main;f@2:1;lambda@2:3;f@2:1;lambda@2:3;f@2:1;lambda@2:3;f@2:1", "main;lambda@3:14", "main;lambda@3:6",
+		]);
+		let f = profile.lambdas().into_iter().find(|l| profile.names[l.lambda] == "f@2:1").unwrap();
+		// f runs 4 times, the last call sees 0
+		assert_eq!(f.own.steps, 4 * 4);
+		let all: u64 = profile.stacks.values().map(|cost| cost.steps).sum();
+		assert!(f.total.steps > f.own.steps && f.total.steps < all);
+		assert_eq!(profile.lambdas().iter().find(|l| l.lambda == 6).unwrap().total.steps, all);
+		assert!(profile.report().contains("f@2:1\n"));
+	}
+
+	#[test]
+	fn test_bytecode() {
+		for opt_level in 0..=3 {
+			let profile = bytecode(CODE, opt_level);
+			let chains: Vec<String> = steps(&profile).into_iter().map(|(chain, _)| chain).collect();
+			// `[..]?` is inlined, and `f;!` at the end of f is a tail call that replaces f
+			assert!(chains.contains(&"main;f@2:1".to_string()), "{}: {:?}", opt_level, chains);
+			assert!(!chains.iter().any(|chain| chain.contains("lambda@2:3") || chain.contains("f@2:1;f@2:1")), "{}: {:?}", opt_level, chains);
+			assert_eq!(chains.contains(&"main;f@2:1;d@1:1".to_string()), opt_level < 3, "{}: {:?}", opt_level, chains);
+		}
+	}
+
+	#[test]
+	fn test_errors() {
+		let mut vm = vm::FalseVM::new();
+		vm.load("[1 0/]f: f;!").unwrap();
+		let mut profiler = Profiler::new("[1 0/]f: f;!", Dialect::Rfalse).unwrap();
+		assert!(profiler.run_tree(&mut vm).is_err());
+		assert_eq!(steps(&profiler.profile), [("main".to_string(), 6), ("main;f@1:1".to_string(), 2)]);
+	}
+}
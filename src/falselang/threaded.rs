@@ -0,0 +1,832 @@
+use std::io::Write;
+
+use super::error::Error;
+use super::interpreter::read_char;
+use super::vm2::{invalid_jump, invalid_opcode, pick_out_of_range, FalseVM, Instr, CALL_STACK_START, FIRST_INSTR, MEM_SIZE};
+
+
+/// A vm2 instruction with its operands decoded in place. Jump targets are indexes into `ThreadedCode::ops`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+	Noop,
+	Push(i32),
+	Dup,
+	Drop,
+	Swap,
+	Rot,
+	Pick,
+	Plus,
+	Minus,
+	Mul,
+	Div,
+	Negate,
+	BitAnd,
+	BitOr,
+	BitNot,
+	Gt,
+	Eq,
+	LessThan,
+	ReadChar,
+	WriteChar,
+	WriteInt,
+	WriteString(u32, u32), // memory address of the first char, length
+	FlushIO,
+
+	Call,
+	CallIf,
+	Return,
+	Goto(u32),
+	GotoIf(u32),
+	Halt,
+
+	VarRead,
+	VarWrite,
+	MoveToCallStack,
+	PickFromCallStack(i32),
+	DropFromCallStack,
+	TailCall,
+	TailCallIf,
+
+	LoadVar(i32),
+	StoreVar(i32),
+	CallImm(u32),
+	CallIfImm(u32),
+	AddImm(i32),
+	GtImm(i32),
+	EqImm(i32),
+	DupGtImm(i32),
+
+	Invalid(i32), // not an opcode, fails when executed
+	BadJump(i32), // Goto or CallImm to an address that is not an instruction, or running past the end
+	BadJumpIf(i32), // GotoIf or CallIfImm to an address that is not an instruction, fails if taken
+	TruncatedString(u32, u32), // WriteString that runs past the end, writes what there is and fails
+}
+
+/// The memory image of a vm2 program, predecoded into a dense array of ops.
+/// Call stack entries and the VM cursor still hold memory addresses, so the VM
+/// state stays the same as if it was run by `FalseVM::run`.
+pub struct ThreadedCode {
+	pub ops: Vec<Op>,
+	addr_of: Vec<u32>, // op index -> memory address, has an extra entry for the end of code
+	op_at: Vec<u32>, // memory address - FIRST_INSTR -> op index, or u32::MAX
+}
+
+impl ThreadedCode {
+	pub fn new(vm: &FalseVM) -> ThreadedCode {
+		let len = vm.code_end - FIRST_INSTR;
+		let mut op_at = vec![u32::MAX; len];
+		let mut addr_of = Vec::new();
+		let mut addr = FIRST_INSTR;
+		while addr < vm.code_end {
+			op_at[addr - FIRST_INSTR] = addr_of.len() as u32;
+			addr_of.push(addr as u32);
+			addr += match Instr::try_from(vm.memory[addr]) {
+				Ok(Instr::WriteString) => 2 + vm.memory[addr + 1].max(0) as usize,
+				Ok(i) => 1 + i.arg_count(),
+				Err(_) => 1,
+			};
+		}
+		addr_of.push(vm.code_end as u32);
+
+		let mut code = ThreadedCode { ops: Vec::with_capacity(addr_of.len()), addr_of, op_at };
+		for n in 0..code.addr_of.len() - 1 {
+			let addr = code.addr_of[n] as usize;
+			let arg = vm.memory.get(addr + 1).copied().unwrap_or(0);
+			let target = code.op_index(arg);
+			let op = match Instr::try_from(vm.memory[addr]) {
+				Err(cell) => Op::Invalid(cell.number),
+				Ok(i) => match i {
+					Instr::Noop => Op::Noop,
+					Instr::Push => Op::Push(arg),
+					Instr::Dup => Op::Dup,
+					Instr::Drop => Op::Drop,
+					Instr::Swap => Op::Swap,
+					Instr::Rot => Op::Rot,
+					Instr::Pick => Op::Pick,
+					Instr::Plus => Op::Plus,
+					Instr::Minus => Op::Minus,
+					Instr::Mul => Op::Mul,
+					Instr::Div => Op::Div,
+					Instr::Negate => Op::Negate,
+					Instr::BitAnd => Op::BitAnd,
+					Instr::BitOr => Op::BitOr,
+					Instr::BitNot => Op::BitNot,
+					Instr::Gt => Op::Gt,
+					Instr::Eq => Op::Eq,
+					Instr::LessThan => Op::LessThan,
+					Instr::ReadChar => Op::ReadChar,
+					Instr::WriteChar => Op::WriteChar,
+					Instr::WriteInt => Op::WriteInt,
+					// `step` fails reading the length or the first char past the end
+					Instr::WriteString if addr + 2 + arg.max(0) as usize > vm.code_end => {
+						let start = (addr + 2).min(vm.code_end);
+						Op::TruncatedString(start as u32, (vm.code_end - start) as u32)
+					}
+					Instr::WriteString => Op::WriteString(addr as u32 + 2, arg.max(0) as u32),
+					Instr::FlushIO => Op::FlushIO,
+
+					Instr::Call => Op::Call,
+					Instr::CallIf => Op::CallIf,
+					Instr::Return => Op::Return,
+					Instr::Goto => target.map_or(Op::BadJump(arg), Op::Goto),
+					Instr::GotoIf => target.map_or(Op::BadJumpIf(arg), Op::GotoIf),
+					Instr::Halt => Op::Halt,
+
+					Instr::VarRead => Op::VarRead,
+					Instr::VarWrite => Op::VarWrite,
+					Instr::MoveToCallStack => Op::MoveToCallStack,
+					Instr::PickFromCallStack => Op::PickFromCallStack(arg),
+					Instr::DropFromCallStack => Op::DropFromCallStack,
+					Instr::TailCall => Op::TailCall,
+					Instr::TailCallIf => Op::TailCallIf,
+
+					Instr::LoadVar => Op::LoadVar(arg),
+					Instr::StoreVar => Op::StoreVar(arg),
+					Instr::CallImm => target.map_or(Op::BadJump(arg), Op::CallImm),
+					Instr::CallIfImm => target.map_or(Op::BadJumpIf(arg), Op::CallIfImm),
+					Instr::AddImm => Op::AddImm(arg),
+					Instr::GtImm => Op::GtImm(arg),
+					Instr::EqImm => Op::EqImm(arg),
+					Instr::DupGtImm => Op::DupGtImm(arg),
+				},
+			};
+			code.ops.push(op);
+		}
+		code.ops.push(Op::BadJump(vm.code_end as i32));
+		code
+	}
+
+	/// Op index of the instruction starting at memory address `addr`.
+	pub fn op_index(&self, addr: i32) -> Option<u32> {
+		let i = (addr as usize).checked_sub(FIRST_INSTR)?;
+		self.op_at.get(i).copied().filter(|n| *n != u32::MAX)
+	}
+
+	/// Op index of a computed jump target.
+	fn jump(&self, addr: i32) -> Result<usize, Error> {
+		self.op_index(addr).map(|n| n as usize).ok_or_else(|| invalid_jump(addr))
+	}
+
+	/// Runs from the VM cursor until Halt, same as `FalseVM::run`.
+	/// On error the VM cursor is left at the failing instruction.
+	pub fn run(&self, vm: &mut FalseVM) -> Result<(), Error> {
+		let stack_end = vm.stack_end();
+		let mem = &mut vm.memory;
+		let out = &mut vm.output;
+		let input = &mut vm.input;
+		let mut sp = vm.stack_pointer;
+		let mut csp = vm.callstack_pointer;
+		let mut pc = self.jump(vm.cursor as i32)?;
+		let mut fuel = vm.fuel.unwrap_or(u64::MAX);
+
+		macro_rules! fail {
+			($e:expr) => {{ std::hint::cold_path(); break Err($e); }};
+		}
+		macro_rules! check {
+			($e:expr) => {
+				match $e {
+					Ok(x) => x,
+					Err(e) => fail!(Error::from(e)),
+				}
+			};
+		}
+		// makes sure the stack has at least `n` elements, otherwise pops what there is like `FalseVM::step` does
+		macro_rules! need {
+			($n:expr) => {
+				if sp + $n > MEM_SIZE {
+					sp = MEM_SIZE;
+					fail!(underflow());
+				}
+			};
+		}
+		macro_rules! pop {
+			() => {{
+				need!(1);
+				let x = mem[sp];
+				sp += 1;
+				x
+			}};
+		}
+		macro_rules! push {
+			($x:expr) => {{
+				let x = $x;
+				if sp <= stack_end {
+					fail!(Error::runtime("stack overflow"));
+				}
+				sp -= 1;
+				mem[sp] = x;
+			}};
+		}
+		macro_rules! call {
+			($target:expr) => {{
+				let target = $target;
+				if csp + 1 >= FIRST_INSTR {
+					fail!(Error::runtime("call stack overflow"));
+				}
+				csp += 1;
+				mem[csp] = self.addr_of[pc + 1] as i32;
+				pc = target;
+				continue;
+			}};
+		}
+		macro_rules! callstack_pop {
+			() => {{
+				if csp < CALL_STACK_START {
+					fail!(Error::runtime("call stack underflow"));
+				}
+				let x = mem[csp];
+				csp -= 1;
+				x
+			}};
+		}
+
+		let result = loop {
+			if fuel == 0 {
+				fail!(Error::OutOfFuel);
+			}
+			fuel -= 1;
+			match self.ops[pc] {
+				Op::Noop => {}
+				Op::Push(x) => push!(x),
+				Op::Dup => { need!(1); push!(mem[sp]) }
+				Op::Drop => { pop!(); }
+				Op::Swap => { need!(2); mem.swap(sp, sp + 1) }
+				Op::Rot => {
+					need!(3);
+					let c = mem[sp + 2];
+					mem[sp + 2] = mem[sp + 1];
+					mem[sp + 1] = mem[sp];
+					mem[sp] = c;
+				}
+				Op::Pick => {
+					let n = pop!();
+					push!(mem[check!(pick_addr(sp, n))]);
+				}
+				Op::Plus => { let a = pop!(); let b = pop!(); push!(b.wrapping_add(a)); }
+				Op::Minus => { let a = pop!(); let b = pop!(); push!(b.wrapping_sub(a)); }
+				Op::Mul => { let a = pop!(); let b = pop!(); push!(b.wrapping_mul(a)); }
+				Op::Div => {
+					let a = pop!();
+					let b = pop!();
+					if a == 0 {
+						fail!(Error::runtime("division by zero"));
+					}
+					push!(b.wrapping_div(a));
+				}
+				Op::Negate => { let a = pop!(); push!(a.wrapping_neg()); }
+				Op::BitAnd => { let a = pop!(); let b = pop!(); push!(a & b); }
+				Op::BitOr => { let a = pop!(); let b = pop!(); push!(a | b); }
+				Op::BitNot => { let a = pop!(); push!(!a); }
+				Op::Gt => { let a = pop!(); let b = pop!(); push!(if b > a { !0 } else { 0 }); }
+				Op::Eq => { let a = pop!(); let b = pop!(); push!(if a == b { !0 } else { 0 }); }
+				Op::LessThan => { let a = pop!(); let b = pop!(); push!(if b < a { !0 } else { 0 }); }
+				Op::ReadChar => push!(read_char(input)),
+				Op::WriteChar => {
+					let a = pop!();
+					check!(write!(out, "{}", a as u8 as char));
+				}
+				Op::WriteInt => {
+					let a = pop!();
+					check!(write!(out, "{}", a));
+				}
+				Op::WriteString(start, len) => {
+					let text = mem[start as usize..(start + len) as usize].iter();
+					check!(text.map(|c| *c as u8 as char).try_for_each(|c| write!(out, "{}", c)));
+				}
+				Op::FlushIO => check!(out.flush()),
+
+				Op::Call => {
+					let addr = pop!();
+					call!(check!(self.jump(addr)));
+				}
+				Op::CallIf => {
+					let addr = pop!();
+					let cond = pop!();
+					if cond != 0 {
+						call!(check!(self.jump(addr)));
+					}
+				}
+				Op::Return => {
+					let addr = callstack_pop!();
+					pc = check!(self.jump(addr));
+					continue;
+				}
+				Op::Goto(target) => {
+					pc = target as usize;
+					continue;
+				}
+				Op::GotoIf(target) => {
+					if pop!() != 0 {
+						pc = target as usize;
+						continue;
+					}
+				}
+				Op::Halt => break Ok(()),
+
+				Op::VarRead => {
+					let n = pop!();
+					push!(mem[check!(FalseVM::var_addr(n))]);
+				}
+				Op::VarWrite => {
+					let var = pop!();
+					let value = pop!();
+					mem[check!(FalseVM::var_addr(var))] = value;
+				}
+				Op::MoveToCallStack => {
+					let x = pop!();
+					if csp + 1 >= FIRST_INSTR {
+						fail!(Error::runtime("call stack overflow"));
+					}
+					csp += 1;
+					mem[csp] = x;
+				}
+				Op::PickFromCallStack(n) => push!(mem[check!(callstack_pick_addr(csp, n))]),
+				Op::DropFromCallStack => { callstack_pop!(); }
+				Op::TailCall => {
+					let addr = pop!();
+					pc = check!(self.jump(addr));
+					continue;
+				}
+				Op::TailCallIf => {
+					let addr = pop!();
+					let cond = pop!();
+					if cond != 0 {
+						pc = check!(self.jump(addr));
+						continue;
+					}
+				}
+
+				Op::LoadVar(n) => push!(mem[check!(FalseVM::var_addr(n))]),
+				Op::StoreVar(n) => {
+					let value = pop!();
+					mem[check!(FalseVM::var_addr(n))] = value;
+				}
+				Op::CallImm(target) => call!(target as usize),
+				Op::CallIfImm(target) => {
+					if pop!() != 0 {
+						call!(target as usize);
+					}
+				}
+				Op::AddImm(n) => { need!(1); mem[sp] = mem[sp].wrapping_add(n) }
+				Op::GtImm(n) => { need!(1); mem[sp] = if mem[sp] > n { !0 } else { 0 } }
+				Op::EqImm(n) => { need!(1); mem[sp] = if mem[sp] == n { !0 } else { 0 } }
+				Op::DupGtImm(n) => { need!(1); push!(if mem[sp] > n { !0 } else { 0 }) }
+
+				Op::Invalid(cell) => fail!(invalid_opcode(cell)),
+				Op::BadJump(addr) => fail!(invalid_jump(addr)),
+				Op::BadJumpIf(addr) => {
+					if pop!() != 0 {
+						fail!(invalid_jump(addr));
+					}
+				}
+				Op::TruncatedString(start, len) => {
+					let text = mem[start as usize..(start + len) as usize].iter();
+					check!(text.map(|c| *c as u8 as char).try_for_each(|c| write!(out, "{}", c)));
+					fail!(invalid_jump((start + len) as i32));
+				}
+			}
+			pc += 1;
+		};
+
+		vm.stack_pointer = sp;
+		vm.callstack_pointer = csp;
+		vm.cursor = self.addr_of[pc] as usize + result.is_ok() as usize;
+		if vm.fuel.is_some() {
+			vm.fuel = Some(fuel);
+		}
+		result
+	}
+
+	/// Same as `run`, but keeps the top of the data stack in a local instead of memory.
+	/// `memory[stack_pointer]` is stale while running and gets written back at the end.
+	/// Popped cells are never cleared.
+	pub fn run_cached(&self, vm: &mut FalseVM) -> Result<(), Error> {
+		let stack_end = vm.stack_end();
+		let mem = &mut vm.memory;
+		let out = &mut vm.output;
+		let input = &mut vm.input;
+		let mut sp = vm.stack_pointer;
+		let mut csp = vm.callstack_pointer;
+		let mut pc = self.jump(vm.cursor as i32)?;
+		let mut fuel = vm.fuel.unwrap_or(u64::MAX);
+		let mut tos = if sp < MEM_SIZE { mem[sp] } else { 0 };
+
+		macro_rules! fail {
+			($e:expr) => {{ std::hint::cold_path(); break Err($e); }};
+		}
+		macro_rules! check {
+			($e:expr) => {
+				match $e {
+					Ok(x) => x,
+					Err(e) => fail!(Error::from(e)),
+				}
+			};
+		}
+		// makes sure the stack has at least `n` elements, the first one is `tos`, see `run`
+		macro_rules! need {
+			($n:expr) => {
+				if sp + $n > MEM_SIZE {
+					sp = MEM_SIZE;
+					fail!(underflow());
+				}
+			};
+		}
+		// everything below the top lives in memory, so only pushes have to spill
+		macro_rules! pop {
+			() => {{
+				need!(1);
+				let x = tos;
+				sp += 1;
+				tos = mem.get(sp).copied().unwrap_or(0);
+				x
+			}};
+		}
+		macro_rules! push {
+			($x:expr) => {{
+				let x = $x;
+				if sp <= stack_end {
+					fail!(Error::runtime("stack overflow"));
+				}
+				if sp < MEM_SIZE {
+					mem[sp] = tos;
+				}
+				sp -= 1;
+				tos = x;
+			}};
+		}
+		// replaces the top two elements with one, without touching memory except for reading the second
+		macro_rules! binop {
+			(|$b:ident, $a:ident| $e:expr) => {{
+				need!(2);
+				let $a = tos;
+				sp += 1;
+				let $b = mem[sp];
+				tos = $e;
+			}};
+		}
+		macro_rules! call {
+			($target:expr) => {{
+				let target = $target;
+				if csp + 1 >= FIRST_INSTR {
+					fail!(Error::runtime("call stack overflow"));
+				}
+				csp += 1;
+				mem[csp] = self.addr_of[pc + 1] as i32;
+				pc = target;
+				continue;
+			}};
+		}
+		macro_rules! callstack_pop {
+			() => {{
+				if csp < CALL_STACK_START {
+					fail!(Error::runtime("call stack underflow"));
+				}
+				csp -= 1;
+				mem[csp + 1]
+			}};
+		}
+
+		let result = loop {
+			if fuel == 0 {
+				fail!(Error::OutOfFuel);
+			}
+			fuel -= 1;
+			match self.ops[pc] {
+				Op::Noop => {}
+				Op::Push(x) => push!(x),
+				Op::Dup => { need!(1); push!(tos) }
+				Op::Drop => { pop!(); }
+				Op::Swap => { need!(2); std::mem::swap(&mut tos, &mut mem[sp + 1]) }
+				Op::Rot => {
+					need!(3);
+					let c = mem[sp + 2];
+					mem[sp + 2] = mem[sp + 1];
+					mem[sp + 1] = tos;
+					tos = c;
+				}
+				Op::Pick => {
+					// after the pop all of the stack is in memory
+					let n = pop!();
+					push!(mem[check!(pick_addr(sp, n))]);
+				}
+				Op::Plus => binop!(|b, a| b.wrapping_add(a)),
+				Op::Minus => binop!(|b, a| b.wrapping_sub(a)),
+				Op::Mul => binop!(|b, a| b.wrapping_mul(a)),
+				Op::Div => {
+					need!(2);
+					if tos == 0 {
+						// both operands are gone, as in `run`
+						pop!();
+						pop!();
+						fail!(Error::runtime("division by zero"));
+					}
+					binop!(|b, a| b.wrapping_div(a));
+				}
+				Op::Negate => { need!(1); tos = tos.wrapping_neg() }
+				Op::BitAnd => binop!(|b, a| a & b),
+				Op::BitOr => binop!(|b, a| a | b),
+				Op::BitNot => { need!(1); tos = !tos }
+				Op::Gt => binop!(|b, a| if b > a { !0 } else { 0 }),
+				Op::Eq => binop!(|b, a| if a == b { !0 } else { 0 }),
+				Op::LessThan => binop!(|b, a| if b < a { !0 } else { 0 }),
+				Op::ReadChar => push!(read_char(input)),
+				Op::WriteChar => {
+					let a = pop!();
+					check!(write!(out, "{}", a as u8 as char));
+				}
+				Op::WriteInt => {
+					let a = pop!();
+					check!(write!(out, "{}", a));
+				}
+				Op::WriteString(start, len) => {
+					let text = mem[start as usize..(start + len) as usize].iter();
+					check!(text.map(|c| *c as u8 as char).try_for_each(|c| write!(out, "{}", c)));
+				}
+				Op::FlushIO => check!(out.flush()),
+
+				Op::Call => {
+					let addr = pop!();
+					call!(check!(self.jump(addr)));
+				}
+				Op::CallIf => {
+					let addr = pop!();
+					let cond = pop!();
+					if cond != 0 {
+						call!(check!(self.jump(addr)));
+					}
+				}
+				Op::Return => {
+					let addr = callstack_pop!();
+					pc = check!(self.jump(addr));
+					continue;
+				}
+				Op::Goto(target) => {
+					pc = target as usize;
+					continue;
+				}
+				Op::GotoIf(target) => {
+					if pop!() != 0 {
+						pc = target as usize;
+						continue;
+					}
+				}
+				Op::Halt => break Ok(()),
+
+				Op::VarRead => {
+					need!(1);
+					match FalseVM::var_addr(tos) {
+						Ok(addr) => tos = mem[addr],
+						Err(e) => {
+							pop!();
+							fail!(e);
+						}
+					}
+				}
+				Op::VarWrite => {
+					let var = pop!();
+					let value = pop!();
+					mem[check!(FalseVM::var_addr(var))] = value;
+				}
+				Op::MoveToCallStack => {
+					let x = pop!();
+					if csp + 1 >= FIRST_INSTR {
+						fail!(Error::runtime("call stack overflow"));
+					}
+					csp += 1;
+					mem[csp] = x;
+				}
+				Op::PickFromCallStack(n) => push!(mem[check!(callstack_pick_addr(csp, n))]),
+				Op::DropFromCallStack => { callstack_pop!(); }
+				Op::TailCall => {
+					let addr = pop!();
+					pc = check!(self.jump(addr));
+					continue;
+				}
+				Op::TailCallIf => {
+					let addr = pop!();
+					let cond = pop!();
+					if cond != 0 {
+						pc = check!(self.jump(addr));
+						continue;
+					}
+				}
+
+				Op::LoadVar(n) => push!(mem[check!(FalseVM::var_addr(n))]),
+				Op::StoreVar(n) => {
+					let value = pop!();
+					mem[check!(FalseVM::var_addr(n))] = value;
+				}
+				Op::CallImm(target) => call!(target as usize),
+				Op::CallIfImm(target) => {
+					if pop!() != 0 {
+						call!(target as usize);
+					}
+				}
+				Op::AddImm(n) => { need!(1); tos = tos.wrapping_add(n) }
+				Op::GtImm(n) => { need!(1); tos = if tos > n { !0 } else { 0 } }
+				Op::EqImm(n) => { need!(1); tos = if tos == n { !0 } else { 0 } }
+				Op::DupGtImm(n) => { need!(1); push!(if tos > n { !0 } else { 0 }) }
+
+				Op::Invalid(cell) => fail!(invalid_opcode(cell)),
+				Op::BadJump(addr) => fail!(invalid_jump(addr)),
+				Op::BadJumpIf(addr) => {
+					if pop!() != 0 {
+						fail!(invalid_jump(addr));
+					}
+				}
+				Op::TruncatedString(start, len) => {
+					let text = mem[start as usize..(start + len) as usize].iter();
+					check!(text.map(|c| *c as u8 as char).try_for_each(|c| write!(out, "{}", c)));
+					fail!(invalid_jump((start + len) as i32));
+				}
+			}
+			pc += 1;
+		};
+
+		if sp < MEM_SIZE {
+			mem[sp] = tos;
+		}
+		vm.stack_pointer = sp;
+		vm.callstack_pointer = csp;
+		vm.cursor = self.addr_of[pc] as usize + result.is_ok() as usize;
+		if vm.fuel.is_some() {
+			vm.fuel = Some(fuel);
+		}
+		result
+	}
+}
+
+
+#[cold]
+fn underflow() -> Error {
+	Error::runtime("stack underflow")
+}
+
+/// Address of the `n`th element below the top of the stack at `sp`.
+fn pick_addr(sp: usize, n: i32) -> Result<usize, Error> {
+	usize::try_from(n).ok()
+		.map(|n| sp + n)
+		.filter(|addr| *addr < MEM_SIZE)
+		.ok_or_else(|| pick_out_of_range(n))
+}
+
+/// Address of the `n`th element below the top of the call stack at `csp`.
+fn callstack_pick_addr(csp: usize, n: i32) -> Result<usize, Error> {
+	usize::try_from(n).ok()
+		.and_then(|n| csp.checked_sub(n))
+		.filter(|addr| *addr >= CALL_STACK_START)
+		.ok_or_else(|| Error::runtime("call stack underflow"))
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::asm;
+	use crate::falselang::interpreter::Capture;
+
+	const FUEL: u64 = 10_000_000;
+
+	/// Runs the program with all engines and checks that the VM ends up in the same state,
+	/// with the same fuel left, also when it fails.
+	/// Cells that were popped off either stack are not compared, popping leaves them as they were.
+	fn check(code: &str, opt_level: u8) {
+		check_loaded(&format!("{:?} at level {}", code, opt_level), |vm| vm.load(code, opt_level).unwrap());
+	}
+
+	/// `check` for a program that `load` puts into the VM.
+	fn check_loaded(name: &str, load: impl Fn(&mut FalseVM)) {
+		let mut runs = Vec::new();
+		for engine in 0..3 {
+			let buf = Capture::default();
+			let mut vm = FalseVM::new();
+			vm.input = Box::new(std::io::Cursor::new(b"xy".to_vec()));
+			vm.output = Box::new(buf.clone());
+			vm.fuel = Some(FUEL);
+			load(&mut vm);
+			let result = match engine {
+				0 => vm.run(),
+				1 => ThreadedCode::new(&vm).run(&mut vm),
+				_ => ThreadedCode::new(&vm).run_cached(&mut vm),
+			};
+			let out = String::from_utf8(buf.bytes().to_vec()).unwrap();
+			let live = [
+				&vm.memory[..=vm.callstack_pointer],
+				&vm.memory[FIRST_INSTR..vm.code_end],
+				&vm.memory[vm.stack_pointer..],
+			].concat();
+			runs.push((result, out, live, vm.cursor, vm.stack_pointer, vm.callstack_pointer, vm.fuel));
+		}
+		assert!(runs[0] == runs[1], "threaded engine disagrees on {}", name);
+		assert!(runs[0] == runs[2], "cached engine disagrees on {}", name);
+	}
+
+	#[test]
+	fn test_same_as_step_on_bad_code() {
+		// hand-written code can jump anywhere and run off the end
+		for source in [
+			"push 1\ngoto 1000\nhalt",
+			"push 0\ngotoif 1000\npush 1\ngotoif 1000\nhalt",
+			"push 0\ncallifimm 1000\ncallimm 1000\nhalt",
+			"push 1\nwritestring \"ab\"",
+			"push 1\n.word 1020\n.word 50\n.word 97\n.word 98",
+			"push 1\n.word 1020",
+			"push 1\n.word 7\nhalt",
+		] {
+			check_loaded(&format!("{:?}", source), |vm| asm::assemble(vm, source).unwrap());
+		}
+	}
+
+	#[test]
+	fn test_cached_resume() {
+		// the cached top has to be loaded from and written back to memory
+		let mut vm = FalseVM::new();
+		vm.load("1+ 5 \\", 0).unwrap();
+		vm.push(10).unwrap();
+		ThreadedCode::new(&vm).run_cached(&mut vm).unwrap();
+		assert_eq!(vm.stack_size(), 2);
+		assert_eq!(vm.pop(), Ok(11));
+		assert_eq!(vm.pop(), Ok(5));
+	}
+
+	#[test]
+	fn test_predecode() {
+		let mut vm = FalseVM::new();
+		vm.load("[1+]f: 2 f;! \"hi\"", 0).unwrap();
+		let code = ThreadedCode::new(&vm);
+		assert_eq!(code.ops, vec![
+			Op::Push(1), Op::Plus, Op::Return,
+			Op::Push(FIRST_INSTR as i32), Op::Push(5), Op::VarWrite,
+			Op::Push(2), Op::Push(5), Op::VarRead, Op::Call,
+			Op::WriteString(FIRST_INSTR as u32 + 17, 2),
+			Op::Halt,
+			Op::BadJump(FIRST_INSTR as i32 + 20),
+		]);
+		assert_eq!(code.op_index(FIRST_INSTR as i32 + 4), Some(3));
+		assert_eq!(code.op_index(FIRST_INSTR as i32 + 5), None);
+	}
+
+	#[test]
+	fn test_jumps() {
+		let mut vm = FalseVM::new();
+		vm.load("10[$5>][1-]#", 2).unwrap();
+		let code = ThreadedCode::new(&vm);
+		assert_eq!(code.ops[4..], [Op::Push(10), Op::Goto(7), Op::AddImm(-1), Op::DupGtImm(5), Op::GotoIf(6), Op::Halt, Op::BadJump(vm.code_end as i32)]);
+		code.run(&mut vm).unwrap();
+		assert_eq!(vm.stack_size(), 1);
+		assert_eq!(vm.pop(), Ok(5));
+	}
+
+	#[test]
+	fn test_same_as_step() {
+		let programs = [
+			"",
+			"1 2 3 4 5 2ø 7 8 9 2P 1 2 3% 1 2\\ 0 1 2 3@ 2 4$",
+			"1 2- 3 4* 9 2/ 7_ 2/ 2 3> 3 2> 4 4= 4 5= 3 1& 3 1| 5~",
+			"1[777]? 0[333]? [1+]f: 50 f;! [2]a: 1a;?",
+			"[$1=$[\\%1\\]?~[$1-f;!*]?]f: 6 f;!",
+			"[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;!",
+			"[$0>[1-f;!]?]f: 10000 f;!",
+			"10 [$5 >]c: [1 -]b: c;b;#",
+			"0 3[$][1- \\ 2[$][1- \\ 1+ \\]# % \\]#%",
+			"\"hello\" 10, 2 3+. 'x,",
+			"^^,, ^ 2 3< 3 2< 2 2< ß",
+			"99b:\n[b;0=[\"No more bottles of beer\"]?b;1=[\"1 more bottle of beer\"]?b;1>[b;.\" bottles of beer\"]?]a:\n[b;0>][a;!\" on the wall\"10,a;!10,\"Take one down, pass it around\"10,b;1-b:a;!\" on the wall\n\"]#",
+			"50 9[1-$][\\$@$@$@$@\\/*=[1-$$[%\\1-$@]?0=[\\$.' ,\\]?]?]#",
+		];
+		for code in programs {
+			for opt_level in [0, 1, 2] {
+				check(code, opt_level);
+			}
+		}
+	}
+
+	#[test]
+	fn test_same_as_step_on_errors() {
+		let programs = [
+			"5 0/",
+			"1 2 5 0/",
+			"1\\",
+			"1 2+ +",
+			"1@",
+			"1 2@",
+			"$",
+			"1+ 2",
+			"3 4 5>",
+			"7 50;",
+			"7 1 2 50:",
+			"1 2 5ø",
+			"[f;! 1]f: f;!",
+			"1 2 3 0!",
+			"1 3 1?",
+			"1 2.. .",
+			"9 [1 0/]f: [f;!]g: g;!",
+			"10 [$0>][1-$5=[0 0/]?]#",
+		];
+		for code in programs {
+			for opt_level in [0, 1, 2] {
+				check(code, opt_level);
+			}
+		}
+	}
+}
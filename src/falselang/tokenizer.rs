@@ -1,3 +1,6 @@
+use super::error::Error;
+
+
 /// Supported tokens:
 ///
 /// ### Literals
@@ -49,7 +52,36 @@
 /// ### Other
 /// - `{...}` comment
 pub struct Tokenizer {
-	code: String,
+	code: Vec<char>,
+	pos: usize,
+	start: usize,
+	pub dialect: Dialect,
+}
+
+/// Which flavor of FALSE is accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+	Rfalse, // with the extensions marked above
+	Strict, // the original language only
+}
+
+impl Dialect {
+	pub const ALL: [Dialect; 2] = [Dialect::Rfalse, Dialect::Strict];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Dialect::Rfalse => "rfalse",
+			Dialect::Strict => "strict",
+		}
+	}
+}
+
+impl std::str::FromStr for Dialect {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Dialect::ALL.iter().find(|d| d.name() == s).copied().ok_or_else(|| format!("unknown dialect: {}", s))
+	}
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -158,74 +190,98 @@ const SIMPLE_TOKENS: [(char, Token); 55] = [
 	('B', Token::FlushIO),
 ];
 
+/// Characters of `SIMPLE_TOKENS` that only exist in `Dialect::Rfalse`.
+const EXTENSIONS: [char; 3] = ['P', '<', 'B'];
+
 impl Tokenizer {
 	pub fn new(code: &str) -> Tokenizer {
+		Tokenizer::with_dialect(code, Dialect::Rfalse)
+	}
+
+	pub fn with_dialect(code: &str, dialect: Dialect) -> Tokenizer {
 		Tokenizer {
-			code: code.to_string(),
+			code: code.chars().collect(),
+			pos: 0,
+			start: 0,
+			dialect,
 		}
 	}
 
+	/// Char offset of the next token, or of the whitespace before it.
+	pub fn pos(&self) -> usize {
+		self.pos
+	}
+
+	/// Char offset where the token last returned by `next_token` begins.
+	pub fn start(&self) -> usize {
+		self.start
+	}
+
 	pub fn skip_whitespace(&mut self) {
-		match self.code.chars().next() {
-			None => return,
-			Some(c) => {
-				if c.is_whitespace() {
-					self.code = self.code[1..].to_string();
-					self.skip_whitespace();
-				}
-			}
+		while self.code.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+			self.pos += 1;
 		}
 	}
 
-	pub fn next_token(&mut self) -> Option<Token> {
-		self.skip_whitespace();
-
-		let c = match self.code.chars().next() {
-			None => return None,
-			Some(x) => x,
-		};
+	/// Offset of the next `c` at or after `from`.
+	fn find(&self, c: char, from: usize) -> Option<usize> {
+		self.code.get(from..)?.iter().position(|x| *x == c).map(|i| from + i)
+	}
 
-		for (token_char, token) in SIMPLE_TOKENS.iter() {
-			if c == *token_char {
-				self.code = self.code.chars().skip(1).collect();
-				return Some(token.clone())
+	pub fn next_token(&mut self) -> Result<Option<Token>, Error> {
+		loop {
+			self.skip_whitespace();
+			let start = self.pos;
+			let c = match self.code.get(start) {
+				Some(c) => *c,
+				None => return Ok(None),
+			};
+			self.start = start;
+			if self.dialect == Dialect::Strict && EXTENSIONS.contains(&c) {
+				return Err(Error::syntax(start, &format!("{} is an rfalse extension", c)));
 			}
-		}
 
-		match c {
-			'{' => {
-				let end = self.code.find('}').expect("parser error");
-				self.code = self.code[end+1..].to_string();
-				return self.next_token()
-			}
-			'"' => {
-				self.code = self.code[1..].to_string();
-				let end = self.code.find('"').expect("parser error");
-				let token = Token::PrintString(self.code[0..end].to_string());
-				self.code = self.code[end+1..].to_string();
-				return Some(token)
-			}
-			'\'' => {
-				let charcode = self.code.chars().nth(1).expect("parser error");
-				self.code = self.code[2..].to_string();
-				return Some(Token::Number(charcode as i64))
+			for (token_char, token) in SIMPLE_TOKENS.iter() {
+				if c == *token_char {
+					self.pos += 1;
+					return Ok(Some(token.clone()));
+				}
 			}
-			_ => {
-				let end = self.code.find(|c: char| !c.is_digit(10)).unwrap_or(self.code.len());
-				let token = self.code[..end].parse().expect("Invalid token");
-				self.code = self.code[end..].to_string();
-				return Some(Token::Number(token))
+
+			match c {
+				'{' => {
+					let end = self.find('}', start + 1).ok_or_else(|| Error::incomplete(start, "unterminated comment"))?;
+					self.pos = end + 1;
+				}
+				'"' => {
+					let end = self.find('"', start + 1).ok_or_else(|| Error::incomplete(start, "unterminated string"))?;
+					self.pos = end + 1;
+					return Ok(Some(Token::PrintString(self.code[start + 1..end].iter().collect())));
+				}
+				'\'' => {
+					let charcode = *self.code.get(start + 1).ok_or_else(|| Error::incomplete(start, "missing character after '"))?;
+					self.pos += 2;
+					return Ok(Some(Token::Number(charcode as i64)));
+				}
+				_ if c.is_ascii_digit() => {
+					let len = self.code[start..].iter().take_while(|c| c.is_ascii_digit()).count();
+					let digits: String = self.code[start..start + len].iter().collect();
+					let n = digits.parse().map_err(|_| Error::syntax(start, "number too large"))?;
+					self.pos += len;
+					return Ok(Some(Token::Number(n)));
+				}
+				_ => return Err(Error::syntax(start, &format!("invalid token {:?}", c))),
 			}
 		}
 	}
 
 	#[allow(dead_code)]
-	pub fn all(&mut self) -> Vec<Token> {
+	pub fn all(&mut self) -> Result<Vec<Token>, Error> {
 		let mut tokens = Vec::new();
-		while let Some(token) = self.next_token() {
+		while let Some(token) = self.next_token()? {
 			tokens.push(token);
 		}
-		tokens
+		Ok(tokens)
 	}
 }
 
@@ -238,22 +294,22 @@ mod tests {
 	fn test_next_token() {
 		let mut parser = Tokenizer::new("1+2-3* 4    / 5     ");
 
-		assert_eq!(parser.next_token(), Some(Token::Number(1)));
-		assert_eq!(parser.next_token(), Some(Token::Plus));
-		assert_eq!(parser.next_token(), Some(Token::Number(2)));
-		assert_eq!(parser.next_token(), Some(Token::Minus));
-		assert_eq!(parser.next_token(), Some(Token::Number(3)));
-		assert_eq!(parser.next_token(), Some(Token::Mul));
-		assert_eq!(parser.next_token(), Some(Token::Number(4)));
-		assert_eq!(parser.next_token(), Some(Token::Div));
-		assert_eq!(parser.next_token(), Some(Token::Number(5)));
-		assert_eq!(parser.next_token(), None);
+		assert_eq!(parser.next_token(), Ok(Some(Token::Number(1))));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Plus)));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Number(2))));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Minus)));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Number(3))));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Mul)));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Number(4))));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Div)));
+		assert_eq!(parser.next_token(), Ok(Some(Token::Number(5))));
+		assert_eq!(parser.next_token(), Ok(None));
 	}
 
 	#[test]
 	fn test_1() {
 		let mut parser = Tokenizer::new("1+2-3* 4    / 5     ");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::Number(1),
@@ -271,7 +327,7 @@ mod tests {
 	#[test]
 	fn test_2() {
 		let mut parser = Tokenizer::new("'a'b     'c    'd    266667");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::Number(97),
@@ -285,7 +341,7 @@ mod tests {
 	#[test]
 	fn test_3() {
 		let mut parser = Tokenizer::new("$ % \\ @ ø P");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::Dup,
@@ -300,7 +356,7 @@ mod tests {
 	#[test]
 	fn test_4() {
 		let mut parser = Tokenizer::new("+ - * / _ & | ~");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::Plus,
@@ -317,7 +373,7 @@ mod tests {
 	#[test]
 	fn test_5() {
 		let mut parser = Tokenizer::new("> = <");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::GreaterThan,
@@ -329,7 +385,7 @@ mod tests {
 	#[test]
 	fn test_6() {
 		let mut parser = Tokenizer::new("[]!?#");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::LambdaStart,
@@ -343,7 +399,7 @@ mod tests {
 	#[test]
 	fn test_7() {
 		let mut parser = Tokenizer::new("a:b;z");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::Variable('a'),
@@ -357,7 +413,7 @@ mod tests {
 	#[test]
 	fn test_8() {
 		let mut parser = Tokenizer::new("a:b;z");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::Variable('a'),
@@ -371,7 +427,7 @@ mod tests {
 	#[test]
 	fn test_9() {
 		let mut parser = Tokenizer::new("^,.ßB");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::ReadChar,
@@ -385,7 +441,7 @@ mod tests {
 	#[test]
 	fn test_10() {
 		let mut parser = Tokenizer::new("\"hello\"        \"wo\nr\nld\"");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::PrintString("hello".to_string()),
@@ -396,7 +452,7 @@ mod tests {
 	#[test]
 	fn test_11() {
 		let mut parser = Tokenizer::new("1{wow}2{cool}3");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::Number(1),
@@ -408,7 +464,7 @@ mod tests {
 	#[test]
 	fn test_12() {
 		let mut parser = Tokenizer::new("[$1=$[\\%1\\]?~[$1-f;!*]?]f:");
-		let tokens = parser.all();
+		let tokens = parser.all().unwrap();
 
 		assert_eq!(tokens, vec![
 			Token::LambdaStart,
@@ -439,4 +495,46 @@ mod tests {
 			Token::VarWrite,
 		]);
 	}
+
+	#[test]
+	fn test_strict() {
+		let mut parser = Tokenizer::with_dialect("ø ß 'P \"B<\"", Dialect::Strict);
+		assert_eq!(parser.all().unwrap(), vec![
+			Token::Pick,
+			Token::FlushIO,
+			Token::Number(80),
+			Token::PrintString("B<".to_string()),
+		]);
+		let mut parser = Tokenizer::with_dialect("1 0P", Dialect::Strict);
+		assert_eq!(parser.all(), Err(Error::syntax(3, "P is an rfalse extension")));
+	}
+
+	#[test]
+	fn test_errors() {
+		for (code, error) in [
+			("1 {abc", Error::incomplete(2, "unterminated comment")),
+			("\"abc", Error::incomplete(0, "unterminated string")),
+			("12 '", Error::incomplete(3, "missing character after '")),
+			("99999999999999999999", Error::syntax(0, "number too large")),
+			("1 2 Q", Error::syntax(4, "invalid token 'Q'")),
+		] {
+			assert_eq!(Tokenizer::new(code).all(), Err(error), "{:?}", code);
+		}
+		assert!(Tokenizer::new("1 {abc").all().unwrap_err().is_incomplete());
+		assert!(Tokenizer::new("12 '").all().unwrap_err().is_incomplete());
+		assert!(!Tokenizer::new("1 2 Q").all().unwrap_err().is_incomplete());
+	}
+
+	#[test]
+	fn test_unicode() {
+		// multibyte whitespace and characters, positions are in chars
+		let mut parser = Tokenizer::new("\u{3000}'é\"ü\"{ø}1");
+		assert_eq!(parser.all().unwrap(), vec![
+			Token::Number('é' as i64),
+			Token::PrintString("ü".to_string()),
+			Token::Number(1),
+		]);
+		assert_eq!(parser.pos(), 10);
+		assert_eq!(parser.start(), 9);
+	}
 }
@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use super::disasm::decode;
+use super::error::Error;
+use super::formatter::token_text;
+use super::parser::Parser;
+use super::profiler::names;
+use super::tokenizer::{Dialect, Token, Tokenizer};
+use super::vm::{self, StackElement, StepResult};
+use super::vm2::{self, Instr};
+
+
+/// Which steps `Tracer` writes. A step has to pass all filters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+	/// Steps that enter or leave a lambda.
+	Calls,
+	/// Steps that read input or write output.
+	Io,
+	/// Steps of a lambda itself, by its name as in `Profile::names` or by the part before the `@`.
+	Lambda(String),
+}
+
+/// Parses `calls`, `io` and `lambda=NAME`.
+impl FromStr for Filter {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.split_once('=') {
+			None if s == "calls" => Ok(Filter::Calls),
+			None if s == "io" => Ok(Filter::Io),
+			Some(("lambda", name)) if !name.is_empty() => Ok(Filter::Lambda(name.to_string())),
+			_ => Err(format!("unknown trace filter: {}", s)),
+		}
+	}
+}
+
+/// One step, as `Tracer` sees it.
+struct Step {
+	place: String, // JSON fields for where in the code
+	op: String,
+	arg: Option<String>, // JSON value
+	pos: Option<usize>, // char offset of the token
+	lambda: Option<usize>,
+	stack: Vec<String>, // JSON values, bottom first
+	depth: usize,
+	set: Option<(char, String)>,
+	call: bool,
+	io: bool,
+}
+
+/// Runs a program one step at a time and writes a JSON object per step to `out`, one per line:
+///
+/// ```text
+/// {"step":3,"engine":"tree","fn":1,"cursor":0,"op":"2","lambda":"f@1:1","line":1,"col":2,"stack":[1,2],"depth":2}
+/// ```
+///
+/// Where in the code is `fn` and `cursor` for the tree-walking VM and `addr` for the bytecode VM,
+/// bytecode ops have an `arg` if they take one. `line` and `col` are those of the token the step
+/// comes from, `stack` holds the top values after the step, bottom first, and `set` the variable
+/// the step wrote. The step that fails gets an `error`. Steps are numbered before filtering.
+pub struct Tracer {
+	pub filters: Vec<Filter>,
+	/// Most values of the stack in each line.
+	pub stack_size: usize,
+	names: Vec<String>, // see `Profile::names`
+	lines: Vec<usize>, // char offset where each line starts
+	out: Box<dyn Write>,
+	steps: u64,
+}
+
+impl Tracer {
+	/// Names the lambdas of `source`, which is what the VM has to be loaded with.
+	pub fn new(source: &str, dialect: Dialect, out: Box<dyn Write>) -> Result<Tracer, Error> {
+		let mut parser = Parser::new(Tokenizer::with_dialect(source, dialect));
+		parser.parse()?;
+		let names = names(source, &parser.lambda_storage, &parser.spans);
+		let mut lines = vec![0];
+		lines.extend(source.chars().enumerate().filter(|(_, c)| *c == '\n').map(|(pos, _)| pos + 1));
+		Ok(Tracer { filters: Vec::new(), stack_size: 8, names, lines, out, steps: 0 })
+	}
+
+	/// Runs the tree-walking VM to the end.
+	pub fn run_tree(&mut self, vm: &mut vm::FalseVM) -> Result<(), Error> {
+		// lambdas from earlier loads come first
+		let base = vm.functions.len() - self.names.len();
+		loop {
+			let (fn_index, cursor, depth) = (vm.fn_index, vm.cursor, vm.frames.len());
+			let token = vm.functions[fn_index].get(cursor).cloned();
+			let var = match (&token, vm.stack.last()) {
+				(Some(Token::VarWrite), Some(StackElement::Variable(v))) => Some(*v),
+				_ => None,
+			};
+			let result = vm.step();
+			if result == Ok(StepResult::End) {
+				return self.flush();
+			}
+			let step = Step {
+				place: format!("\"fn\":{},\"cursor\":{}", fn_index, cursor),
+				op: token.as_ref().map_or("]".to_string(), token_text),
+				arg: None,
+				pos: vm.spans.get(fn_index).and_then(|spans| spans.get(cursor)).copied(),
+				lambda: fn_index.checked_sub(base),
+				stack: vm.stack.iter().rev().take(self.stack_size).rev().map(json_value).collect(),
+				depth: vm.stack.len(),
+				set: var.and_then(|v| vm.variables.get(&v).map(|x| (v, json_value(x)))),
+				call: (vm.fn_index, vm.frames.len()) != (fn_index, depth),
+				io: matches!(token, Some(Token::ReadChar | Token::WriteChar | Token::WriteInt | Token::PrintString(_) | Token::FlushIO)),
+			};
+			self.write(step, result.as_ref().err())?;
+			result?;
+		}
+	}
+
+	/// Runs the bytecode VM to the end. Code that `load` inlined counts for the lambda it was
+	/// inlined into, so entering it is no call.
+	pub fn run_bytecode(&mut self, vm: &mut vm2::FalseVM) -> Result<(), Error> {
+		let starts: BTreeMap<usize, usize> = vm.fn_pointer.iter().map(|(n, addr)| (*addr, *n)).collect();
+		let lambda_at = |addr: usize| starts.range(..=addr).next_back().map(|(_, n)| *n);
+		loop {
+			let at = vm.cursor;
+			let calls = vm.callstack_pointer;
+			let decoded = (vm2::FIRST_INSTR..vm.code_end).contains(&at).then(|| {
+				let len = vm.memory.get(at + 1).map_or(0, |len| (*len).max(0) as usize);
+				decode(vm, at, (at + 2 + len).min(vm.code_end)).remove(0)
+			});
+			let instr = decoded.as_ref().and_then(|d| d.instr.ok());
+			let var = match instr {
+				Some(Instr::VarWrite) => vm.memory.get(vm.stack_pointer).copied(),
+				Some(Instr::StoreVar) => decoded.as_ref().map(|d| d.args[0]),
+				_ => None,
+			};
+			let result = vm.step();
+			if result == Ok(StepResult::End) {
+				return self.flush();
+			}
+			let (op, arg) = match &decoded {
+				Some(d) => match d.instr {
+					Ok(Instr::WriteString) => {
+						let text: String = d.args.iter().map(|c| char::from_u32(*c as u32).unwrap_or('?')).collect();
+						("writestring".to_string(), Some(json_string(&text)))
+					}
+					Ok(i) => (i.mnemonic().to_string(), d.args.first().map(|x| x.to_string())),
+					Err(cell) => ("invalid".to_string(), Some(cell.to_string())),
+				},
+				None => ("invalid".to_string(), None),
+			};
+			let step = Step {
+				place: format!("\"addr\":{}", at),
+				op,
+				arg,
+				pos: vm.spans.get(&at).and_then(|spans| spans.iter().min()).copied(),
+				lambda: lambda_at(at),
+				stack: vm.memory[vm.stack_pointer..].iter().take(self.stack_size).rev().map(|x| x.to_string()).collect(),
+				depth: vm.stack_size(),
+				set: var.filter(|n| (0..26).contains(n)).map(|n| ((b'a' + n as u8) as char, vm.memory[vm2::FIRST_VAR + n as usize].to_string())),
+				call: match instr {
+					Some(Instr::Call | Instr::CallIf | Instr::CallImm | Instr::CallIfImm) => vm.callstack_pointer > calls,
+					Some(Instr::TailCall | Instr::TailCallIf | Instr::Goto | Instr::GotoIf) => starts.contains_key(&vm.cursor),
+					Some(Instr::Return) => true,
+					_ => false,
+				},
+				io: matches!(instr, Some(Instr::ReadChar | Instr::WriteChar | Instr::WriteInt | Instr::WriteString | Instr::FlushIO)),
+			};
+			self.write(step, result.as_ref().err())?;
+			result?;
+		}
+	}
+
+	/// Numbers `step` and writes it, unless a filter leaves it out.
+	fn write(&mut self, step: Step, error: Option<&Error>) -> Result<(), Error> {
+		self.steps += 1;
+		let lambda = step.lambda.and_then(|n| self.names.get(n)).map_or("?", |name| name.as_str());
+		let keep = self.filters.iter().all(|filter| match filter {
+			Filter::Calls => step.call,
+			Filter::Io => step.io,
+			Filter::Lambda(name) => lambda == name || lambda.split('@').next() == Some(name.as_str()),
+		});
+		if !keep {
+			return Ok(());
+		}
+
+		let engine = if step.place.starts_with("\"fn\"") { "tree" } else { "bytecode" };
+		let mut line = format!("{{\"step\":{},\"engine\":\"{}\",{},\"op\":{}", self.steps, engine, step.place, json_string(&step.op));
+		if let Some(arg) = step.arg {
+			line += &format!(",\"arg\":{}", arg);
+		}
+		line += &format!(",\"lambda\":{}", json_string(lambda));
+		if let Some(pos) = step.pos {
+			let n = self.lines.partition_point(|start| *start <= pos);
+			line += &format!(",\"line\":{},\"col\":{}", n, pos - self.lines[n - 1] + 1);
+		}
+		line += &format!(",\"stack\":[{}],\"depth\":{}", step.stack.join(","), step.depth);
+		if let Some((name, value)) = step.set {
+			line += &format!(",\"set\":{{\"{}\":{}}}", name, value);
+		}
+		if let Some(e) = error {
+			line += &format!(",\"error\":{}", json_string(&e.to_string()));
+		}
+		line += "}";
+		writeln!(self.out, "{}", line).map_err(trace_error)?;
+		if error.is_some() {
+			self.flush()?;
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		self.out.flush().map_err(trace_error)
+	}
+}
+
+fn trace_error(e: std::io::Error) -> Error {
+	Error::Runtime(format!("can't write the trace: {}", e))
+}
+
+/// A stack value as JSON: numbers as they are, lambdas as `"[fnN]"` and variables by name.
+pub(crate) fn json_value(x: &StackElement) -> String {
+	match x {
+		StackElement::Number(n) => n.to_string(),
+		StackElement::Lambda(n) => json_string(&format!("[fn{}]", n)),
+		StackElement::Variable(v) => json_string(&v.to_string()),
+	}
+}
+
+/// `s` as a JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+	let mut out = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' => out += "\\\"",
+			'\\' => out += "\\\\",
+			'\n' => out += "\\n",
+			'\t' => out += "\\t",
+			c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::falselang::interpreter::Capture;
+
+	/// Trace lines of `code` on `engine`, and whether the run went well.
+	fn trace(code: &str, engine: &str, filters: &[Filter]) -> (Vec<String>, bool) {
+		let buf = Capture::default();
+		let mut tracer = Tracer::new(code, Dialect::Rfalse, Box::new(buf.clone())).unwrap();
+		tracer.filters = filters.to_vec();
+		tracer.stack_size = 2;
+		let ok = if engine == "tree" {
+			let mut vm = vm::FalseVM::new();
+			vm.input = Box::new(std::io::empty());
+			vm.output = Box::new(std::io::sink());
+			vm.load(code).unwrap();
+			tracer.run_tree(&mut vm).is_ok()
+		} else {
+			let mut vm = vm2::FalseVM::new();
+			vm.input = Box::new(std::io::empty());
+			vm.output = Box::new(std::io::sink());
+			vm.load(code, 0).unwrap();
+			tracer.run_bytecode(&mut vm).is_ok()
+		};
+		let text = String::from_utf8(buf.bytes().to_vec()).unwrap();
+		(text.lines().map(String::from).collect(), ok)
+	}
+
+	#[test]
+	fn test_tree() {
+		let (lines, ok) = trace("1 [2 3]!\n5a:", "tree", &[]);
+		assert!(ok);
+		assert_eq!(lines, [
+			r#"{"step":1,"engine":"tree","fn":1,"cursor":0,"op":"1","lambda":"main","line":1,"col":1,"stack":[1],"depth":1}"#,
+			r#"{"step":2,"engine":"tree","fn":1,"cursor":1,"op":"[fn0]","lambda":"main","line":1,"col":3,"stack":[1,"[fn0]"],"depth":2}"#,
+			r#"{"step":3,"engine":"tree","fn":1,"cursor":2,"op":"!","lambda":"main","line":1,"col":8,"stack":[1],"depth":1}"#,
+			r#"{"step":4,"engine":"tree","fn":0,"cursor":0,"op":"2","lambda":"lambda@1:3","line":1,"col":4,"stack":[1,2],"depth":2}"#,
+			r#"{"step":5,"engine":"tree","fn":0,"cursor":1,"op":"3","lambda":"lambda@1:3","line":1,"col":6,"stack":[2,3],"depth":3}"#,
+			r#"{"step":6,"engine":"tree","fn":0,"cursor":2,"op":"]","lambda":"lambda@1:3","line":1,"col":7,"stack":[2,3],"depth":3}"#,
+			r#"{"step":7,"engine":"tree","fn":1,"cursor":3,"op":"5","lambda":"main","line":2,"col":1,"stack":[3,5],"depth":4}"#,
+			r#"{"step":8,"engine":"tree","fn":1,"cursor":4,"op":"a","lambda":"main","line":2,"col":2,"stack":[5,"a"],"depth":5}"#,
+			r#"{"step":9,"engine":"tree","fn":1,"cursor":5,"op":":","lambda":"main","line":2,"col":3,"stack":[2,3],"depth":3,"set":{"a":5}}"#,
+		]);
+	}
+
+	#[test]
+	fn test_bytecode() {
+		let (lines, ok) = trace("\"a\nb\" 1 0/", "bytecode", &[]);
+		assert!(!ok);
+		assert_eq!(lines, [
+			r#"{"step":1,"engine":"bytecode","addr":666,"op":"writestring","arg":"a\nb","lambda":"main","line":1,"col":1,"stack":[],"depth":0}"#,
+			r#"{"step":2,"engine":"bytecode","addr":671,"op":"push","arg":1,"lambda":"main","line":2,"col":4,"stack":[1],"depth":1}"#,
+			r#"{"step":3,"engine":"bytecode","addr":673,"op":"push","arg":0,"lambda":"main","line":2,"col":6,"stack":[1,0],"depth":2}"#,
+			r#"{"step":4,"engine":"bytecode","addr":675,"op":"div","lambda":"main","line":2,"col":7,"stack":[],"depth":0,"error":"runtime error: division by zero"}"#,
+		]);
+		let (lines, _) = trace("[1+]f: 5a: 2f;!a;+ b:", "bytecode", &[]);
+		let sets: Vec<&str> = lines.iter().filter_map(|line| line.split_once(r#""set":"#)).map(|(_, set)| set).collect();
+		assert_eq!(sets, [r#"{"f":666}}"#, r#"{"a":5}}"#, r#"{"b":8}}"#]);
+	}
+
+	#[test]
+	fn test_filters() {
+		let code = "[$[1-f;!]?]f: 2f;! 'x, [^]!";
+		for engine in ["tree", "bytecode"] {
+			let (lines, _) = trace(code, engine, &[Filter::Io]);
+			assert_eq!(lines.len(), 2, "{}: {:?}", engine, lines);
+			assert!(lines.iter().all(|line| line.contains(r#""lambda":"main""#) || line.contains(r#""lambda":"lambda@1:24""#)), "{}: {:?}", engine, lines);
+			// f is entered three times, and returns as often
+			let (lines, _) = trace(code, engine, &[Filter::Calls, Filter::Lambda("main".to_string())]);
+			assert!(lines.iter().all(|line| line.contains(r#""lambda":"main""#)), "{}: {:?}", engine, lines);
+			let (lines, _) = trace(code, engine, &[Filter::Lambda("f".to_string())]);
+			assert!(!lines.is_empty() && lines.iter().all(|line| line.contains(r#""lambda":"f@1:1""#)), "{}: {:?}", engine, lines);
+		}
+		assert_eq!("lambda=f".parse(), Ok(Filter::Lambda("f".to_string())));
+		assert_eq!("io".parse(), Ok(Filter::Io));
+		assert!("lambda=".parse::<Filter>().is_err());
+		assert!("all".parse::<Filter>().is_err());
+	}
+}
@@ -1,13 +1,15 @@
-use super::tokenizer::{Token, Tokenizer};
+use super::error::Error;
+use super::interpreter::read_char;
+use super::tokenizer::{Dialect, Token, Tokenizer};
 
-use std::{collections::HashMap, io::{self, Read, Write}};
+use std::{collections::HashMap, io::{Read, Write}};
 
+pub use super::interpreter::StepResult;
+
+/// Most frames `FalseVM` keeps before failing with a call stack overflow. Far more than the
+/// bytecode VM's `CALL_STACK_SIZE`, deep recursion only costs heap here.
+pub const MAX_FRAMES: usize = 1 << 20;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum StepResult {
-	OK,
-	End,
-}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StackElement {
@@ -39,16 +41,43 @@ impl StackElement {
 	}
 }
 
-#[derive(Debug)]
+/// Where to continue when the current lambda ends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+	/// Back to the caller of `!` or `?`, `cursor` is already past the call.
+	Return { fn_index: usize, cursor: usize },
+	/// Inside of `#`, `in_cond` tells whether the lambda that is running is the condition.
+	While { cond: usize, body: usize, in_cond: bool, fn_index: usize, cursor: usize },
+}
+
+/// Machine state of a `FalseVM`, without the program and I/O. See `FalseVM::snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+	stack: Vec<StackElement>,
+	variables: HashMap<char, StackElement>,
+	fn_index: usize,
+	cursor: usize,
+	frames: Vec<Frame>,
+}
+
 pub struct FalseVM {
 	pub stack: Vec<StackElement>,
 	pub variables: HashMap<char, StackElement>,
 	pub functions: Vec<Vec<Token>>,
+	/// Char offset of each token of `functions` in the code it was loaded from, see `Parser::spans`.
+	pub spans: Vec<Vec<usize>>,
 
 	pub fn_index: usize,
 	pub cursor: usize,
+	pub frames: Vec<Frame>,
 
-	pub verbose: bool,
+	pub input: Box<dyn Read>,
+	pub output: Box<dyn Write>,
+	pub dialect: Dialect,
+	/// Steps left before `step` fails with `Error::OutOfFuel`, unlimited if `None`.
+	pub fuel: Option<u64>,
+	/// Most values the data stack may hold, unlimited if `None`.
+	pub max_stack: Option<usize>,
 
 }
 
@@ -59,231 +88,296 @@ impl FalseVM {
 			variables: HashMap::new(),
 			fn_index: 0,
 			cursor: 0,
-			verbose: false,
+			frames: Vec::new(),
+			input: Box::new(std::io::stdin()),
+			output: Box::new(std::io::stdout()),
+			dialect: Dialect::Rfalse,
+			fuel: None,
+			max_stack: None,
 			functions: Vec::new(),
+			spans: Vec::new(),
 		}
 	}
 
-	pub fn load(&mut self, code: &str) {
-		let t = Tokenizer::new(code);
+	/// Adds `code` to the functions loaded before and makes it the next thing to run. Stack and
+	/// variables are kept, so lambdas stored by earlier code can still be called.
+	pub fn load(&mut self, code: &str) -> Result<(), Error> {
+		let t = Tokenizer::with_dialect(code, self.dialect);
 		let mut parser = super::parser::Parser::new(t);
-		self.fn_index = match parser.parse() {
+		let main = match parser.parse()? {
 			Token::LambdaPointer(l) => l,
-			_ => panic!("Expected lambda"),
+			_ => unreachable!("parser returns the main lambda"),
 		};
-		self.functions = parser.lambda_storage;
+		// the parser numbers lambdas from 0, move them past the ones already here
+		let base = self.functions.len();
+		for mut function in parser.lambda_storage {
+			for token in function.iter_mut() {
+				if let Token::LambdaPointer(n) = token {
+					*n += base;
+				}
+			}
+			self.functions.push(function);
+		}
+		self.spans.append(&mut parser.spans);
+		self.fn_index = base + main;
 		self.cursor = 0;
+		self.frames.clear();
+		Ok(())
+	}
+
+	/// Saves stack, variables and position, so that `restore` can go back to this point.
+	/// It only makes sense with the program that was loaded when it was taken.
+	pub fn snapshot(&self) -> Snapshot {
+		Snapshot {
+			stack: self.stack.clone(),
+			variables: self.variables.clone(),
+			fn_index: self.fn_index,
+			cursor: self.cursor,
+			frames: self.frames.clone(),
+		}
+	}
+
+	pub fn restore(&mut self, snapshot: &Snapshot) {
+		self.stack.clone_from(&snapshot.stack);
+		self.variables.clone_from(&snapshot.variables);
+		self.fn_index = snapshot.fn_index;
+		self.cursor = snapshot.cursor;
+		self.frames.clone_from(&snapshot.frames);
 	}
 
 	pub fn peek_instruction(&self) -> Option<&Token> {
 		self.functions.get(self.fn_index).and_then(|v| v.get(self.cursor))
 	}
 
-	pub fn gosub(&mut self, lambda_index: usize) {
-		// save
-		let tmph = self.cursor;
-		let tmp = self.fn_index.clone();
-		// replace & run
+	/// Enters a lambda, `frame` says where to go once it ends.
+	fn enter(&mut self, lambda_index: usize, frame: Frame) -> Result<(), Error> {
+		if self.frames.len() >= MAX_FRAMES {
+			return Err(Error::runtime("call stack overflow"));
+		}
+		self.frames.push(frame);
 		self.fn_index = lambda_index;
 		self.cursor = 0;
-		self.run();
-		// restore
-		self.fn_index = tmp;
-		self.cursor = tmph;
+		Ok(())
+	}
+
+	fn push(&mut self, x: StackElement) -> Result<(), Error> {
+		if self.max_stack.is_some_and(|max| self.stack.len() >= max) {
+			return Err(Error::runtime("stack overflow"));
+		}
+		self.stack.push(x);
+		Ok(())
+	}
+
+	fn pop(&mut self) -> Result<StackElement, Error> {
+		self.stack.pop().ok_or_else(|| Error::runtime("stack underflow"))
+	}
+
+	fn pop_number(&mut self) -> Result<i64, Error> {
+		match self.pop()? {
+			StackElement::Number(n) => Ok(n),
+			other => Err(Error::Runtime(format!("expected number, got {:?}", other))),
+		}
+	}
+
+	fn pop_lambda(&mut self) -> Result<usize, Error> {
+		match self.pop()? {
+			StackElement::Lambda(l) => Ok(l),
+			other => Err(Error::Runtime(format!("expected lambda, got {:?}", other))),
+		}
 	}
 
-	pub fn step(&mut self) -> StepResult {
-		assert!(!self.functions.is_empty(), "invalid VM state (did you call load()?)");
-		let curr = self.functions.get(self.fn_index).expect("wrong curr");
+	fn pop_variable(&mut self) -> Result<char, Error> {
+		match self.pop()? {
+			StackElement::Variable(v) => Ok(v),
+			other => Err(Error::Runtime(format!("expected variable, got {:?}", other))),
+		}
+	}
+
+	/// Pops `b` and `a` and pushes `f(a, b)`, for `a b op`.
+	fn binop(&mut self, f: impl Fn(i64, i64) -> i64) -> Result<(), Error> {
+		let b = self.pop_number()?;
+		let a = self.pop_number()?;
+		self.push(StackElement::Number(f(a, b)))?;
+		Ok(())
+	}
+
+	/// Called when the current lambda has no more tokens. Goes back to the caller
+	/// or runs the next part of a `#` loop. Returns `End` after the main function.
+	fn lambda_end(&mut self) -> Result<StepResult, Error> {
+		match self.frames.pop() {
+			None => return Ok(StepResult::End),
+			Some(Frame::Return { fn_index, cursor }) => {
+				self.fn_index = fn_index;
+				self.cursor = cursor;
+			}
+			Some(Frame::While { cond, body, in_cond: true, fn_index, cursor }) => {
+				if self.pop_number()? != 0 {
+					self.enter(body, Frame::While { cond, body, in_cond: false, fn_index, cursor })?;
+				} else {
+					self.fn_index = fn_index;
+					self.cursor = cursor;
+				}
+			}
+			Some(Frame::While { cond, body, in_cond: false, fn_index, cursor }) => {
+				self.enter(cond, Frame::While { cond, body, in_cond: true, fn_index, cursor })?;
+			}
+		}
+		Ok(StepResult::OK)
+	}
+
+	/// Executes one token, or returns from a lambda that has ended.
+	pub fn step(&mut self) -> Result<StepResult, Error> {
+		if self.functions.is_empty() {
+			return Err(Error::runtime("no program loaded"));
+		}
+		if let Some(fuel) = self.fuel.as_mut() {
+			if *fuel == 0 {
+				return Err(Error::OutOfFuel);
+			}
+			*fuel -= 1;
+		}
+		let curr = &self.functions[self.fn_index];
 		if self.cursor >= curr.len() {
-			return StepResult::End;
+			return self.lambda_end();
 		}
-		match &curr[self.cursor] {
-			Token::Number(n) => self.stack.push(StackElement::Number(*n)),
+		let ret = Frame::Return { fn_index: self.fn_index, cursor: self.cursor + 1 };
+		match curr[self.cursor].clone() {
+			Token::Number(n) => self.push(StackElement::Number(n))?,
 
 			Token::Dup => {
-				let a = self.stack.last().expect("Stack underflow").clone();
-				self.stack.push(a);
+				let a = self.stack.last().ok_or_else(|| Error::runtime("stack underflow"))?.clone();
+				self.push(a)?;
 			}
 			Token::Drop => {
-				self.stack.pop().expect("Stack underflow");
+				self.pop()?;
 			}
 			Token::Swap => {
-				let a = self.stack.pop().expect("Stack underflow");
-				let b = self.stack.pop().expect("Stack underflow");
-				self.stack.push(a);
-				self.stack.push(b);
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(a)?;
+				self.push(b)?;
 			}
 			Token::Rot => {
-				let a = self.stack.pop().expect("Stack underflow");
-				let b = self.stack.pop().expect("Stack underflow");
-				let c = self.stack.pop().expect("Stack underflow");
-				self.stack.push(b);
-				self.stack.push(a);
-				self.stack.push(c);
+				let a = self.pop()?;
+				let b = self.pop()?;
+				let c = self.pop()?;
+				self.push(b)?;
+				self.push(a)?;
+				self.push(c)?;
 			}
 			Token::Pick => {
-				let n = self.stack.pop().expect("Stack underflow");
-				if let StackElement::Number(idx) = n {
-					let idx = self.stack.len() - 1 - idx as usize;
-					let v = self.stack.get(idx).expect("Stack underflow").clone();
-					self.stack.push(v);
-				} else {
-					panic!("Invalid index");
-				}
+				let n = self.pop_number()?;
+				let v = usize::try_from(n).ok()
+					.and_then(|n| self.stack.len().checked_sub(n + 1))
+					.map(|idx| self.stack[idx].clone())
+					.ok_or_else(|| Error::Runtime(format!("pick index {} out of range", n)))?;
+				self.push(v)?;
 			}
 
-			Token::Plus => {
-				let a: i64 = self.stack.pop().expect("Stack underflow").expect_number();
-				let b: i64 = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(b + a));
-			}
-			Token::Minus => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(b - a));
-			}
-			Token::Mul => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(b * a));
-			}
+			Token::Plus => self.binop(i64::wrapping_add)?,
+			Token::Minus => self.binop(i64::wrapping_sub)?,
+			Token::Mul => self.binop(i64::wrapping_mul)?,
 			Token::Div => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(b / a));
+				let b = self.pop_number()?;
+				let a = self.pop_number()?;
+				if b == 0 {
+					return Err(Error::runtime("division by zero"));
+				}
+				self.push(StackElement::Number(a.wrapping_div(b)))?;
 			}
 			Token::Negate => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(-a));
-			}
-			Token::BitAnd => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(a & b));
-			}
-			Token::BitOr => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(a | b));
+				let a = self.pop_number()?;
+				self.push(StackElement::Number(a.wrapping_neg()))?;
 			}
+			Token::BitAnd => self.binop(|a, b| a & b)?,
+			Token::BitOr => self.binop(|a, b| a | b)?,
 			Token::BitNot => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(!a));
+				let a = self.pop_number()?;
+				self.push(StackElement::Number(!a))?;
 			}
 
-			Token::GreaterThan => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(if a < b { !0 } else { 0 }));
-			}
-			Token::Equal => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(if a == b { !0 } else { 0 }));
-			}
-			Token::LessThan => {
-				let a = self.stack.pop().expect("Stack underflow").expect_number();
-				let b = self.stack.pop().expect("Stack underflow").expect_number();
-				self.stack.push(StackElement::Number(if a > b { !0 } else { 0 }));
-			}
+			Token::GreaterThan => self.binop(|a, b| if a > b { !0 } else { 0 })?,
+			Token::Equal => self.binop(|a, b| if a == b { !0 } else { 0 })?,
+			Token::LessThan => self.binop(|a, b| if a < b { !0 } else { 0 })?,
 
 			Token::LambdaPointer(v) => {
-				self.stack.push(StackElement::Lambda(*v));
+				self.push(StackElement::Lambda(v))?;
 			}
 			Token::LambdaExecute => {
-				let l = self.stack.pop().expect("Stack underflow");
-				let l = l.expect_lambda();
-				self.gosub(l);
+				let l = self.pop_lambda()?;
+				self.enter(l, ret)?;
+				return Ok(StepResult::OK);
 			}
 			Token::LambdaIf => {
-				let l = self.stack.pop().expect("Stack underflow");
-				let l = l.expect_lambda();
-				let cond = self.stack.pop().expect("Stack underflow").expect_number();
+				let l = self.pop_lambda()?;
+				let cond = self.pop_number()?;
 				if cond != 0 {
-					self.gosub(l);
+					self.enter(l, ret)?;
+					return Ok(StepResult::OK);
 				}
 			}
 			Token::LambdaWhile => {
-				let body = self.stack.pop().expect("Stack underflow");
-				let body = body.expect_lambda();
-				let cond = self.stack.pop().expect("Stack underflow");
-				let cond = cond.expect_lambda();
-
-				loop {
-					self.gosub(cond);
-					let cond = self.stack.pop().expect("Stack underflow").expect_number();
-					if cond == 0 {
-						break;
-					}
-					self.gosub(body);
-				}
-			}
-			Token::LambdaStart => {
-				panic!("LambdaStart must not be output by parser")
+				let body = self.pop_lambda()?;
+				let cond = self.pop_lambda()?;
+				self.enter(cond, Frame::While { cond, body, in_cond: true, fn_index: self.fn_index, cursor: self.cursor + 1 })?;
+				return Ok(StepResult::OK);
 			}
-			Token::LambdaEnd => {
-				panic!("LambdaEnd must not be output by parser")
+			Token::LambdaStart | Token::LambdaEnd => {
+				unreachable!("lambda brackets are not output by the parser")
 			}
 
 			Token::Variable(x) => {
-				self.stack.push(StackElement::Variable(*x))
+				self.push(StackElement::Variable(x))?
 			}
 			Token::VarWrite => {
-				let var = self.stack.pop().expect("Stack underflow").expect_variable();
-				let val = self.stack.pop().expect("Stack underflow");
+				let var = self.pop_variable()?;
+				let val = self.pop()?;
 				self.variables.insert(var, val);
 			}
 			Token::VarRead => {
-				let var = self.stack.pop().expect("Stack underflow").expect_variable();
-				let val = self.variables.get(&var).expect("Variable not initialized").clone();
-				self.stack.push(val);
+				let var = self.pop_variable()?;
+				let val = self.variables.get(&var)
+					.ok_or_else(|| Error::Runtime(format!("variable {} not initialized", var)))?
+					.clone();
+				self.push(val)?;
 			}
 
 			Token::ReadChar => {
-				// read char from stdin
-				let mut buf = [0u8; 1];
-				io::stdin().read_exact(&mut buf).unwrap();
-				let c = buf[0] as i64;
-				self.stack.push(StackElement::Number(c));
+				let c = read_char(&mut self.input) as i64;
+				self.push(StackElement::Number(c))?;
 			}
 			Token::WriteChar => {
-				let c = self.stack.pop().expect("Stack underflow").expect_number();
-				let c = std::char::from_u32(c as u32).expect("Invalid char");
-				print!("{}", c);
+				let c = self.pop_number()?;
+				let c = u32::try_from(c).ok().and_then(std::char::from_u32)
+					.ok_or_else(|| Error::Runtime(format!("invalid character {}", c)))?;
+				write!(self.output, "{}", c)?;
 			}
 			Token::PrintString(s) => {
-				print!("{}", s);
+				write!(self.output, "{}", s)?;
 			}
 			Token::WriteInt => {
-				let n = self.stack.pop().expect("Stack underflow").expect_number();
-				print!("{}", n);
+				let n = self.pop_number()?;
+				write!(self.output, "{}", n)?;
 			}
 			Token::FlushIO => {
-				std::io::stdout().flush().unwrap();
+				self.output.flush()?;
 			}
 		}
 		self.cursor += 1;
-		StepResult::OK
+		Ok(StepResult::OK)
 	}
 
 	#[allow(dead_code)]
-	pub fn run(&mut self) {
+	pub fn run(&mut self) -> Result<(), Error> {
 		loop {
-			if self.verbose {
-				println!("\n==> Doing instr: {:?}", self.peek_instruction());
-			}
-			let r = self.step();
+			let r = self.step()?;
 			// wait for keystroke
 			// std::io::stdin().read_line(&mut String::new()).unwrap();
 			if r == StepResult::End {
 				break;
 			}
-			if self.verbose {
-				println!("\n==> Stack: {:?}", self.stack);
-				println!("    Vars: {:?}", self.variables);
-			}
 		}
+		Ok(())
 	}
 }
 
@@ -298,20 +392,22 @@ impl Default for FalseVM {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::falselang::interpreter::Capture;
+	use std::io::BufWriter;
 
 	#[test]
 	fn test_empty() {
 		let mut vm = FalseVM::new();
-		vm.load("");
-		vm.run();
+		vm.load("").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack.len(), 0);
 	}
 
 	#[test]
 	fn test_put_i() {
 		let mut vm = FalseVM::new();
-		vm.load("1 2 3 4 5");
-		vm.run();
+		vm.load("1 2 3 4 5").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(1),
 			StackElement::Number(2),
@@ -324,16 +420,16 @@ mod tests {
 	#[test]
 	fn test_2plus2() {
 		let mut vm = FalseVM::new();
-		vm.load("2 2+");
-		vm.run();
+		vm.load("2 2+").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![ StackElement::Number(4) ]);
 	}
 
 	#[test]
 	fn test_charcode() {
 		let mut vm = FalseVM::new();
-		vm.load("'a    'b         'c'd");
-		vm.run();
+		vm.load("'a    'b         'c'd").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(97),
 			StackElement::Number(98),
@@ -345,8 +441,8 @@ mod tests {
 	#[test]
 	fn test_dup() {
 		let mut vm = FalseVM::new();
-		vm.load("2 4$");
-		vm.run();
+		vm.load("2 4$").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(2),
 			StackElement::Number(4),
@@ -357,8 +453,8 @@ mod tests {
 	#[test]
 	fn test_drop() {
 		let mut vm = FalseVM::new();
-		vm.load("1 2 3%");
-		vm.run();
+		vm.load("1 2 3%").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(1),
 			StackElement::Number(2),
@@ -368,8 +464,8 @@ mod tests {
 	#[test]
 	fn test_swap() {
 		let mut vm = FalseVM::new();
-		vm.load("1 2 \\");
-		vm.run();
+		vm.load("1 2 \\").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(2),
 			StackElement::Number(1),
@@ -379,8 +475,8 @@ mod tests {
 	#[test]
 	fn test_rot() {
 		let mut vm = FalseVM::new();
-		vm.load("0 1 2 3 @");
-		vm.run();
+		vm.load("0 1 2 3 @").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(0),
 			StackElement::Number(2),
@@ -392,8 +488,8 @@ mod tests {
 	#[test]
 	fn test_pick_1() {
 		let mut vm = FalseVM::new();
-		vm.load("7 8 9 2 ø");
-		vm.run();
+		vm.load("7 8 9 2 ø").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(7),
 			StackElement::Number(8),
@@ -405,8 +501,8 @@ mod tests {
 	#[test]
 	fn test_pick_2() {
 		let mut vm = FalseVM::new();
-		vm.load("7 8 9 2P");
-		vm.run();
+		vm.load("7 8 9 2P").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(7),
 			StackElement::Number(8),
@@ -418,8 +514,8 @@ mod tests {
 	#[test]
 	fn test_negate() {
 		let mut vm = FalseVM::new();
-		vm.load("1920_");
-		vm.run();
+		vm.load("1920_").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(-1920),
 		]);
@@ -428,8 +524,8 @@ mod tests {
 	#[test]
 	fn test_bit_and() {
 		let mut vm = FalseVM::new();
-		vm.load("3 1 &");
-		vm.run();
+		vm.load("3 1 &").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(1),
 		]);
@@ -438,8 +534,8 @@ mod tests {
 	#[test]
 	fn test_bit_or() {
 		let mut vm = FalseVM::new();
-		vm.load("3   1|");
-		vm.run();
+		vm.load("3   1|").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(3),
 		]);
@@ -448,18 +544,86 @@ mod tests {
 	#[test]
 	fn test_bit_not() {
 		let mut vm = FalseVM::new();
-		vm.load("5~");
-		vm.run();
+		vm.load("5~").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(-6),
 		]);
 	}
 
+	#[test]
+	fn test_io() {
+		let out = Capture::default();
+		let mut vm = FalseVM::new();
+		vm.input = Box::new(std::io::Cursor::new(b"ab".to_vec()));
+		vm.output = Box::new(BufWriter::new(out.clone()));
+		vm.load("^^,, \"!\"ß ^").unwrap();
+		for _ in 0..5 {
+			vm.step().unwrap();
+		}
+		assert_eq!(&*out.bytes(), b"");
+		// ß flushes
+		vm.step().unwrap();
+		assert_eq!(&*out.bytes(), b"ba!");
+		vm.run().unwrap();
+		// -1 at the end of input
+		assert_eq!(vm.stack, vec![StackElement::Number(-1)]);
+	}
+
+	#[test]
+	fn test_while() {
+		let mut vm = FalseVM::new();
+		vm.load("0 3[$][1- \\ 2[$][1- \\ 1+ \\]# % \\]#%").unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack, vec![
+			StackElement::Number(6),
+		]);
+		assert!(vm.frames.is_empty());
+	}
+
+	#[test]
+	fn test_step_into() {
+		// `!` only enters the lambda, the body runs in the following steps
+		let mut vm = FalseVM::new();
+		vm.load("[1 2+]! 4").unwrap();
+		vm.step().unwrap();
+		assert_eq!(vm.step(), Ok(StepResult::OK));
+		assert_eq!(vm.frames, vec![Frame::Return { fn_index: 1, cursor: 2 }]);
+		assert_eq!(vm.stack, vec![]);
+		vm.step().unwrap();
+		vm.step().unwrap();
+		assert_eq!(vm.stack, vec![StackElement::Number(1), StackElement::Number(2)]);
+		vm.step().unwrap();
+		vm.step().unwrap(); // return
+		assert_eq!((vm.fn_index, vm.cursor), (1, 2));
+		assert!(vm.frames.is_empty());
+		vm.step().unwrap();
+		assert_eq!(vm.step(), Ok(StepResult::End));
+		assert_eq!(vm.stack, vec![StackElement::Number(3), StackElement::Number(4)]);
+	}
+
+	#[test]
+	fn test_deep_recursion() {
+		// lambdas don't recurse on the Rust stack
+		let mut vm = FalseVM::new();
+		vm.load("[$0>[1-f;!]?]f: 100000 f;!").unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack, vec![StackElement::Number(0)]);
+	}
+
+	#[test]
+	fn test_call_stack_overflow() {
+		let mut vm = FalseVM::new();
+		vm.load("[f;! 1]f: f;!").unwrap();
+		assert_eq!(vm.run(), Err(Error::runtime("call stack overflow")));
+		assert_eq!(vm.frames.len(), MAX_FRAMES);
+	}
+
 	#[test]
 	fn test_fn_factorial() {
 		let mut vm = FalseVM::new();
-		vm.load("[$1=$[\\%1\\]?~[$1-f;!*]?]f:    6 f;!");
-		vm.run();
+		vm.load("[$1=$[\\%1\\]?~[$1-f;!*]?]f:    6 f;!").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(720),
 		]);
@@ -468,8 +632,8 @@ mod tests {
 	#[test]
 	fn test_fn_fibonacci() {
 		let mut vm = FalseVM::new();
-		vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;!");
-		vm.run();
+		vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;!").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			StackElement::Number(144),
 		]);
@@ -478,12 +642,104 @@ mod tests {
 	#[test]
 	fn test_fn_primes() {
 		let mut vm = FalseVM::new();
-		vm.load("50 9[1-$][\\$@$@$@$@\\/*=[1-$$[%\\1-$@]?0=[\\' ,\\]?]?]#");
-		vm.run();
+		vm.load("50 9[1-$][\\$@$@$@$@\\/*=[1-$$[%\\1-$@]?0=[\\' ,\\]?]?]#").unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack, vec![
 			// 47 43 41 37 31 29 23 19 17 13 11 7 5 3 2
 			StackElement::Number(1),
 			StackElement::Number(0),
 		]);
 	}
+
+	#[test]
+	fn test_errors() {
+		for (code, message) in [
+			("1 2+ +", "stack underflow"),
+			("1 [2]+", "expected number, got Lambda(0)"),
+			("1 0/", "division by zero"),
+			("1 2 5ø", "pick index 5 out of range"),
+			("1_ø", "pick index -1 out of range"),
+			("a;", "variable a not initialized"),
+			("1 1:", "expected variable, got Number(1)"),
+			("55296,", "invalid character 55296"),
+		] {
+			let mut vm = FalseVM::new();
+			vm.load(code).unwrap();
+			assert_eq!(vm.run(), Err(Error::runtime(message)), "{:?}", code);
+		}
+		// like in vm2, what a failing token popped stays popped
+		let mut vm = FalseVM::new();
+		vm.load("7 5 0/").unwrap();
+		assert_eq!(vm.run(), Err(Error::runtime("division by zero")));
+		assert_eq!(vm.stack, vec![StackElement::Number(7)]);
+	}
+
+	#[test]
+	fn test_fuel() {
+		let mut vm = FalseVM::new();
+		vm.fuel = Some(1000);
+		vm.load("[1][]#").unwrap();
+		assert_eq!(vm.run(), Err(Error::OutOfFuel));
+		assert_eq!(vm.fuel, Some(0));
+
+		vm.fuel = Some(3);
+		vm.load("1 2").unwrap();
+		assert_eq!(vm.run(), Ok(()));
+		assert_eq!(vm.fuel, Some(0));
+	}
+
+	#[test]
+	fn test_max_stack() {
+		let mut vm = FalseVM::new();
+		vm.max_stack = Some(3);
+		vm.load("1 2 3 %%% 1 2 3").unwrap();
+		assert_eq!(vm.run(), Ok(()));
+		vm.load("1 2 3 4").unwrap();
+		vm.stack.clear();
+		assert_eq!(vm.run(), Err(Error::runtime("stack overflow")));
+		assert_eq!(vm.stack.len(), 3);
+	}
+
+	#[test]
+	fn test_wrapping() {
+		let mut vm = FalseVM::new();
+		vm.load("9223372036854775807 1+ $ 1_/ $_").unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack, vec![StackElement::Number(i64::MIN); 3]);
+	}
+
+	#[test]
+	fn test_load_again() {
+		let mut vm = FalseVM::new();
+		vm.load("[[2*]!]d: 1 2").unwrap();
+		vm.run().unwrap();
+		vm.load("[1+]i: d;! i;!").unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack, vec![StackElement::Number(1), StackElement::Number(5)]);
+		assert_eq!(vm.functions.len(), 5);
+		// offsets are into the code each function came from
+		assert_eq!(vm.spans[3], vec![1, 2, 3]);
+		assert!(vm.load("[").is_err());
+		assert_eq!(vm.functions.len(), 5);
+		assert_eq!(vm.spans.len(), 5);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let mut vm = FalseVM::new();
+		vm.load("0i: [i;3>~][i;$1+i:]# 5").unwrap();
+		for _ in 0..12 {
+			vm.step().unwrap();
+		}
+		let snapshot = vm.snapshot();
+		vm.run().unwrap();
+		let stack = vm.stack.clone();
+		assert_eq!(stack.len(), 5);
+
+		vm.restore(&snapshot);
+		assert_eq!(vm.snapshot(), snapshot);
+		vm.run().unwrap();
+		assert_eq!(vm.stack, stack);
+		assert_eq!(vm.variables[&'i'], StackElement::Number(4));
+	}
 }
@@ -1,7 +1,10 @@
-use core::panic;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
 
+use crate::falselang::error::Error;
 use crate::falselang::tokenizer::*;
+pub use super::interpreter::StepResult;
+use super::interpreter::read_char;
 use num_enum::{TryFromPrimitive, IntoPrimitive};
 
 
@@ -10,11 +13,11 @@ use num_enum::{TryFromPrimitive, IntoPrimitive};
 // * call stack starts at 26 (0x1A)
 // * instructions start at call stack size + 26 (0x1A)
 // * data stack starts at the end (and grows backwards)
-const MEM_SIZE: usize = 131072;
-const FIRST_VAR: usize = 0;
-const CALL_STACK_START: usize = FIRST_VAR + 26;
-const CALL_STACK_SIZE: usize = 640;
-const FIRST_INSTR: usize = CALL_STACK_START + CALL_STACK_SIZE;
+pub const MEM_SIZE: usize = 131072;
+pub const FIRST_VAR: usize = 0;
+pub const CALL_STACK_START: usize = FIRST_VAR + 26;
+pub const CALL_STACK_SIZE: usize = 640;
+pub const FIRST_INSTR: usize = CALL_STACK_START + CALL_STACK_SIZE;
 
 pub struct FalseVM {
 	pub memory: Box<[i32; MEM_SIZE]>,
@@ -22,12 +25,31 @@ pub struct FalseVM {
 	pub stack_pointer: usize,
 	pub callstack_pointer: usize,
 	pub fn_pointer: HashMap<usize, usize>, // stores function pointers, key = fn_index, value = memory pointer
+	pub fn_refs: HashSet<usize>, // memory cells holding function addresses (operands of Push, CallImm, CallIfImm)
+	pub code_end: usize, // first memory cell after the compiled code
+	/// Char offsets of the tokens each instruction was compiled from, by address. The offset of
+	/// a lambda's end, see `Parser::spans`, marks the first instruction of its body.
+	pub spans: HashMap<usize, Vec<usize>>,
+	pub input: Box<dyn Read>,
+	pub output: Box<dyn Write>,
+
+	pub dialect: Dialect,
+	pub inline_size: usize, // lambdas up to this many tokens are inlined at opt level 3
+	pub fuel: Option<u64>, // instructions left before running fails with OutOfFuel, unlimited if None
+	pub max_stack: Option<usize>, // most values on the data stack, it can grow up to the code if None
+}
 
-	pub verbose: bool,
+/// Machine state of a `FalseVM`, see `FalseVM::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+	head: Vec<i32>, // variables and call stack
+	stack: Vec<i32>,
+	cursor: usize,
+	callstack_pointer: usize,
 }
 
 #[repr(i32)]
-#[derive(Debug, TryFromPrimitive, IntoPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
 pub enum Instr {
 	Noop = 0,
 	Push = 1001, // 1 args, +1 stack
@@ -46,16 +68,18 @@ pub enum Instr {
 	BitNot = 1014, // 0 args, 0 stack
 	Gt = 1015, // 0 args, -1 stack
 	Eq = 1016, // 0 args, -1 stack
-	// ReadChar
+	ReadChar = 1017, // 0 args, +1 stack
 	WriteChar = 1018, // 0 args, -1 stack
 	WriteInt = 1019, // 0 args, -1 stack
 	WriteString = 1020, // 1+N args, 0 stack
+	LessThan = 1021, // 0 args, -1 stack
+	FlushIO = 1022, // 0 args, 0 stack
 
 	Call = 1025, // 0 args, -1 stack
 	CallIf = 1026, // 0 args, -2 stack
 	Return = 1027, // 0 args, 0 stack
 	Goto = 1028, // 1 args, 0 stack
-	GotoIf = 1029, // 0 args, -2 stack
+	GotoIf = 1029, // 1 args, -1 stack
 	Halt = 1030,
 
 	VarRead = 1032, // 0 args, 0 stack
@@ -63,12 +87,90 @@ pub enum Instr {
 	MoveToCallStack = 1034, // 0 args, -1 stack
 	PickFromCallStack = 1035, // 1 arg, +1 stack
 	DropFromCallStack = 1036, // 0 args, 0 stack
+	TailCall = 1037, // 0 args, -1 stack (Call; Return)
+	TailCallIf = 1038, // 0 args, -2 stack (CallIf; Return), followed by Return for when the condition is false
+
+	// Superinstructions, emitted by the optimizer
+	LoadVar = 1040, // 1 args, +1 stack (Push n; VarRead)
+	StoreVar = 1041, // 1 args, -1 stack (Push n; VarWrite)
+	CallImm = 1042, // 1 args, 0 stack (Push addr; Call)
+	CallIfImm = 1043, // 1 args, -1 stack (Push addr; CallIf)
+	AddImm = 1044, // 1 args, 0 stack (Push n; Plus) and (Push -n; Minus)
+	GtImm = 1045, // 1 args, 0 stack (Push n; Gt)
+	EqImm = 1046, // 1 args, 0 stack (Push n; Eq)
+	DupGtImm = 1047, // 1 args, +1 stack (Dup; Push n; Gt)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum StepResult {
-	OK,
-	End,
+pub const MNEMONICS: [(Instr, &str); 44] = [
+	(Instr::Noop, "noop"),
+	(Instr::Push, "push"),
+	(Instr::Dup, "dup"),
+	(Instr::Drop, "drop"),
+	(Instr::Swap, "swap"),
+	(Instr::Rot, "rot"),
+	(Instr::Pick, "pick"),
+	(Instr::Plus, "plus"),
+	(Instr::Minus, "minus"),
+	(Instr::Mul, "mul"),
+	(Instr::Div, "div"),
+	(Instr::Negate, "negate"),
+	(Instr::BitAnd, "bitand"),
+	(Instr::BitOr, "bitor"),
+	(Instr::BitNot, "bitnot"),
+	(Instr::Gt, "gt"),
+	(Instr::Eq, "eq"),
+	(Instr::ReadChar, "readchar"),
+	(Instr::WriteChar, "writechar"),
+	(Instr::WriteInt, "writeint"),
+	(Instr::WriteString, "writestring"),
+	(Instr::LessThan, "lt"),
+	(Instr::FlushIO, "flush"),
+
+	(Instr::Call, "call"),
+	(Instr::CallIf, "callif"),
+	(Instr::Return, "return"),
+	(Instr::Goto, "goto"),
+	(Instr::GotoIf, "gotoif"),
+	(Instr::Halt, "halt"),
+
+	(Instr::VarRead, "varread"),
+	(Instr::VarWrite, "varwrite"),
+	(Instr::MoveToCallStack, "movetocallstack"),
+	(Instr::PickFromCallStack, "pickfromcallstack"),
+	(Instr::DropFromCallStack, "dropfromcallstack"),
+	(Instr::TailCall, "tailcall"),
+	(Instr::TailCallIf, "tailcallif"),
+
+	(Instr::LoadVar, "loadvar"),
+	(Instr::StoreVar, "storevar"),
+	(Instr::CallImm, "callimm"),
+	(Instr::CallIfImm, "callifimm"),
+	(Instr::AddImm, "addimm"),
+	(Instr::GtImm, "gtimm"),
+	(Instr::EqImm, "eqimm"),
+	(Instr::DupGtImm, "dupgtimm"),
+];
+
+impl Instr {
+	/// Number of operand cells following the opcode. `WriteString` is followed
+	/// by its length and then that many more cells, only the length is counted here.
+	pub fn arg_count(&self) -> usize {
+		match self {
+			Instr::Push | Instr::Goto | Instr::GotoIf | Instr::PickFromCallStack | Instr::WriteString => 1,
+			Instr::LoadVar | Instr::StoreVar | Instr::CallImm | Instr::CallIfImm => 1,
+			Instr::AddImm | Instr::GtImm | Instr::EqImm | Instr::DupGtImm => 1,
+			_ => 0,
+		}
+	}
+
+	/// Whether the operand may hold a function address, which then goes to `fn_refs`.
+	pub fn takes_fn_address(&self) -> bool {
+		matches!(self, Instr::Push | Instr::CallImm | Instr::CallIfImm)
+	}
+
+	pub fn mnemonic(&self) -> &'static str {
+		MNEMONICS.iter().find(|(i, _)| i == self).map(|(_, m)| *m).expect("mnemonic missing")
+	}
 }
 
 impl FalseVM {
@@ -79,22 +181,68 @@ impl FalseVM {
 			stack_pointer: MEM_SIZE,
 			callstack_pointer: CALL_STACK_START - 1,
 			fn_pointer: HashMap::new(),
-
-			verbose: false,
+			fn_refs: HashSet::new(),
+			code_end: FIRST_INSTR,
+			spans: HashMap::new(),
+			input: Box::new(std::io::stdin()),
+			output: Box::new(std::io::stdout()),
+
+			dialect: Dialect::Rfalse,
+			inline_size: 16,
+			fuel: None,
+			max_stack: None,
 		}
 	}
 
-	/// Compiles a function and puts it into memory. Returns start addr.
-	pub fn compile_fn(&mut self, code: &Vec<Token>, epilogue: Instr) -> usize {
+	/// Compiles function `n` and puts it into memory. Returns start addr.
+	/// `functions` is the parser's lambda storage, used to inline literal lambdas, `spans` go with it.
+	/// `base` is the `fn_pointer` index of `functions[0]`.
+	pub fn compile_fn(&mut self, n: usize, base: usize, functions: &[Vec<Token>], spans: &[Vec<usize>], epilogue: Instr) -> Result<usize, Error> {
 		let ret = self.cursor;
-		if self.verbose {
-			println!("Compiling function: {:?}", code);
-			println!("Function address: {}", ret);
-		}
-		for token in code {
-			if self.verbose {
-				println!(" Token: {:?}", token);
+		self.compile_tokens(n, base, functions, spans, epilogue == Instr::Return)?;
+		self.instr_push(epilogue);
+		self.mark(ret, spans[n][functions[n].len()]);
+		Ok(ret)
+	}
+
+	/// Compiles the tokens of function `n`, without epilogue. `tail` means the code is followed by
+	/// Return, so a call at its very end becomes a jump and doesn't grow the call stack.
+	fn compile_tokens(&mut self, n: usize, base: usize, functions: &[Vec<Token>], spans: &[Vec<usize>], tail: bool) -> Result<(), Error> {
+		let code = &functions[n];
+		let mut i = 0;
+		while i < code.len() {
+			let token = &code[i];
+
+			// `[cond][body]#` and `[body]?` with literal lambdas become plain jumps
+			if let [Token::LambdaPointer(cond), Token::LambdaPointer(body), Token::LambdaWhile, ..] = code[i..] {
+				for pos in &spans[n][i..i + 3] {
+					self.mark(self.cursor, *pos);
+				}
+				let patch = self.cursor + 1;
+				self.instr_push1(Instr::Goto, 0); // to condition
+				let body_addr = self.cursor as i32;
+				self.compile_inline(body, base, functions, spans, false)?;
+				self.patch(patch, self.cursor as i32);
+				self.compile_inline(cond, base, functions, spans, false)?;
+				self.mark(self.cursor, spans[n][i + 2]);
+				self.instr_push1(Instr::GotoIf, body_addr);
+				i += 3;
+				continue;
+			}
+			if let [Token::LambdaPointer(body), Token::LambdaIf, ..] = code[i..] {
+				for pos in &spans[n][i..i + 2] {
+					self.mark(self.cursor, *pos);
+				}
+				self.instr_push1(Instr::GotoIf, self.cursor as i32 + 4); // to body, right after the next goto
+				let patch = self.cursor + 1;
+				self.instr_push1(Instr::Goto, 0); // past the body
+				self.compile_inline(body, base, functions, spans, tail && i + 2 == code.len())?;
+				self.patch(patch, self.cursor as i32);
+				i += 2;
+				continue;
 			}
+
+			self.mark(self.cursor, spans[n][i]);
 			match token {
 				Token::Number(x) => self.instr_push1(Instr::Push, *x as i32),
 
@@ -115,13 +263,22 @@ impl FalseVM {
 
 				Token::GreaterThan => self.instr_push(Instr::Gt),
 				Token::Equal => self.instr_push(Instr::Eq),
+				Token::LessThan => self.instr_push(Instr::LessThan),
 
 				Token::Variable(x) => self.instr_push1(Instr::Push, (*x as i32) - ('a' as i32)),
 				Token::VarRead => self.instr_push(Instr::VarRead),
 				Token::VarWrite => self.instr_push(Instr::VarWrite),
 
+				Token::LambdaExecute if tail && i + 1 == code.len() => self.instr_push(Instr::TailCall),
 				Token::LambdaExecute => self.instr_push(Instr::Call),
-				Token::LambdaPointer(n) => self.instr_push1(Instr::Push, self.fn_pointer.get(&n).unwrap().clone() as i32),
+				Token::LambdaPointer(n) => {
+					// lambdas that are not compiled yet are stored as -1 - fn_index, `load` resolves them
+					let n = base + n;
+					let addr = self.fn_pointer.get(&n).map_or(-1 - n as i32, |addr| *addr as i32);
+					self.instr_push1(Instr::Push, addr);
+					self.fn_refs.insert(self.cursor - 1);
+				}
+				Token::LambdaIf if tail && i + 1 == code.len() => self.instr_push(Instr::TailCallIf),
 				Token::LambdaIf => self.instr_push(Instr::CallIf),
 				Token::LambdaWhile => {
 					self.instr_push(Instr::MoveToCallStack); // body_fn
@@ -132,6 +289,8 @@ impl FalseVM {
 					self.instr_push(Instr::Dup);
 					self.instr_push(Instr::MoveToCallStack); // save cond_result to call stack (._.)
 					self.instr_push1(Instr::PickFromCallStack, 2); // duplicate body addr to data stack
+					// running the body is the branch of `#`
+					self.mark(self.cursor, spans[n][i]);
 					self.instr_push(Instr::CallIf);
 					self.instr_push1(Instr::PickFromCallStack, 0); // duplicate cond_result to data stack
 					self.instr_push(Instr::DropFromCallStack);
@@ -146,47 +305,104 @@ impl FalseVM {
 						self.instr_push_raw(c as i32);
 					}
 				}
+				Token::ReadChar => self.instr_push(Instr::ReadChar),
 				Token::WriteInt => self.instr_push(Instr::WriteInt),
 				Token::WriteChar => self.instr_push(Instr::WriteChar),
+				Token::FlushIO => self.instr_push(Instr::FlushIO),
 
-				unknown => panic!("compile_fn: Not implemented: {:?}", unknown),
+				unknown => return Err(Error::Runtime(format!("cannot compile {:?}", unknown))),
 			}
+			i += 1;
 		}
-		self.instr_push(epilogue);
-		ret
+		Ok(())
+	}
+
+	/// Compiles the body of a literal lambda in place, see `compile_tokens`.
+	fn compile_inline(&mut self, n: usize, base: usize, functions: &[Vec<Token>], spans: &[Vec<usize>], tail: bool) -> Result<(), Error> {
+		let start = self.cursor;
+		self.compile_tokens(n, base, functions, spans, tail)?;
+		// an empty body has no instruction to mark
+		if self.cursor > start {
+			self.mark(start, spans[n][functions[n].len()]);
+		}
+		Ok(())
 	}
 
-	pub fn load(&mut self, code: &str) {
-		let t = Tokenizer::new(code);
+	/// Records that the instruction at `addr` comes from the token at char offset `pos`, see `spans`.
+	fn mark(&mut self, addr: usize, pos: usize) {
+		self.spans.entry(addr).or_default().push(pos);
+	}
+
+	/// Compiles `code` after the code loaded before and points the cursor at it. Stack and
+	/// variables are kept, so programs can be loaded one after another. `opt_level` 0 compiles
+	/// as is, 1 adds peephole optimizations, 2 also superinstructions and 3 also inlining.
+	/// If it fails, the code loaded before stays as it was.
+	pub fn load(&mut self, code: &str, opt_level: u8) -> Result<(), Error> {
+		let (start, cursor) = (self.code_end, self.cursor);
+		// functions are numbered across loads, like the tree VM does
+		let base = self.fn_pointer.len();
+		let result = self.compile(code, base, opt_level);
+		if result.is_err() {
+			self.memory[start..self.code_end.min(self.stack_pointer)].fill(0);
+			self.code_end = start;
+			self.cursor = cursor;
+			self.fn_refs.retain(|cell| *cell < start);
+			self.fn_pointer.retain(|n, _| *n < base);
+			self.spans.retain(|addr, _| *addr < start);
+		}
+		result
+	}
+
+	/// Compiles `code` to `code_end` and onwards, its functions become `base` and up in `fn_pointer`.
+	fn compile(&mut self, code: &str, base: usize, opt_level: u8) -> Result<(), Error> {
+		let start = self.code_end;
+		self.cursor = start;
+		let t = Tokenizer::with_dialect(code, self.dialect);
 		let mut parser = super::parser::Parser::new(t);
 
-		let fn_index = match parser.parse() {
-			Token::LambdaPointer(l) => l,
-			_ => panic!("Expected lambda"),
-		};
-		let functions = parser.lambda_storage;
+		// main function is always the last one
+		parser.parse()?;
+		let mut functions = parser.lambda_storage;
+		let mut spans = parser.spans;
+		if opt_level >= 3 {
+			super::inliner::inline_lambdas(&mut functions, &mut spans, self.inline_size);
+		}
 
-		let mut n = 0;
 		let mut entrypoint: usize = 0;
-		for function in functions.iter() {
+		for n in 0..functions.len() {
 			let is_main = n == functions.len() - 1;
-			let addr = self.compile_fn(function, if is_main { Instr::Halt } else { Instr::Return });
-			if self.verbose {
-				println!("Saving function #{} as address {}", n, addr);
-			}
-			self.fn_pointer.insert(n, addr);
-			n += 1;
+			let addr = self.compile_fn(n, base, &functions, &spans, if is_main { Instr::Halt } else { Instr::Return })?;
+			self.fn_pointer.insert(base + n, addr);
 			entrypoint = addr;
 		}
+		// leave some room for the data stack
+		if self.code_end + CALL_STACK_SIZE > self.stack_pointer {
+			return Err(Error::runtime("program does not fit into memory"));
+		}
+		for cell in self.fn_refs.iter() {
+			if self.memory[*cell] < 0 {
+				self.memory[*cell] = self.fn_pointer[&((-1 - self.memory[*cell]) as usize)] as i32;
+			}
+		}
 
 		self.goto(entrypoint);
+		self.callstack_pointer = CALL_STACK_START - 1;
+		if opt_level > 0 {
+			super::optimizer::optimize(self, start, opt_level);
+		}
+		Ok(())
 	}
 
 	/// Returns the next memory cell under cursor, shifting cursor forward.
-	pub fn instr_consume(&mut self) -> i32 {
+	#[inline]
+	pub fn instr_consume(&mut self) -> Result<i32, Error> {
+		if !(FIRST_INSTR..self.code_end).contains(&self.cursor) {
+			std::hint::cold_path();
+			return Err(invalid_jump(self.cursor as i32));
+		}
 		let ret = self.memory[self.cursor];
 		self.cursor += 1;
-		ret
+		Ok(ret)
 	}
 
 	pub fn instr_push(&mut self, x: Instr) {
@@ -204,317 +420,469 @@ impl FalseVM {
 		self.instr_push_raw(arg2);
 	}
 
+	/// Code that doesn't fit below the data stack is dropped, `load` checks `code_end` afterwards.
 	pub fn instr_push_raw(&mut self, x: i32) {
-		self.memory[self.cursor] = x;
+		if self.cursor < self.stack_pointer {
+			self.memory[self.cursor] = x;
+		}
 		self.cursor += 1;
+		self.code_end = self.code_end.max(self.cursor);
 	}
 
 	pub fn goto(&mut self, addr: usize) {
 		self.cursor = addr
 	}
 
-	/// Pushes a new element onto the stack, shifting stack top pointer to the left.
-	pub fn push(&mut self, x: i32) {
-		self.stack_pointer -= 1;
-		self.memory[self.stack_pointer] = x;
+	/// Jumps to a computed address, which has to be inside of the code.
+	fn jump(&mut self, addr: i32) -> Result<(), Error> {
+		if !(FIRST_INSTR as i32..self.code_end as i32).contains(&addr) {
+			std::hint::cold_path();
+			return Err(invalid_jump(addr));
+		}
+		self.goto(addr as usize);
+		Ok(())
 	}
 
-	pub fn peek(&self) -> i32 {
-		self.memory[self.stack_pointer]
+	/// Jumps to `addr` and pushes the address after the call, a bad target fails before anything is pushed.
+	fn call(&mut self, addr: i32) -> Result<(), Error> {
+		let ret = self.cursor as i32;
+		self.jump(addr)?;
+		self.callstack_push(ret)
 	}
 
-	pub fn pop(&mut self) -> i32 {
-		let ret = self.memory[self.stack_pointer];
-		self.memory[self.stack_pointer] = 0; // optional
-		self.stack_pointer += 1;
-		ret
+	/// Memory address of variable number `n`.
+	pub fn var_addr(n: i32) -> Result<usize, Error> {
+		match n {
+			0..=25 => Ok(FIRST_VAR + n as usize),
+			_ => {
+				std::hint::cold_path();
+				Err(invalid_variable(n))
+			}
+		}
 	}
 
-	pub fn stack_size(&self) -> usize {
-		MEM_SIZE - self.stack_pointer
+	/// Writes a jump target into code emitted earlier.
+	fn patch(&mut self, addr: usize, x: i32) {
+		if let Some(cell) = self.memory.get_mut(addr) {
+			*cell = x;
+		}
 	}
 
-	pub fn dump1(&self) {
-		let mut zeroes = 0;
-		for x in self.memory.iter() {
-			if *x == 0 && zeroes > 10 {
-				continue;
-			}
-			print!("{} ", x);
-			if *x == 0 {
-				zeroes += 1;
-			} else {
-				zeroes = 0
-			}
-			if zeroes > 10 {
-				print!("[...] ")
-			}
+	/// Lowest address the data stack may use, it must stay above the code and within `max_stack`.
+	#[inline]
+	pub fn stack_end(&self) -> usize {
+		match self.max_stack {
+			Some(max) => self.code_end.max(MEM_SIZE.saturating_sub(max)),
+			None => self.code_end,
 		}
-		println!("\n");
 	}
 
-	pub fn dump2(&self) {
-		println!("Cursor is at {}", self.cursor);
-		let line_size = 10;
-		let mut line_idx = 0;
-		let mut addr = 0;
-		let mut line: String = "".to_string();
-		let mut all_zero = true;
-		for x in self.memory.iter() {
-			if *x != 0 {
-				all_zero = false;
-			}
-			if line_idx == 0 {
-				line.push_str(format!("{:6}| ", addr).as_str());
-			}
-			if addr == self.cursor || addr == self.stack_pointer {
-				line.push_str(format!("[{:5}]", x).as_str());
-			} else {
-				line.push_str(format!("{:6} ", x).as_str());
-			}
-
-			addr += 1;
-			line_idx += 1;
+	/// Pushes a new element onto the stack, shifting stack top pointer to the left.
+	/// Fails when the stack would grow into the code or beyond `max_stack`.
+	#[inline]
+	pub fn push(&mut self, x: i32) -> Result<(), Error> {
+		if self.stack_pointer <= self.stack_end() {
+			std::hint::cold_path();
+			return Err(Error::runtime("stack overflow"));
+		}
+		self.stack_pointer -= 1;
+		self.memory[self.stack_pointer] = x;
+		Ok(())
+	}
 
-			if line_idx >= line_size {
-				if !all_zero || addr < 30 {
-					println!("{}", line);
-				}
-				line_idx = 0;
-				all_zero = true;
-				line = String::new();
+	#[inline]
+	pub fn peek(&self) -> Result<i32, Error> {
+		match self.memory.get(self.stack_pointer) {
+			Some(x) => Ok(*x),
+			None => {
+				std::hint::cold_path();
+				Err(Error::runtime("stack underflow"))
 			}
 		}
-		if !all_zero {
-			println!("{}", line);
+	}
+
+	#[inline]
+	pub fn pop(&mut self) -> Result<i32, Error> {
+		let ret = self.peek()?;
+		self.stack_pointer += 1;
+		Ok(ret)
+	}
+
+	/// Saves variables, stacks and cursor, so that `restore` can go back to this point. Code isn't
+	/// included, running doesn't change it.
+	pub fn snapshot(&self) -> Snapshot {
+		Snapshot {
+			head: self.memory[..=self.callstack_pointer].to_vec(),
+			stack: self.memory[self.stack_pointer..].to_vec(),
+			cursor: self.cursor,
+			callstack_pointer: self.callstack_pointer,
 		}
 	}
 
-	pub fn callstack_push(&mut self, x: i32) {
+	pub fn restore(&mut self, snapshot: &Snapshot) {
+		self.memory[..snapshot.head.len()].copy_from_slice(&snapshot.head);
+		self.stack_pointer = MEM_SIZE - snapshot.stack.len();
+		self.memory[self.stack_pointer..].copy_from_slice(&snapshot.stack);
+		self.cursor = snapshot.cursor;
+		self.callstack_pointer = snapshot.callstack_pointer;
+	}
+
+	pub fn stack_size(&self) -> usize {
+		MEM_SIZE - self.stack_pointer
+	}
+
+	#[inline]
+	pub fn callstack_push(&mut self, x: i32) -> Result<(), Error> {
+		if self.callstack_pointer + 1 >= CALL_STACK_START + CALL_STACK_SIZE {
+			std::hint::cold_path();
+			return Err(Error::runtime("call stack overflow"));
+		}
 		self.callstack_pointer += 1;
 		self.memory[self.callstack_pointer] = x;
+		Ok(())
 	}
 
-	pub fn callstack_pop(&mut self) -> i32 {
+	#[inline]
+	pub fn callstack_pop(&mut self) -> Result<i32, Error> {
+		if self.callstack_pointer < CALL_STACK_START {
+			std::hint::cold_path();
+			return Err(Error::runtime("call stack underflow"));
+		}
 		let ret = self.memory[self.callstack_pointer];
-		self.memory[self.callstack_pointer] = 0;  // optional
 		self.callstack_pointer -= 1;
-		ret
+		Ok(ret)
 	}
 
-	pub fn step(&mut self) -> StepResult {
-		if self.verbose {
-			println!();
-			println!();
-			self.dump2();
+	/// Executes one instruction. On error the cursor is left at the failing instruction.
+	#[inline]
+	pub fn step(&mut self) -> Result<StepResult, Error> {
+		if let Some(fuel) = self.fuel.as_mut() {
+			if *fuel == 0 {
+				return Err(Error::OutOfFuel);
+			}
+			*fuel -= 1;
 		}
+		self.step_unmetered()
+	}
 
-		let opcode = Instr::try_from(self.instr_consume()).expect("Invalid opcode");
+	/// `step` without the fuel check, so that `run` only pays for it when there is a limit.
+	#[inline(always)]
+	fn step_unmetered(&mut self) -> Result<StepResult, Error> {
+		let start = self.cursor;
+		self.exec().inspect_err(|_| self.cursor = start)
+	}
 
-		if self.verbose {
-			println!("Step: {:?}", opcode);
-		}
+	#[inline(always)]
+	fn exec(&mut self) -> Result<StepResult, Error> {
+		let cell = self.instr_consume()?;
+		let opcode = Instr::try_from(cell).map_err(|_| invalid_opcode(cell))?;
 
 		match opcode {
-			Instr::Noop => StepResult::OK,
+			Instr::Noop => Ok(StepResult::OK),
 			// Instr::Noop => panic!("noop is disabled"),
 			Instr::Push => {
-				let arg = self.instr_consume();
-				self.push(arg);
-				StepResult::OK
+				let arg = self.instr_consume()?;
+				self.push(arg)?;
+				Ok(StepResult::OK)
 			}
 
 			Instr::Dup => {
-				let x = self.peek();
-				self.push(x);
-				StepResult::OK
+				let x = self.peek()?;
+				self.push(x)?;
+				Ok(StepResult::OK)
 			}
 			Instr::Drop => {
-				_ = self.pop();
-				StepResult::OK
+				_ = self.pop()?;
+				Ok(StepResult::OK)
 			}
 			Instr::Swap => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(a);
-				self.push(b);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(a)?;
+				self.push(b)?;
+				Ok(StepResult::OK)
 			}
 			Instr::Rot => {
-				let a = self.pop();
-				let b = self.pop();
-				let c = self.pop();
-				self.push(b);
-				self.push(a);
-				self.push(c);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				let c = self.pop()?;
+				self.push(b)?;
+				self.push(a)?;
+				self.push(c)?;
+				Ok(StepResult::OK)
 			}
 			Instr::Pick => {
-				let n = self.pop();
-				let addr: usize = (self.stack_pointer as i32 + n) as usize;
-				self.push(self.memory[addr]);
-				StepResult::OK
+				let n = self.pop()?;
+				let addr = usize::try_from(n).ok()
+					.map(|n| self.stack_pointer + n)
+					.filter(|addr| *addr < MEM_SIZE)
+					.ok_or_else(|| pick_out_of_range(n))?;
+				self.push(self.memory[addr])?;
+				Ok(StepResult::OK)
 			}
 
 			Instr::Plus => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(b + a);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(b.wrapping_add(a))?;
+				Ok(StepResult::OK)
 			}
 			Instr::Minus => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(b - a);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(b.wrapping_sub(a))?;
+				Ok(StepResult::OK)
 			}
 			Instr::Mul => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(b * a);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(b.wrapping_mul(a))?;
+				Ok(StepResult::OK)
 			}
 			Instr::Div => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(b / a);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				if a == 0 {
+					return Err(Error::runtime("division by zero"));
+				}
+				self.push(b.wrapping_div(a))?;
+				Ok(StepResult::OK)
 			}
 			Instr::Negate => {
-				let a = self.pop();
-				self.push(-a);
-				StepResult::OK
+				let a = self.pop()?;
+				self.push(a.wrapping_neg())?;
+				Ok(StepResult::OK)
 			}
 			Instr::BitAnd => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(a & b);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(a & b)?;
+				Ok(StepResult::OK)
 			}
 			Instr::BitOr => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(a | b);
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(a | b)?;
+				Ok(StepResult::OK)
 			}
 			Instr::BitNot => {
-				let a = self.pop();
-				self.push(!a);
-				StepResult::OK
+				let a = self.pop()?;
+				self.push(!a)?;
+				Ok(StepResult::OK)
 			}
 
 			Instr::Gt => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(if b > a { !0 } else { 0 });
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(if b > a { !0 } else { 0 })?;
+				Ok(StepResult::OK)
 			}
 			Instr::Eq => {
-				let a = self.pop();
-				let b = self.pop();
-				self.push(if a == b { !0 } else { 0 });
-				StepResult::OK
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(if a == b { !0 } else { 0 })?;
+				Ok(StepResult::OK)
+			}
+			Instr::LessThan => {
+				let a = self.pop()?;
+				let b = self.pop()?;
+				self.push(if b < a { !0 } else { 0 })?;
+				Ok(StepResult::OK)
+			}
+
+			Instr::ReadChar => {
+				let c = read_char(&mut self.input);
+				self.push(c)?;
+				Ok(StepResult::OK)
 			}
 
 			Instr::WriteString => {
-				let n = self.instr_consume();
+				let n = self.instr_consume()?;
 				for _ in 0..n {
-					let a = self.instr_consume();
-					print!("{}", a as u8 as char);
+					let a = self.instr_consume()?;
+					write!(self.output, "{}", a as u8 as char)?;
 				}
-				StepResult::OK
+				Ok(StepResult::OK)
 			}
 			Instr::WriteChar => {
-				let a = self.pop();
-				print!("{}", a as u8 as char);
-				StepResult::OK
+				let a = self.pop()?;
+				write!(self.output, "{}", a as u8 as char)?;
+				Ok(StepResult::OK)
 			}
 			Instr::WriteInt => {
-				let a = self.pop();
-				print!("{}", a);
-				StepResult::OK
+				let a = self.pop()?;
+				write!(self.output, "{}", a)?;
+				Ok(StepResult::OK)
+			}
+			Instr::FlushIO => {
+				self.output.flush()?;
+				Ok(StepResult::OK)
 			}
 
 			Instr::Call => {
-				let addr = self.pop();
-				self.callstack_push(self.cursor as i32);
-				self.goto(addr as usize);
-				StepResult::OK
+				let addr = self.pop()?;
+				self.call(addr)?;
+				Ok(StepResult::OK)
 			}
 			Instr::CallIf => {
-				let body_addr = self.pop();
-				let cond = self.pop();
+				let body_addr = self.pop()?;
+				let cond = self.pop()?;
 				if cond != 0 {
-					self.callstack_push(self.cursor as i32);
-					self.goto(body_addr as usize);
+					self.call(body_addr)?;
 				}
-				StepResult::OK
+				Ok(StepResult::OK)
 			}
 
 			Instr::Goto => {
-				let addr = self.instr_consume();
-				self.goto(addr as usize);
-				StepResult::OK
+				let addr = self.instr_consume()?;
+				self.jump(addr)?;
+				Ok(StepResult::OK)
 			}
 			Instr::GotoIf => {
-				let addr = self.instr_consume();
-				let cond = self.pop();
+				let addr = self.instr_consume()?;
+				let cond = self.pop()?;
 				if cond != 0 {
-					self.goto(addr as usize);
+					self.jump(addr)?;
 				}
-				StepResult::OK
+				Ok(StepResult::OK)
 			}
 
 			Instr::Return => {
-				let addr = self.callstack_pop();
-				self.goto(addr as usize);
-				StepResult::OK
+				let addr = self.callstack_pop()?;
+				self.jump(addr)?;
+				Ok(StepResult::OK)
 			}
-			Instr::Halt => StepResult::End,
+			Instr::Halt => Ok(StepResult::End),
 			Instr::VarRead => {
-				let n = self.pop();
-				self.push(self.memory[FIRST_VAR + n as usize]);
-				StepResult::OK
+				let n = self.pop()?;
+				self.push(self.memory[Self::var_addr(n)?])?;
+				Ok(StepResult::OK)
 			}
 			Instr::VarWrite => {
-				let var = self.pop();
-				let value = self.pop();
-				self.memory[FIRST_VAR + var as usize] = value;
-				StepResult::OK
+				let var = self.pop()?;
+				let value = self.pop()?;
+				self.memory[Self::var_addr(var)?] = value;
+				Ok(StepResult::OK)
 			}
 
 			Instr::MoveToCallStack => {
-				let addr = self.pop();
-				self.callstack_push(addr);
-				StepResult::OK
+				let addr = self.pop()?;
+				self.callstack_push(addr)?;
+				Ok(StepResult::OK)
 			}
 			Instr::PickFromCallStack => {
-				let n = self.instr_consume();
-				let addr = self.memory[self.callstack_pointer - n as usize];
-				self.push(addr);
-				StepResult::OK
+				let n = self.instr_consume()?;
+				let addr = usize::try_from(n).ok()
+					.and_then(|n| self.callstack_pointer.checked_sub(n))
+					.filter(|addr| *addr >= CALL_STACK_START)
+					.ok_or_else(|| Error::runtime("call stack underflow"))?;
+				let addr = self.memory[addr];
+				self.push(addr)?;
+				Ok(StepResult::OK)
 			}
 			Instr::DropFromCallStack => {
-				_ = self.callstack_pop();
-				StepResult::OK
+				_ = self.callstack_pop()?;
+				Ok(StepResult::OK)
+			}
+			Instr::TailCall => {
+				let addr = self.pop()?;
+				self.jump(addr)?;
+				Ok(StepResult::OK)
+			}
+			Instr::TailCallIf => {
+				let body_addr = self.pop()?;
+				let cond = self.pop()?;
+				if cond != 0 {
+					self.jump(body_addr)?;
+				}
+				Ok(StepResult::OK)
+			}
+
+			Instr::LoadVar => {
+				let n = self.instr_consume()?;
+				self.push(self.memory[Self::var_addr(n)?])?;
+				Ok(StepResult::OK)
+			}
+			Instr::StoreVar => {
+				let n = self.instr_consume()?;
+				let value = self.pop()?;
+				self.memory[Self::var_addr(n)?] = value;
+				Ok(StepResult::OK)
+			}
+			Instr::CallImm => {
+				let addr = self.instr_consume()?;
+				self.call(addr)?;
+				Ok(StepResult::OK)
+			}
+			Instr::CallIfImm => {
+				let addr = self.instr_consume()?;
+				let cond = self.pop()?;
+				if cond != 0 {
+					self.call(addr)?;
+				}
+				Ok(StepResult::OK)
+			}
+			Instr::AddImm => {
+				let n = self.instr_consume()?;
+				let a = self.pop()?;
+				self.push(a.wrapping_add(n))?;
+				Ok(StepResult::OK)
+			}
+			Instr::GtImm => {
+				let n = self.instr_consume()?;
+				let a = self.pop()?;
+				self.push(if a > n { !0 } else { 0 })?;
+				Ok(StepResult::OK)
+			}
+			Instr::EqImm => {
+				let n = self.instr_consume()?;
+				let a = self.pop()?;
+				self.push(if a == n { !0 } else { 0 })?;
+				Ok(StepResult::OK)
+			}
+			Instr::DupGtImm => {
+				let n = self.instr_consume()?;
+				let a = self.peek()?;
+				self.push(if a > n { !0 } else { 0 })?;
+				Ok(StepResult::OK)
 			}
 		}
 	}
 
-	pub fn run(&mut self) {
-		let mut total = 0;
-		loop {
-			let result = self.step();
-			if result == StepResult::End {
-				return
-			}
-			total += 1;
-			// if total > 50 { panic!("I'm tired"); }
+	pub fn run(&mut self) -> Result<(), Error> {
+		if self.fuel.is_some() {
+			while self.step()? == StepResult::OK {}
+		} else {
+			while self.step_unmetered()? == StepResult::OK {}
 		}
+		Ok(())
 	}
 }
 
 
+// the error paths are kept out of line, so that they don't slow down the engines
+
+#[cold]
+pub(crate) fn invalid_jump(addr: i32) -> Error {
+	Error::Runtime(format!("invalid jump address {}", addr))
+}
+
+#[cold]
+pub(crate) fn invalid_variable(n: i32) -> Error {
+	Error::Runtime(format!("invalid variable {}", n))
+}
+
+#[cold]
+pub(crate) fn pick_out_of_range(n: i32) -> Error {
+	Error::Runtime(format!("pick index {} out of range", n))
+}
+
+#[cold]
+pub(crate) fn invalid_opcode(cell: i32) -> Error {
+	Error::Runtime(format!("invalid opcode {}", cell))
+}
+
+
 impl Default for FalseVM {
 	fn default() -> Self {
 		Self::new()
@@ -525,6 +893,9 @@ impl Default for FalseVM {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::falselang::interpreter::Capture;
+	use crate::falselang::threaded::ThreadedCode;
+	use std::io::BufWriter;
 
 	#[test]
 	fn test_vm_2plus2() {
@@ -536,7 +907,7 @@ mod tests {
 		vm.instr_push(Instr::Plus);
 		vm.instr_push(Instr::Halt);
 		vm.goto(FIRST_INSTR);
-		vm.run();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_pointer, MEM_SIZE - 1);
 		assert_eq!(vm.memory[vm.stack_pointer], 4);
 	}
@@ -544,171 +915,364 @@ mod tests {
 	#[test]
 	fn test_empty() {
 		let mut vm = FalseVM::new();
-		vm.load("");
-		vm.run();
+		vm.load("", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 0);
 	}
 
 	#[test]
 	fn test_2plus2() {
 		let mut vm = FalseVM::new();
-		vm.load("2 2 +");
-		vm.run();
+		vm.load("2 2 +", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 1);
-		assert_eq!(vm.pop(), 4);
+		assert_eq!(vm.pop(), Ok(4));
 	}
 
 	#[test]
 	fn test_put_i() {
 		let mut vm = FalseVM::new();
-		vm.load("1 2 3 4 5");
-		vm.run();
+		vm.load("1 2 3 4 5", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 5);
 		for i in [5, 4, 3, 2, 1] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_charcode() {
 		let mut vm = FalseVM::new();
-		vm.load("'a    'b         'c'd");
-		vm.run();
+		vm.load("'a    'b         'c'd", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 4);
 		for i in [100, 99, 98, 97] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_dup() {
 		let mut vm = FalseVM::new();
-		vm.load("2 4$");
-		vm.run();
+		vm.load("2 4$", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 3);
 		for i in [4, 4, 2] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_drop() {
 		let mut vm = FalseVM::new();
-		vm.load("1 2 3%");
-		vm.run();
+		vm.load("1 2 3%", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 2);
 		for i in [2, 1] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_swap() {
 		let mut vm = FalseVM::new();
-		vm.load("1 2 \\");
-		vm.run();
+		vm.load("1 2 \\", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 2);
 		for i in [1, 2] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_rot() {
 		let mut vm = FalseVM::new();
-		vm.load("0 1 2 3 @");
-		vm.run();
+		vm.load("0 1 2 3 @", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 4);
 		for i in [1, 3, 2, 0] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_while() {
 		let mut vm = FalseVM::new();
-		vm.load("10 [$5 >][1 -]#");
-		vm.run();
+		vm.load("10 [$5 >][1 -]#", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 1);
-		for i in [5] {
-			assert_eq!(vm.pop(), i);
-		}
+		assert_eq!(vm.pop(), Ok(5));
+	}
+
+	#[test]
+	fn test_while_dynamic() {
+		let mut vm = FalseVM::new();
+		vm.load("10 [$5 >]c: [1 -]b: c;b;#", 0).unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack_size(), 1);
+		assert_eq!(vm.pop(), Ok(5));
+	}
+
+	#[test]
+	fn test_while_nested() {
+		let mut vm = FalseVM::new();
+		vm.load("0 3[$][1- \\ 2[$][1- \\ 1+ \\]# % \\]#%", 0).unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack_size(), 1);
+		assert_eq!(vm.pop(), Ok(6));
 	}
 
 	#[test]
 	fn test_pick_1() {
 		let mut vm = FalseVM::new();
-		vm.load("7 8 9 2 ø");
-		vm.run();
+		vm.load("7 8 9 2 ø", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 4);
 		for i in [7, 9, 8, 7] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_pick_2() {
 		let mut vm = FalseVM::new();
-		vm.load("7 8 9 2P");
-		vm.run();
+		vm.load("7 8 9 2P", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 4);
 		for i in [7, 9, 8, 7] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_negate() {
 		let mut vm = FalseVM::new();
-		vm.load("1920_");
-		vm.run();
+		vm.load("1920_", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 1);
-		for i in [-1920] {
-			assert_eq!(vm.pop(), i);
-		}
+		assert_eq!(vm.pop(), Ok(-1920));
 	}
 
 	#[test]
 	fn test_if() {
 		let mut vm = FalseVM::new();
-		vm.load("1[777]?  0[333]?  2 2+ 4=[777]?");
-		vm.run();
+		vm.load("1[777]?  0[333]?  2 2+ 4=[777]?", 0).unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack_size(), 2);
+		for i in [777, 777] {
+			assert_eq!(vm.pop(), Ok(i));
+		}
+	}
+
+	#[test]
+	fn test_if_dynamic() {
+		let mut vm = FalseVM::new();
+		vm.load("[777]a: [333]b: 1a;?  0b;?  2 2+ 4=a;?", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 2);
 		for i in [777, 777] {
-			assert_eq!(vm.pop(), i);
+			assert_eq!(vm.pop(), Ok(i));
 		}
 	}
 
 	#[test]
 	fn test_vars_1() {
 		let mut vm = FalseVM::new();
-		vm.load("50 f: 1 f; +");
-		vm.run();
+		vm.load("50 f: 1 f; +", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 1);
-		for i in [51] {
-			assert_eq!(vm.pop(), i);
-		}
+		assert_eq!(vm.pop(), Ok(51));
 	}
 
 	#[test]
 	fn test_vars_2() {
 		let mut vm = FalseVM::new();
-		vm.load("[1 +]f: 50 f;!");
-		vm.run();
+		vm.load("[1 +]f: 50 f;!", 0).unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack_size(), 1);
+		assert_eq!(vm.pop(), Ok(51));
+	}
+
+	#[test]
+	fn test_less_than() {
+		let mut vm = FalseVM::new();
+		vm.load("2 3< 3 2< 2 2<", 0).unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.stack_size(), 3);
+		for i in [0, 0, -1] {
+			assert_eq!(vm.pop(), Ok(i));
+		}
+	}
+
+	#[test]
+	fn test_io() {
+		let out = Capture::default();
+		let mut vm = FalseVM::new();
+		vm.input = Box::new(std::io::Cursor::new(b"ab".to_vec()));
+		vm.output = Box::new(BufWriter::new(out.clone()));
+		vm.load("^^,, \"!\"ß ^", 0).unwrap();
+		for _ in 0..5 {
+			vm.step().unwrap();
+		}
+		assert_eq!(&*out.bytes(), b"");
+		// ß flushes
+		vm.step().unwrap();
+		assert_eq!(&*out.bytes(), b"ba!");
+		vm.run().unwrap();
+		// -1 at the end of input
 		assert_eq!(vm.stack_size(), 1);
-		for i in [51] {
-			assert_eq!(vm.pop(), i);
+		assert_eq!(vm.pop(), Ok(-1));
+	}
+
+	/// Runs to the end, returns the deepest call stack seen.
+	fn run_measuring_calls(vm: &mut FalseVM) -> usize {
+		let mut depth = 0;
+		while vm.step() == Ok(StepResult::OK) {
+			depth = depth.max(vm.callstack_pointer + 1 - CALL_STACK_START);
+		}
+		depth
+	}
+
+	#[test]
+	fn test_tail_call() {
+		for opt_level in [0, 2] {
+			let mut vm = FalseVM::new();
+			vm.load("[$0>[1-f;!]?]f: 100000 f;!", opt_level).unwrap();
+			assert_eq!(run_measuring_calls(&mut vm), 1);
+			assert_eq!(vm.stack_size(), 1);
+			assert_eq!(vm.pop(), Ok(0));
 		}
 	}
 
+	#[test]
+	fn test_tail_call_mutual() {
+		for opt_level in [0, 2] {
+			let mut vm = FalseVM::new();
+			vm.load("[1-f;!]g: [$0>g;?]f: 0s: [s;1+s: 1 2 3%%%]h: [h;!$0>g;?]f: 5000 f;! s;", opt_level).unwrap();
+			assert_eq!(run_measuring_calls(&mut vm), 2);
+			assert_eq!(vm.stack_size(), 2);
+			for i in [5001, 0] {
+				assert_eq!(vm.pop(), Ok(i));
+			}
+		}
+	}
+
+	#[test]
+	fn test_not_tail_call() {
+		let mut vm = FalseVM::new();
+		vm.load("[$0>[1-f;!1+]?]f: 100 f;!", 0).unwrap();
+		assert_eq!(run_measuring_calls(&mut vm), 101);
+		assert_eq!(vm.stack_size(), 1);
+		assert_eq!(vm.pop(), Ok(100));
+	}
+
 	#[test]
 	fn test_fn_factorial() {
 		let mut vm = FalseVM::new();
-		vm.load("[$1=$[\\%1\\]?~[$1-f;!*]?]f:    6 f;!");
-		vm.run();
+		vm.load("[$1=$[\\%1\\]?~[$1-f;!*]?]f:    6 f;!", 0).unwrap();
+		vm.run().unwrap();
 		assert_eq!(vm.stack_size(), 1);
-		for i in [720] {
-			assert_eq!(vm.pop(), i);
+		assert_eq!(vm.pop(), Ok(720));
+	}
+
+	#[test]
+	fn test_load_again() {
+		let mut vm = FalseVM::new();
+		vm.load("[1+]f: 5a: 1 0/", 0).unwrap();
+		assert!(vm.run().is_err());
+		vm.load("a;f;! 2", 0).unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.memory[vm.stack_pointer..], [2, 6]);
+
+		// optimizing the new code must not move lambdas stored before
+		let mut vm = FalseVM::new();
+		vm.load("[1 1+ +]f: 1 2 3", 3).unwrap();
+		vm.run().unwrap();
+		let code = vm.memory[FIRST_INSTR..vm.code_end].to_vec();
+		vm.load("f;! 2 2+ f;!", 0).unwrap();
+		assert_eq!(vm.memory[FIRST_INSTR..FIRST_INSTR + code.len()], code);
+		vm.run().unwrap();
+		assert_eq!(vm.memory[vm.stack_pointer..], [6, 5, 2, 1]);
+	}
+
+	#[test]
+	fn test_load_failed() {
+		let mut vm = FalseVM::new();
+		vm.load("[1]f: 7", 0).unwrap();
+		vm.run().unwrap();
+		let code = vm.memory[FIRST_INSTR..vm.code_end].to_vec();
+		let huge = format!("[2]g: \"{}\"", "a".repeat(MEM_SIZE));
+		assert_eq!(vm.load(&huge, 0), Err(Error::runtime("program does not fit into memory")));
+		assert!(vm.load("[3]g: ]", 0).is_err());
+		assert_eq!(vm.memory[FIRST_INSTR..vm.code_end], code);
+		assert_eq!(vm.memory[vm.stack_pointer..], [7]);
+
+		// functions get new numbers instead of replacing the ones loaded before
+		vm.load("[4]g: f;! g;!", 0).unwrap();
+		vm.run().unwrap();
+		assert_eq!(vm.memory[vm.stack_pointer..], [4, 1, 7]);
+		let mut functions: Vec<usize> = vm.fn_pointer.keys().copied().collect();
+		functions.sort();
+		assert_eq!(functions, [0, 1, 2, 3]);
+	}
+
+	#[test]
+	fn test_spans() {
+		let spans = |vm: &FalseVM| {
+			let mut spans: Vec<(usize, Vec<usize>)> = vm.spans.iter().map(|(addr, pos)| (*addr - FIRST_INSTR, pos.clone())).collect();
+			spans.sort();
+			spans
+		};
+		let mut vm = FalseVM::new();
+		vm.load("1[2]?", 0).unwrap();
+		// the lambda is compiled on its own and inline, its end offset marks where each copy starts
+		assert_eq!(spans(&vm), [(0, vec![2, 3]), (3, vec![0, 5]), (5, vec![1, 4]), (9, vec![2, 3])]);
+
+		let mut vm = FalseVM::new();
+		vm.load("1 2+ $%", 1).unwrap();
+		// `$%` is optimized away
+		assert_eq!(spans(&vm), [(0, vec![0, 7, 2, 3])]);
+	}
+
+	#[test]
+	fn test_compile_unknown_token() {
+		// the parser never outputs lambda brackets, hand-made token lists may
+		let mut vm = FalseVM::new();
+		let result = vm.compile_fn(0, 0, &[vec![Token::LambdaStart]], &[vec![0, 1]], Instr::Halt);
+		assert_eq!(result, Err(Error::runtime("cannot compile LambdaStart")));
+	}
+
+	#[test]
+	fn test_max_stack() {
+		let mut vm = FalseVM::new();
+		vm.max_stack = Some(3);
+		vm.load("1 2 3 %%% 1 2 3", 0).unwrap();
+		assert_eq!(vm.run(), Ok(()));
+		vm.load("%%% 1 2 3 4", 0).unwrap();
+		assert_eq!(vm.run(), Err(Error::runtime("stack overflow")));
+		assert_eq!(vm.stack_size(), 3);
+		vm.load("%%% 1 2 3 4", 0).unwrap();
+		assert_eq!(ThreadedCode::new(&vm).run_cached(&mut vm), Err(Error::runtime("stack overflow")));
+		assert_eq!(vm.stack_size(), 3);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let mut vm = FalseVM::new();
+		vm.load("[$0>[1-f;!1+]?]f: 0i: [i;3>~][i;$1+i:3f;!]# 5", 0).unwrap();
+		for _ in 0..40 {
+			vm.step().unwrap();
 		}
+		assert!(vm.callstack_pointer >= CALL_STACK_START);
+		let snapshot = vm.snapshot();
+		vm.run().unwrap();
+		let stack = vm.memory[vm.stack_pointer..].to_vec();
+		assert_eq!(stack.len(), 9);
+
+		vm.restore(&snapshot);
+		assert_eq!(vm.snapshot(), snapshot);
+		vm.run().unwrap();
+		assert_eq!(vm.memory[vm.stack_pointer..], stack);
+		assert_eq!(vm.memory[FIRST_VAR + 8], 4);
 	}
 }
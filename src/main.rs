@@ -1,33 +1,645 @@
-mod falselang;
-use falselang::vm::FalseVM;
-// use falselang::vm2::FalseVM;
-use std::{io::Read, time::Instant};
+use rfalse::falselang::interpreter::{run_with_stats, Engine, Interpreter, Limits, Stats};
+use rfalse::falselang::parser::Parser;
+use rfalse::falselang::tokenizer::{Dialect, Tokenizer};
+use rfalse::falselang::error::{line_col, Error};
+use rfalse::falselang::coverage::Coverage;
+use rfalse::falselang::profiler::Profiler;
+use rfalse::falselang::trace::{Filter, Tracer};
+use rfalse::falselang::{asm, disasm, formatter, vm, vm2};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 
+mod debug;
+mod repl;
+use debug::Session;
+use repl::Repl;
 
-fn main() {
-	let args: Vec<String> = std::env::args().collect();
-	// if a filename is provided, read from file
-	let code = if args.len() == 2 {
-		std::fs::read_to_string(&args[1]).expect("Failed to read file")
+
+const USAGE: &str = "\
+Usage: rfalse [COMMAND] [OPTIONS] [FILE | -e CODE]
+
+Commands:
+  run       run the program (default)
+  check     only parse the program and report syntax errors
+  compile   write the bytecode as assembly, which `run --asm` can load
+  disasm    print the bytecode listing
+  fmt       print the program in canonical form, comments are not kept
+  repl      read and run one entry at a time, the stack and variables are kept, see :help
+  debug     run the program step by step with the tree engine, reads commands from stdin, see help
+
+The program is read from FILE, from CODE, or from stdin if neither is given or FILE is `-`.
+What the program reads with `^` comes from stdin, unless --input or --input-string is given.
+Only one of the two can use stdin.
+
+Options:
+  -e, --eval CODE        program text
+  -i, --input FILE       program input, `-` for stdin (run, repl and debug)
+      --input-string S   program input, given as text (run, repl and debug)
+      --engine NAME      tree (default) or bytecode
+      --dialect NAME     rfalse (default) or strict
+      --limits LIMITS    steps=N,stack=N, either may be left out
+  -O, --opt-level N      bytecode optimizations, 0 to 3 (default 0), repl up to 2
+      --asm              the program is bytecode assembly (run only)
+      --time[=json]      write parse and run times to stderr (run only)
+      --stats[=json]     also count steps and the deepest data and call stack, runs
+                         one step at a time, which is a lot slower (run only)
+      --profile          write steps and time per lambda to stderr, also one step
+                         at a time (run only)
+      --folded FILE      with --profile, also write the call chains in the folded
+                         format of flamegraph.pl and inferno, weighted by steps
+      --coverage         write the source to stderr with how often each line ran,
+                         what each ? and # did and which tokens never ran (run
+                         only, not with -O 3)
+      --lcov FILE        with --coverage, also write the counts as an lcov tracefile
+      --trace FILE       write each step as a line of JSON to FILE, `-` for stderr,
+                         also one step at a time (run only)
+      --trace-filter F   with --trace, only write the steps that enter or leave a
+                         lambda (calls), read or write (io) or are in a lambda
+                         (lambda=NAME, e.g. lambda=f), can be given more than once
+  -o, --output FILE      where compile writes to, stdout by default
+  -h, --help             show this help
+
+Exit codes:
+  0  success
+  1  the program failed while running
+  2  usage error, or a file could not be read or written
+  3  syntax error";
+
+const EXIT_RUNTIME: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_SYNTAX: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+	Run,
+	Check,
+	Compile,
+	Disasm,
+	Fmt,
+	Repl,
+	Debug,
+}
+
+const COMMANDS: [(&str, Command); 7] = [
+	("run", Command::Run),
+	("check", Command::Check),
+	("compile", Command::Compile),
+	("disasm", Command::Disasm),
+	("fmt", Command::Fmt),
+	("repl", Command::Repl),
+	("debug", Command::Debug),
+];
+
+/// Where the program, or the program's input, comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+	File(String),
+	Eval(String),
+	Stdin,
+}
+
+/// What `run` writes to stderr when it is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Report {
+	None,
+	Time,
+	Stats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Options {
+	command: Command,
+	source: Source,
+	input: Option<Source>, // stdin if None
+	engine: Engine,
+	dialect: Dialect,
+	limits: Limits,
+	opt_level: u8,
+	asm: bool,
+	output: Option<String>,
+	report: Report,
+	json: bool,
+	profile: bool,
+	folded: Option<String>,
+	coverage: bool,
+	lcov: Option<String>,
+	trace: Option<String>,
+	trace_filters: Vec<Filter>,
+}
+
+/// Parses the arguments after the program name. `Ok(None)` means help was asked for.
+fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+	let mut options = Options {
+		command: Command::Run,
+		source: Source::Stdin,
+		input: None,
+		engine: Engine::Tree,
+		dialect: Dialect::Rfalse,
+		limits: Limits::default(),
+		opt_level: 0,
+		asm: false,
+		output: None,
+		report: Report::None,
+		json: false,
+		profile: false,
+		folded: None,
+		coverage: false,
+		lcov: None,
+		trace: None,
+		trace_filters: Vec::new(),
+	};
+	let mut args = args.iter().peekable();
+	if let Some((_, command)) = args.peek().and_then(|arg| COMMANDS.iter().find(|(name, _)| name == arg)) {
+		options.command = *command;
+		args.next();
+	}
+
+	let mut file = None;
+	while let Some(arg) = args.next() {
+		// both `--name value` and `--name=value`
+		let (name, inline) = match arg.split_once('=') {
+			Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+			_ => (arg.as_str(), None),
+		};
+		let mut value = || inline.clone().or_else(|| args.next().cloned()).ok_or_else(|| format!("{} needs a value", name));
+		match name {
+			"-h" | "--help" => return Ok(None),
+			"-e" | "--eval" => options.source = Source::Eval(value()?),
+			"-i" | "--input" => options.input = Some(match value()?.as_str() {
+				"-" => Source::Stdin,
+				path => Source::File(path.to_string()),
+			}),
+			"--input-string" => options.input = Some(Source::Eval(value()?)),
+			"--engine" => options.engine = value()?.parse()?,
+			"--dialect" => options.dialect = value()?.parse()?,
+			"--limits" => options.limits = value()?.parse()?,
+			"-O" | "--opt-level" => {
+				let level = value()?;
+				options.opt_level = level.parse().ok().filter(|n| *n <= 3).ok_or_else(|| format!("invalid opt level: {}", level))?;
+			}
+			"--asm" => options.asm = true,
+			"-o" | "--output" => options.output = Some(value()?),
+			"--time" | "--stats" => {
+				let report = if name == "--time" { Report::Time } else { Report::Stats };
+				options.report = options.report.max(report);
+				options.json |= match inline.as_deref() {
+					None => false,
+					Some("json") => true,
+					Some(format) => return Err(format!("unknown {} format: {}", name, format)),
+				};
+			}
+			"--profile" => options.profile = true,
+			"--folded" => options.folded = Some(value()?),
+			"--coverage" => options.coverage = true,
+			"--lcov" => options.lcov = Some(value()?),
+			"--trace" => options.trace = Some(value()?),
+			"--trace-filter" => options.trace_filters.push(value()?.parse()?),
+			"-" => file = Some(Source::Stdin),
+			_ if name.starts_with('-') => return Err(format!("unknown option: {}", name)),
+			_ if file.is_some() => return Err(format!("unexpected argument: {}", arg)),
+			_ => file = Some(Source::File(arg.clone())),
+		}
+	}
+	match (file, &options.source) {
+		(Some(_), Source::Eval(_)) => return Err("give either FILE or -e, not both".to_string()),
+		(Some(source), _) => options.source = source,
+		_ => {}
+	}
+	if options.input.is_some() && !matches!(options.command, Command::Run | Command::Repl | Command::Debug) {
+		return Err("--input only works with run, repl and debug".to_string());
+	}
+	if options.command == Command::Debug && options.source == Source::Stdin {
+		return Err("debug reads commands from stdin, give the program as FILE or with -e".to_string());
+	}
+	if options.command == Command::Debug && options.engine != Engine::Tree {
+		return Err("debug only works with the tree engine".to_string());
+	}
+	let program_on_stdin = options.source == Source::Stdin || matches!(options.command, Command::Repl | Command::Debug);
+	if program_on_stdin && options.input == Some(Source::Stdin) {
+		return Err("the program and its input can't both come from stdin".to_string());
+	}
+	if options.asm && options.command != Command::Run {
+		return Err("--asm only works with run".to_string());
+	}
+	if options.report != Report::None && options.command != Command::Run {
+		return Err("--time and --stats only work with run".to_string());
+	}
+	if options.folded.is_some() && !options.profile {
+		return Err("--folded needs --profile".to_string());
+	}
+	if options.lcov.is_some() && !options.coverage {
+		return Err("--lcov needs --coverage".to_string());
+	}
+	if !options.trace_filters.is_empty() && options.trace.is_none() {
+		return Err("--trace-filter needs --trace".to_string());
+	}
+	let instruments = [options.profile, options.coverage, options.trace.is_some()].iter().filter(|on| **on).count();
+	if instruments > 0 && options.command != Command::Run {
+		return Err("--profile, --coverage and --trace only work with run".to_string());
+	}
+	if instruments > 1 {
+		return Err("give only one of --profile, --coverage and --trace".to_string());
+	}
+	if instruments > 0 && (options.asm || options.report == Report::Stats) {
+		return Err("--profile, --coverage and --trace can't be combined with --asm or --stats".to_string());
+	}
+	if options.coverage && options.opt_level >= 3 {
+		return Err("--coverage doesn't work with -O 3, inlining drops the calls it replaces".to_string());
+	}
+	if options.command == Command::Repl && options.opt_level >= 3 {
+		return Err("repl doesn't work with -O 3, inlining only sees the variables of one entry".to_string());
+	}
+	if options.output.is_some() && options.command != Command::Compile {
+		return Err("-o only works with compile".to_string());
+	}
+	Ok(Some(options))
+}
+
+/// Something that stops the command, with the exit code to stop with.
+struct Failure {
+	code: i32,
+	message: String,
+}
+
+impl Failure {
+	fn usage(message: String) -> Failure {
+		Failure { code: EXIT_USAGE, message }
+	}
+}
+
+/// Program text and the name to use in error messages.
+fn read_source(source: &Source) -> Result<(String, String), Failure> {
+	match source {
+		Source::File(path) => std::fs::read_to_string(path)
+			.map(|code| (code, path.clone()))
+			.map_err(|e| Failure::usage(format!("can't read {}: {}", path, e))),
+		Source::Eval(code) => Ok((code.clone(), "-e".to_string())),
+		Source::Stdin => {
+			let mut code = String::new();
+			std::io::stdin().read_to_string(&mut code).map_err(|e| Failure::usage(format!("can't read stdin: {}", e)))?;
+			Ok((code, "<stdin>".to_string()))
+		}
+	}
+}
+
+/// Stream for `^`.
+fn open_input(input: &Option<Source>) -> Result<Box<dyn Read>, Failure> {
+	match input {
+		None | Some(Source::Stdin) => Ok(Box::new(std::io::stdin())),
+		Some(Source::Eval(text)) => Ok(Box::new(std::io::Cursor::new(text.clone().into_bytes()))),
+		Some(Source::File(path)) => std::fs::File::open(path)
+			.map(|file| Box::new(std::io::BufReader::new(file)) as Box<dyn Read>)
+			.map_err(|e| Failure::usage(format!("can't read {}: {}", path, e))),
+	}
+}
+
+/// Turns a VM error into a message that points into the source.
+fn failure(e: Error, name: &str, code: &str) -> Failure {
+	match e {
+		Error::Syntax { pos, message, .. } => {
+			let (line, col) = line_col(code, pos);
+			Failure { code: EXIT_SYNTAX, message: format!("{}:{}:{}: syntax error: {}", name, line, col, message) }
+		}
+		e => Failure { code: EXIT_RUNTIME, message: format!("{}: {}", name, e) },
+	}
+}
+
+/// VM with the settings from `options`, reading and writing stdin and stdout.
+fn create_vm(options: &Options) -> Box<dyn Interpreter> {
+	let mut vm: Box<dyn Interpreter> = match options.engine {
+		Engine::Tree => Box::new(vm::FalseVM::new()),
+		Engine::Bytecode => Box::new(vm2::FalseVM::new()),
+	};
+	vm.set_dialect(options.dialect);
+	vm.set_limits(options.limits);
+	vm
+}
+
+fn compile(options: &Options, name: &str, code: &str) -> Result<vm2::FalseVM, Failure> {
+	let mut vm = vm2::FalseVM::new();
+	vm.dialect = options.dialect;
+	vm.load(code, options.opt_level).map_err(|e| failure(e, name, code))?;
+	Ok(vm)
+}
+
+fn run(options: &Options) -> Result<(), Failure> {
+	let (code, name) = read_source(&options.source)?;
+	if options.profile {
+		return profile(options, &name, &code);
+	}
+	if options.coverage {
+		return coverage(options, &name, &code);
+	}
+	if let Some(path) = &options.trace {
+		return trace(options, path, &name, &code);
+	}
+
+	let start = Instant::now();
+	let mut vm = if options.asm {
+		let mut vm = vm2::FalseVM::new();
+		asm::assemble(&mut vm, &code).map_err(|e| Failure { code: EXIT_SYNTAX, message: format!("{}:{}: {}", name, e.line, e.message) })?;
+		vm.set_limits(options.limits);
+		vm.input = open_input(&options.input)?;
+		Box::new(vm) as Box<dyn Interpreter>
 	} else {
-		// otherwise, read from stdin until EOF
-		let mut code = String::new();
-		std::io::stdin().read_to_string(&mut code).expect("Failed to read stdin");
-		code
+		let mut vm = create_vm(options);
+		vm.set_io(open_input(&options.input)?, Box::new(std::io::stdout()));
+		vm.load(&code, options.opt_level).map_err(|e| failure(e, &name, &code))?;
+		vm
 	};
+	let parse_time = start.elapsed();
 
-	let mut vm = FalseVM::new();
-	print!("Parsing... ");
 	let start = Instant::now();
-	vm.load(&code);
-	println!("ok, {:?}", start.elapsed());
-	// vm.dump2();
+	let mut stats = Stats::default();
+	let result = match options.report {
+		Report::Stats => run_with_stats(vm.as_mut(), &mut stats),
+		_ => vm.run(),
+	};
+	let run_time = start.elapsed();
+	if options.report != Report::None {
+		let stats = (options.report == Report::Stats).then_some(stats);
+		let _ = std::io::stdout().flush();
+		eprintln!("{}", report(parse_time, run_time, stats, options.json));
+	}
+	result.map_err(|e| failure(e, &name, &code))
+}
+
+/// Text for `--time` and `--stats`, `stats` is only there for the latter.
+fn report(parse_time: Duration, run_time: Duration, stats: Option<Stats>, json: bool) -> String {
+	let mut fields = vec![("parse_us", parse_time.as_micros().to_string()), ("run_us", run_time.as_micros().to_string())];
+	if let Some(stats) = stats {
+		fields.push(("steps", stats.steps.to_string()));
+		fields.push(("max_stack", stats.max_stack.to_string()));
+		fields.push(("max_calls", stats.max_calls.to_string()));
+	}
+	if json {
+		let fields: Vec<String> = fields.iter().map(|(name, value)| format!("\"{}\":{}", name, value)).collect();
+		return format!("{{{}}}", fields.join(","));
+	}
+	let mut lines = vec![format!("parse:     {:?}", parse_time), format!("run:       {:?}", run_time)];
+	if let Some(stats) = stats {
+		lines.push(format!("steps:     {}", stats.steps));
+		lines.push(format!("max stack: {}", stats.max_stack));
+		lines.push(format!("max calls: {}", stats.max_calls));
+	}
+	lines.join("\n")
+}
+
+/// Runs a program on either VM one step at a time and keeps track of something, see `run_instrumented`.
+trait Instrument {
+	fn run_tree(&mut self, vm: &mut vm::FalseVM) -> Result<(), Error>;
+	fn run_bytecode(&mut self, vm: &mut vm2::FalseVM) -> Result<(), Error>;
+}
+
+impl Instrument for Profiler {
+	fn run_tree(&mut self, vm: &mut vm::FalseVM) -> Result<(), Error> {
+		Profiler::run_tree(self, vm)
+	}
+	fn run_bytecode(&mut self, vm: &mut vm2::FalseVM) -> Result<(), Error> {
+		Profiler::run_bytecode(self, vm)
+	}
+}
+
+impl Instrument for Coverage {
+	fn run_tree(&mut self, vm: &mut vm::FalseVM) -> Result<(), Error> {
+		Coverage::run_tree(self, vm)
+	}
+	fn run_bytecode(&mut self, vm: &mut vm2::FalseVM) -> Result<(), Error> {
+		Coverage::run_bytecode(self, vm)
+	}
+}
+
+impl Instrument for Tracer {
+	fn run_tree(&mut self, vm: &mut vm::FalseVM) -> Result<(), Error> {
+		Tracer::run_tree(self, vm)
+	}
+	fn run_bytecode(&mut self, vm: &mut vm2::FalseVM) -> Result<(), Error> {
+		Tracer::run_bytecode(self, vm)
+	}
+}
+
+/// Loads `code` into the engine from `options` and lets `instrument` run it. Writes `--time` if
+/// asked for and returns how the run went, so that the caller can report before failing.
+fn run_instrumented(options: &Options, name: &str, code: &str, instrument: &mut dyn Instrument) -> Result<Result<(), Error>, Failure> {
+	let start = Instant::now();
+	let mut tree = vm::FalseVM::new();
+	let mut bytecode = vm2::FalseVM::new();
+	let vm: &mut dyn Interpreter = match options.engine {
+		Engine::Tree => &mut tree,
+		Engine::Bytecode => &mut bytecode,
+	};
+	vm.set_dialect(options.dialect);
+	vm.set_limits(options.limits);
+	vm.set_io(open_input(&options.input)?, Box::new(std::io::stdout()));
+	vm.load(code, options.opt_level).map_err(|e| failure(e, name, code))?;
+	let parse_time = start.elapsed();
 
-	println!("Running...\n");
 	let start = Instant::now();
-	// vm.verbose = true;
-	vm.run();
-	println!("\n\nRun complete, {:?}", start.elapsed());
-	// println!();
-	// vm.dump2();
+	let result = match options.engine {
+		Engine::Tree => instrument.run_tree(&mut tree),
+		Engine::Bytecode => instrument.run_bytecode(&mut bytecode),
+	};
+	let run_time = start.elapsed();
+	let _ = std::io::stdout().flush();
+	if options.report == Report::Time {
+		eprintln!("{}", report(parse_time, run_time, None, options.json));
+	}
+	Ok(result)
+}
+
+fn write_file(path: &str, text: &str) -> Result<(), Failure> {
+	std::fs::write(path, text).map_err(|e| Failure::usage(format!("can't write {}: {}", path, e)))
+}
+
+/// `run --profile`: the report is written even if the program fails.
+fn profile(options: &Options, name: &str, code: &str) -> Result<(), Failure> {
+	let mut profiler = Profiler::new(code, options.dialect).map_err(|e| failure(e, name, code))?;
+	let result = run_instrumented(options, name, code, &mut profiler)?;
+	eprint!("{}", profiler.profile.report());
+	if let Some(path) = &options.folded {
+		write_file(path, &profiler.profile.folded(false))?;
+	}
+	result.map_err(|e| failure(e, name, code))
+}
+
+/// `run --coverage`: like `profile`, with the annotated source instead of the report.
+fn coverage(options: &Options, name: &str, code: &str) -> Result<(), Failure> {
+	let mut coverage = Coverage::new(code, options.dialect).map_err(|e| failure(e, name, code))?;
+	let result = run_instrumented(options, name, code, &mut coverage)?;
+	eprint!("{}", coverage.listing());
+	if let Some(path) = &options.lcov {
+		write_file(path, &coverage.lcov(name))?;
+	}
+	result.map_err(|e| failure(e, name, code))
+}
+
+/// `run --trace`: the trace is written while the program runs.
+fn trace(options: &Options, path: &str, name: &str, code: &str) -> Result<(), Failure> {
+	let out: Box<dyn Write> = match path {
+		"-" => Box::new(std::io::stderr()),
+		path => Box::new(std::fs::File::create(path).map_err(|e| Failure::usage(format!("can't write {}: {}", path, e)))?),
+	};
+	let mut tracer = Tracer::new(code, options.dialect, Box::new(std::io::BufWriter::new(out))).map_err(|e| failure(e, name, code))?;
+	tracer.filters = options.trace_filters.clone();
+	run_instrumented(options, name, code, &mut tracer)?.map_err(|e| failure(e, name, code))
+}
+
+fn check(options: &Options) -> Result<(), Failure> {
+	let (code, name) = read_source(&options.source)?;
+	let mut parser = Parser::new(Tokenizer::with_dialect(&code, options.dialect));
+	parser.parse().map_err(|e| failure(e, &name, &code))?;
+	Ok(())
+}
+
+fn compile_to_file(options: &Options) -> Result<(), Failure> {
+	let (code, name) = read_source(&options.source)?;
+	let listing = disasm::disassemble(&compile(options, &name, &code)?);
+	match &options.output {
+		Some(path) => write_file(path, &listing),
+		None => {
+			print!("{}", listing);
+			Ok(())
+		}
+	}
+}
+
+fn fmt(options: &Options) -> Result<(), Failure> {
+	let (code, name) = read_source(&options.source)?;
+	let mut parser = Parser::new(Tokenizer::with_dialect(&code, options.dialect));
+	parser.parse().map_err(|e| failure(e, &name, &code))?;
+	println!("{}", formatter::format(&parser.lambda_storage));
+	Ok(())
+}
+
+fn repl(options: &Options) -> Result<(), Failure> {
+	let mut repl = Repl::new(options.clone(), open_input(&options.input)?, Box::new(std::io::stdout()));
+	// `^` may read stdin too, so it can't stay locked
+	repl.run(|line| std::io::stdin().read_line(line)).map_err(|e| Failure::usage(format!("I/O error: {}", e)))
+}
+
+fn debug(options: &Options) -> Result<(), Failure> {
+	let (code, name) = read_source(&options.source)?;
+	let input = open_input(&options.input)?;
+	let mut session = Session::new(options, &name, &code, input, Box::new(std::io::stdout())).map_err(|e| failure(e, &name, &code))?;
+	session.run(|line| std::io::stdin().read_line(line)).map_err(|e| Failure::usage(format!("I/O error: {}", e)))
+}
+
+fn main() {
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let options = match parse_args(&args) {
+		Ok(Some(options)) => options,
+		Ok(None) => {
+			println!("{}", USAGE);
+			return;
+		}
+		Err(message) => {
+			eprintln!("{}\n\n{}", message, USAGE);
+			std::process::exit(EXIT_USAGE);
+		}
+	};
+
+	let result = match options.command {
+		Command::Run => run(&options),
+		Command::Check => check(&options),
+		Command::Compile => compile_to_file(&options),
+		Command::Disasm => read_source(&options.source).and_then(|(code, name)| {
+			print!("{}", disasm::disassemble(&compile(&options, &name, &code)?));
+			Ok(())
+		}),
+		Command::Fmt => fmt(&options),
+		Command::Repl => repl(&options),
+		Command::Debug => debug(&options),
+	};
+	let _ = std::io::stdout().flush();
+	if let Err(failure) = result {
+		eprintln!("{}", failure.message);
+		std::process::exit(failure.code);
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(args: &str) -> Result<Option<Options>, String> {
+		parse_args(&args.split_whitespace().map(String::from).collect::<Vec<_>>())
+	}
+
+	#[test]
+	fn test_parse_args() {
+		let options = parse("prog.false").unwrap().unwrap();
+		assert_eq!((options.command, options.source, options.engine), (Command::Run, Source::File("prog.false".to_string()), Engine::Tree));
+
+		let options = parse("run --engine bytecode -O 2 --limits=steps=10 --dialect strict -e 1").unwrap().unwrap();
+		assert_eq!(options.engine, Engine::Bytecode);
+		assert_eq!(options.opt_level, 2);
+		assert_eq!(options.limits, Limits { steps: Some(10), stack: None });
+		assert_eq!(options.dialect, Dialect::Strict);
+		assert_eq!(options.source, Source::Eval("1".to_string()));
+
+		assert_eq!(parse("disasm").unwrap().unwrap().source, Source::Stdin);
+		assert_eq!(parse("check -").unwrap().unwrap().source, Source::Stdin);
+		assert_eq!(parse("compile a -o b").unwrap().unwrap().output, Some("b".to_string()));
+		assert_eq!(parse("fmt --help"), Ok(None));
+
+		let options = parse("--stats=json --time a").unwrap().unwrap();
+		assert_eq!((options.report, options.json), (Report::Stats, true));
+		assert!(parse("--time=json a").unwrap().unwrap().json);
+
+		assert_eq!(parse("a -i -").unwrap().unwrap().input, Some(Source::Stdin));
+		assert_eq!(parse("-i b").unwrap().unwrap().input, Some(Source::File("b".to_string())));
+		assert_eq!(parse("repl --input-string=ab").unwrap().unwrap().input, Some(Source::Eval("ab".to_string())));
+
+		let options = parse("a --profile --folded=b").unwrap().unwrap();
+		assert_eq!((options.profile, options.folded), (true, Some("b".to_string())));
+		let options = parse("a --coverage --lcov b").unwrap().unwrap();
+		assert_eq!((options.coverage, options.lcov), (true, Some("b".to_string())));
+		let options = parse("a --trace - --trace-filter calls --trace-filter=lambda=f").unwrap().unwrap();
+		assert_eq!(options.trace, Some("-".to_string()));
+		assert_eq!(options.trace_filters, vec![Filter::Calls, Filter::Lambda("f".to_string())]);
+	}
+
+	#[test]
+	fn test_parse_args_errors() {
+		assert_eq!(parse("--engine vm3"), Err("unknown engine: vm3".to_string()));
+		assert_eq!(parse("--engine"), Err("--engine needs a value".to_string()));
+		assert_eq!(parse("-O 4"), Err("invalid opt level: 4".to_string()));
+		assert_eq!(parse("--frobnicate"), Err("unknown option: --frobnicate".to_string()));
+		assert_eq!(parse("a b"), Err("unexpected argument: b".to_string()));
+		assert!(parse("a -e 1").is_err());
+		assert!(parse("check --asm a").is_err());
+		assert!(parse("run -o a b").is_err());
+		assert!(parse("check --time a").is_err());
+		assert!(parse("--stats=xml a").is_err());
+		assert!(parse("-i -").is_err());
+		assert!(parse("- --input -").is_err());
+		assert!(parse("repl -i -").is_err());
+		assert!(parse("fmt a -i b").is_err());
+		assert!(parse("debug").is_err());
+		assert!(parse("debug a --engine bytecode").is_err());
+		assert!(parse("debug a -i -").is_err());
+		assert!(parse("a --folded b").is_err());
+		assert!(parse("check a --profile").is_err());
+		assert!(parse("a --profile --stats").is_err());
+		assert!(parse("a --lcov b").is_err());
+		assert!(parse("a --coverage --profile").is_err());
+		assert!(parse("a --coverage --asm").is_err());
+		assert!(parse("a --coverage -O 3").is_err());
+		assert!(parse("repl -O 3").is_err());
+		assert!(parse("repl -O 2").is_ok());
+		assert_eq!(parse("a --trace - --trace-filter x"), Err("unknown trace filter: x".to_string()));
+		assert!(parse("a --trace-filter io").is_err());
+		assert!(parse("a --trace b --profile").is_err());
+		assert!(parse("disasm a --trace b").is_err());
+	}
+
+	#[test]
+	fn test_report() {
+		let stats = Stats { steps: 10, max_stack: 2, max_calls: 1 };
+		let (parse, run) = (Duration::from_micros(5), Duration::from_millis(3));
+		assert_eq!(report(parse, run, Some(stats), true), r#"{"parse_us":5,"run_us":3000,"steps":10,"max_stack":2,"max_calls":1}"#);
+		assert_eq!(report(parse, run, None, true), r#"{"parse_us":5,"run_us":3000}"#);
+		assert_eq!(report(parse, run, None, false), "parse:     5µs\nrun:       3ms");
+	}
 }
@@ -0,0 +1,260 @@
+use rfalse::falselang::disasm;
+use rfalse::falselang::interpreter::{Engine, Interpreter};
+use rfalse::falselang::parser::Parser;
+use rfalse::falselang::tokenizer::Tokenizer;
+use rfalse::falselang::vm::StackElement;
+use std::{cell::RefCell, io::{self, Read, Write}, rc::Rc};
+
+use crate::{compile, create_vm, failure, Options};
+
+
+const HELP: &str = "\
+Each entry is run on the same VM, so the stack and variables are kept. An entry goes on
+over more lines while a lambda, string or comment is open.
+
+:stack          show the stack
+:vars           show the variables that aren't 0
+:reset          start over with an empty VM
+:load FILE      run a file as an entry
+:engine [NAME]  show the engine, or switch to tree or bytecode, which starts over
+:disasm [CODE]  bytecode of CODE, or of the last entry
+:help           show this help
+:quit           leave, so does end of input";
+
+/// Output shared by the VM and the REPL, so that the REPL can tell whether the program
+/// stopped in the middle of a line.
+#[derive(Clone)]
+pub struct Output(Rc<RefCell<(Box<dyn Write>, bool)>>);
+
+impl Output {
+	pub fn new(writer: Box<dyn Write>) -> Output {
+		Output(Rc::new(RefCell::new((writer, true))))
+	}
+
+	fn at_line_start(&self) -> bool {
+		self.0.borrow().1
+	}
+
+	pub fn set_line_start(&self) {
+		self.0.borrow_mut().1 = true;
+	}
+
+	/// Ends the line the program left unfinished, so that what follows starts on a new one.
+	pub fn end_line(&mut self) -> io::Result<()> {
+		if !self.at_line_start() {
+			writeln!(self)?;
+		}
+		Ok(())
+	}
+}
+
+impl Write for Output {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let (writer, line_start) = &mut *self.0.borrow_mut();
+		let n = writer.write(buf)?;
+		if n > 0 {
+			*line_start = buf[n - 1] == b'\n';
+		}
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.0.borrow_mut().0.flush()
+	}
+}
+
+/// Program input, kept when the VM is replaced.
+#[derive(Clone)]
+struct Input(Rc<RefCell<Box<dyn Read>>>);
+
+impl Read for Input {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.0.borrow_mut().read(buf)
+	}
+}
+
+pub struct Repl {
+	options: Options,
+	vm: Box<dyn Interpreter>,
+	input: Input,
+	output: Output,
+	last: String, // last entry, for :disasm
+}
+
+impl Repl {
+	pub fn new(options: Options, input: Box<dyn Read>, output: Box<dyn Write>) -> Repl {
+		let input = Input(Rc::new(RefCell::new(input)));
+		let output = Output::new(output);
+		let vm = create_vm(&options);
+		let mut repl = Repl { options, vm, input, output, last: String::new() };
+		repl.reset();
+		repl
+	}
+
+	/// Reads lines with `read_line` until it returns 0 or `:quit` is entered.
+	pub fn run(&mut self, mut read_line: impl FnMut(&mut String) -> io::Result<usize>) -> io::Result<()> {
+		let mut entry = String::new();
+		loop {
+			write!(self.output, "{}", if entry.is_empty() { "> " } else { ". " })?;
+			self.output.flush()?;
+			let mut line = String::new();
+			if read_line(&mut line)? == 0 {
+				break;
+			}
+			// the user pressed enter
+			self.output.set_line_start();
+
+			if entry.is_empty() && line.trim_start().starts_with(':') {
+				if !self.command(line.trim())? {
+					return Ok(());
+				}
+				continue;
+			}
+			entry.push_str(&line);
+			if entry.trim().is_empty() {
+				entry.clear();
+			} else if !self.is_incomplete(&entry) {
+				self.entry(&std::mem::take(&mut entry), "repl")?;
+			}
+		}
+		if !entry.is_empty() {
+			self.entry(&entry, "repl")?;
+		}
+		self.output.end_line()
+	}
+
+	fn is_incomplete(&self, code: &str) -> bool {
+		let mut parser = Parser::new(Tokenizer::with_dialect(code, self.options.dialect));
+		parser.parse().is_err_and(|e| e.is_incomplete())
+	}
+
+	/// Loads and runs `code`, then shows the stack.
+	fn entry(&mut self, code: &str, name: &str) -> io::Result<()> {
+		self.last = code.to_string();
+		let result = self.vm.load(code, self.options.opt_level).and_then(|_| self.vm.run());
+		self.output.flush()?;
+		self.output.end_line()?;
+		match result {
+			Ok(()) => writeln!(self.output, "[{}]", show_stack(&self.vm.data_stack())),
+			Err(e) => writeln!(self.output, "{}", failure(e, name, code).message),
+		}
+	}
+
+	/// Runs a meta-command, returns false for `:quit`.
+	fn command(&mut self, line: &str) -> io::Result<bool> {
+		let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+		let arg = arg.trim();
+		match name {
+			":stack" => writeln!(self.output, "[{}]", show_stack(&self.vm.data_stack()))?,
+			":vars" => {
+				let vars: Vec<String> = ('a'..='z')
+					.filter_map(|name| self.vm.variable(name).map(|value| (name, value)))
+					.filter(|(_, value)| *value != StackElement::Number(0))
+					.map(|(name, value)| format!("{} = {}", name, show(&value)))
+					.collect();
+				writeln!(self.output, "{}", if vars.is_empty() { "no variables set".to_string() } else { vars.join("\n") })?;
+			}
+			":reset" => {
+				self.reset();
+				writeln!(self.output, "started over")?;
+			}
+			":load" if arg.is_empty() => writeln!(self.output, ":load needs a file name")?,
+			":load" => match std::fs::read_to_string(arg) {
+				Ok(code) => self.entry(&code, arg)?,
+				Err(e) => writeln!(self.output, "can't read {}: {}", arg, e)?,
+			},
+			":engine" if arg.is_empty() => writeln!(self.output, "{}", self.options.engine.name())?,
+			":engine" => match arg.parse::<Engine>() {
+				Ok(engine) => {
+					self.options.engine = engine;
+					self.reset();
+					writeln!(self.output, "switched to {}, started over", engine.name())?;
+				}
+				Err(e) => writeln!(self.output, "{}", e)?,
+			},
+			":disasm" => {
+				let code = if arg.is_empty() { self.last.clone() } else { arg.to_string() };
+				match compile(&self.options, "repl", &code) {
+					Ok(vm) => write!(self.output, "{}", disasm::disassemble(&vm))?,
+					Err(e) => writeln!(self.output, "{}", e.message)?,
+				}
+			}
+			":help" => writeln!(self.output, "{}", HELP)?,
+			":quit" | ":q" => return Ok(false),
+			_ => writeln!(self.output, "unknown command {}, see :help", name)?,
+		}
+		Ok(true)
+	}
+
+	fn reset(&mut self) {
+		self.vm = create_vm(&self.options);
+		self.vm.set_io(Box::new(self.input.clone()), Box::new(self.output.clone()));
+	}
+}
+
+pub fn show(value: &StackElement) -> String {
+	match value {
+		StackElement::Number(n) => n.to_string(),
+		StackElement::Lambda(n) => format!("[fn{}]", n),
+		StackElement::Variable(v) => v.to_string(),
+	}
+}
+
+pub fn show_stack(stack: &[StackElement]) -> String {
+	stack.iter().map(show).collect::<Vec<_>>().join(" ")
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parse_args;
+	use rfalse::falselang::interpreter::Capture;
+	use std::io::BufRead;
+
+	/// Everything the REPL writes for `lines`.
+	fn session(args: &str, lines: &str) -> String {
+		let options = parse_args(&args.split_whitespace().map(String::from).collect::<Vec<_>>()).unwrap().unwrap();
+		let out = Capture::default();
+		let mut repl = Repl::new(options, Box::new(io::Cursor::new(b"xy".to_vec())), Box::new(out.clone()));
+		let mut lines = io::Cursor::new(lines.as_bytes().to_vec());
+		repl.run(|line| lines.read_line(line)).unwrap();
+		let bytes = out.bytes().to_vec();
+		String::from_utf8(bytes).unwrap()
+	}
+
+	#[test]
+	fn test_entries() {
+		for engine in ["tree", "bytecode"] {
+			let args = format!("repl --engine {}", engine);
+			assert_eq!(session(&args, "1 2\n+\n"), "> [1 2]\n> [3]\n> \n");
+			// lambdas from earlier entries can still be called
+			assert!(session(&args, "[1+]f:\n2f;!\n\n3a:\n:vars\n").starts_with("> []\n> [3]\n> > [3]\n> a = 3\nf = "));
+			assert_eq!(session(&args, "1 2 \"sum: \"+.\n^,\n"), "> sum: 3\n[]\n> x\n[]\n> \n");
+			assert_eq!(session(&args, "1 %%\n2\n"), "> repl: runtime error: stack underflow\n> [2]\n> \n");
+			// an entry that doesn't compile leaves nothing behind
+			let huge = format!("[\"{}\"]%\n1 2+\n", "a".repeat(200_000));
+			assert!(session(&args, &huge).ends_with("> [3]\n> \n"));
+		}
+	}
+
+	#[test]
+	fn test_multiline() {
+		assert_eq!(session("repl", "[1\n2]!\n{ a\ncomment }\"a\nb\"\n"), "> . [1 2]\n> . . a\nb\n[1 2]\n> \n");
+		assert_eq!(session("repl", "1 [\n"), "> . \nrepl:2:1: syntax error: unterminated lambda\n");
+		assert_eq!(session("repl", "1 Q\n"), "> repl:1:3: syntax error: invalid token 'Q'\n> \n");
+	}
+
+	#[test]
+	fn test_commands() {
+		assert_eq!(session("repl", "1 2\n:reset\n:stack\n:q\n3\n"), "> [1 2]\n> started over\n> []\n> ");
+		assert_eq!(session("repl", ":engine\n1\n:engine bytecode\n:stack\n:engine vm3\n"),
+			"> tree\n> [1]\n> switched to bytecode, started over\n> []\n> unknown engine: vm3\n> \n");
+		assert_eq!(session("repl", "1 2+\n:disasm\n:disasm 3\n"),
+			"> [3]\n> .fn 0                           ; 666\n\tpush 1\n\tpush 2\n\tplus\n\thalt\n> .fn 0                           ; 666\n\tpush 3\n\thalt\n> \n");
+		assert_eq!(session("repl -O 1", ":disasm 1 2+\n"), "> .fn 0                           ; 666\n\tpush 3\n\thalt\n> \n");
+		assert_eq!(session("repl", ":load examples/2plus2.false\n:load\n:frobnicate\n"),
+			"> 4\n[]\n> :load needs a file name\n> unknown command :frobnicate, see :help\n> \n");
+		assert!(session("repl", ":help\n").contains(":disasm [CODE]"));
+	}
+}
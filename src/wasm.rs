@@ -1,12 +1,11 @@
 pub mod falselang;
 #[cfg(target_arch = "wasm32")]
-use falselang::vm::FalseVM;
-// use std::time::Instant;
+use falselang::api::{self, RunOptions};
+#[cfg(target_arch = "wasm32")]
+use falselang::tokenizer::Dialect;
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
-#[cfg(target_arch = "wasm32")]
-use log::*;
 
 #[cfg(target_arch = "wasm32")]
 extern crate console_error_panic_hook;
@@ -14,32 +13,95 @@ extern crate console_error_panic_hook;
 use std::panic;
 
 
+/// Sends panics to the browser console, runs when the module is loaded.
 #[cfg(target_arch = "wasm32")]
-#[wasm_bindgen]
-pub fn wasm_main() {
+#[wasm_bindgen(start)]
+pub fn start() {
 	panic::set_hook(Box::new(console_error_panic_hook::hook));
-	let _ = console_log::init_with_level(Level::Debug);
-	info!("Hello from wasm_main!");
-
-	let mut vm = FalseVM::new();
-	info!("Parsing... ");
-	// let start = Instant::now();
-	// vm.load("   2 2 +  ");
-	// vm.load("[2 2+]![2 2+]!");
-	// vm.load("1[\"hello\"]? 0[\"hello\"]?");
-	// vm.load("[$1=$[\\%1\\]?~[$1-f;!*]?]f:    \"factorial of \" 5 $. \" is \" f;!.");  // factorial example
-	// vm.load("123.");
-	// vm.load("99b:\n[b;0=[\"No more bottles of beer\"]?b;1=[\"1 more bottle of beer\"]?b;1>[b;.\" bottles of beer\"]?]a:\n[b;0>][a;!\" on the wall\"10,a;!10,\"Take one down, pass it around\"10,b;1-b:a;!\" on the wall\n\"]#");
-	// vm.load("^^^,,,");
-	vm.load("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:       33 f;! $. {compute & print 33th fibonacci number}");
-	// info!("ok, {:?}", start.elapsed());
-	info!("ok");
-
-	info!("Running...");
-	// let start = Instant::now();
-	// vm.verbose = true;
-	vm.run();
-	// info!("\nRun complete, {:?}", start.elapsed());
-	info!("Run complete");
-	info!("{:?}", vm.stack);
+}
+
+/// Runs `code` to the end, `input` is what `^` reads. `options` may be left out or have any of
+/// `engine` ("tree" or "bytecode"), `dialect` ("rfalse" or "strict"), `optLevel` (0 to 3) and
+/// the limits `steps` (`api::DEFAULT_STEPS` if left out) and `stack`. Returns `{output, stack, variables, steps, error}`, see
+/// `RunResult::to_json`, and throws on invalid options.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn run(code: &str, input: &str, options: JsValue) -> Result<JsValue, JsValue> {
+	let options = run_options(&options).map_err(|e| JsValue::from_str(&e))?;
+	js_sys::JSON::parse(&api::run(code, input.as_bytes(), &options).to_json(code))
+}
+
+/// A program that runs a few steps at a time: `new Session(code, input, options)` with the same
+/// arguments as `run`, throws the syntax error like `check` returns it.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub struct Session(api::Session);
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+impl Session {
+	#[wasm_bindgen(constructor)]
+	pub fn new(code: &str, input: &str, options: JsValue) -> Result<Session, JsValue> {
+		let options = run_options(&options).map_err(|e| JsValue::from_str(&e))?;
+		match api::Session::new(code, input.as_bytes(), &options) {
+			Ok(session) => Ok(Session(session)),
+			Err(problem) => Err(js_sys::JSON::parse(&problem.to_json(code))?),
+		}
+	}
+
+	/// Runs up to `n` steps and returns the state like `run` does, with only the output since the
+	/// last call, `done`, and `pos`, `line` and `col` of the token that runs next.
+	pub fn step(&mut self, n: f64) -> Result<JsValue, JsValue> {
+		self.0.step(n as u64);
+		js_sys::JSON::parse(&self.0.state())
+	}
+}
+
+/// The tokens of `code` for highlighting, `{tokens, error}`, see `api::tokenize`.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn tokenize(code: &str, dialect: Option<String>) -> Result<JsValue, JsValue> {
+	js_sys::JSON::parse(&api::tokenize(code, dialect_of(dialect)?))
+}
+
+/// The syntax error in `code` as `{kind, message, pos, line, col}`, or `null`.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn check(code: &str, dialect: Option<String>) -> Result<JsValue, JsValue> {
+	js_sys::JSON::parse(&api::check(code, dialect_of(dialect)?))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn dialect_of(name: Option<String>) -> Result<Dialect, JsValue> {
+	name.map_or(Ok(Dialect::Rfalse), |name| name.parse().map_err(|e: String| JsValue::from_str(&e)))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn run_options(options: &JsValue) -> Result<RunOptions, String> {
+	let mut run = RunOptions::default();
+	if options.is_undefined() || options.is_null() {
+		return Ok(run);
+	}
+	let get = |name: &str| js_sys::Reflect::get(options, &JsValue::from_str(name)).ok().filter(|x| !x.is_undefined() && !x.is_null());
+	let string = |name: &str| match get(name) {
+		None => Ok(None),
+		Some(x) => x.as_string().map(Some).ok_or_else(|| format!("{} must be a string", name)),
+	};
+	let number = |name: &str| match get(name) {
+		None => Ok(None),
+		Some(x) => x.as_f64().filter(|n| n.fract() == 0.0 && *n >= 0.0).map(|n| Some(n as u64))
+			.ok_or_else(|| format!("{} must be a whole number", name)),
+	};
+	if let Some(engine) = string("engine")? {
+		run.engine = engine.parse()?;
+	}
+	if let Some(dialect) = string("dialect")? {
+		run.dialect = dialect.parse()?;
+	}
+	if let Some(level) = number("optLevel")? {
+		run.opt_level = u8::try_from(level).ok().filter(|n| *n <= 3).ok_or_else(|| format!("invalid opt level: {}", level))?;
+	}
+	run.limits.steps = number("steps")?;
+	run.limits.stack = number("stack")?.map(|n| n as usize);
+	Ok(run)
 }
@@ -0,0 +1,155 @@
+//! Runs the `rfalse` binary and checks its output and exit codes.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+
+/// Exit code, stdout and stderr of `rfalse args`, with `stdin` as input.
+fn rfalse_stderr(args: &[&str], stdin: &str) -> (i32, String, String) {
+	let mut child = Command::new(env!("CARGO_BIN_EXE_rfalse"))
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.unwrap();
+	child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+	let output = child.wait_with_output().unwrap();
+	let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+	(output.status.code().unwrap(), text(&output.stdout), text(&output.stderr))
+}
+
+/// Exit code and stdout of `rfalse args`.
+fn rfalse(args: &[&str], stdin: &str) -> (i32, String) {
+	let (code, stdout, _) = rfalse_stderr(args, stdin);
+	(code, stdout)
+}
+
+#[test]
+fn test_exit_codes() {
+	assert_eq!(rfalse(&["-e", "1 2+."], "").0, 0);
+	assert_eq!(rfalse(&["--engine", "bytecode", "-e", "1 0/"], "").0, 1);
+	assert_eq!(rfalse(&["--limits", "steps=100", "-e", "[1][]#"], "").0, 1);
+	assert_eq!(rfalse(&["--engine", "vm3"], "").0, 2);
+	assert_eq!(rfalse(&["no-such-file.false"], "").0, 2);
+	assert_eq!(rfalse(&["check", "-e", "[1"], "").0, 3);
+	assert_eq!(rfalse(&["check", "-"], "1 2+").0, 0);
+}
+
+#[test]
+fn test_commands() {
+	assert_eq!(rfalse(&["-e", "6 7*."], "").1, "42");
+	assert_eq!(rfalse(&["fmt", "-"], "1 2 + { sum } .").1, "1 2+.\n");
+	let (code, listing) = rfalse(&["disasm", "examples/hello.false"], "");
+	assert_eq!(code, 0);
+	assert!(!listing.is_empty());
+
+	let path = std::env::temp_dir().join(format!("rfalse-cli-{}.asm", std::process::id()));
+	let path = path.to_str().unwrap();
+	assert_eq!(rfalse(&["compile", "-e", "6 7*.", "-o", path], "").0, 0);
+	let (code, out) = rfalse(&["run", "--asm", path], "");
+	std::fs::remove_file(path).unwrap();
+	assert_eq!((code, out.as_str()), (0, "42"));
+}
+
+#[test]
+fn test_input() {
+	let fib = ["examples/fibonacci-iter.false", "--input-string", "10\n"];
+	assert_eq!(rfalse(&fib, "").1, "Enter N: 55");
+	assert_eq!(rfalse(&fib[..1], "10\n").1, "Enter N: 55");
+	assert_eq!(rfalse(&["-e", "^,^,", "-i", "-"], "xy").1, "xy");
+	assert_eq!(rfalse(&["-", "--input-string", "ok"], "^,^,").1, "ok");
+	assert_eq!(rfalse(&["-e", "^,", "-i", "no-such-file"], "").0, 2);
+	assert_eq!(rfalse(&["-", "-i", "-"], "").0, 2);
+	assert_eq!(rfalse(&["repl", "--input-string", "a"], "^\n").1, "> [97]\n> \n");
+}
+
+#[test]
+fn test_stats() {
+	for engine in ["tree", "bytecode"] {
+		let (code, out, err) = rfalse_stderr(&["--engine", engine, "--stats=json", "-e", "[1 2 3]!%%%4."], "");
+		assert_eq!((code, out.as_str()), (0, "4"), "{}", engine);
+		assert!(err.starts_with("{\"parse_us\":"), "{}: {}", engine, err);
+		assert!(err.contains("\"max_stack\":3,"), "{}: {}", engine, err);
+	}
+	let (code, out, err) = rfalse_stderr(&["--time", "-e", "1 0/"], "");
+	assert_eq!((code, out.as_str()), (1, ""));
+	assert!(err.contains("run:"), "{}", err);
+	assert!(err.contains("division by zero"), "{}", err);
+}
+
+#[test]
+fn test_repl() {
+	let (code, out) = rfalse(&["repl"], "1 2\n+\n");
+	assert_eq!(code, 0);
+	assert!(out.contains("[1 2]"));
+	assert!(out.contains("[3]"));
+
+	// `^` reads from the same stdin as the REPL
+	assert_eq!(rfalse(&["repl"], "^\nx\n").1, "> [120]\n> > \n");
+}
+
+#[test]
+fn test_profile() {
+	let path = std::env::temp_dir().join(format!("rfalse-cli-{}.folded", std::process::id()));
+	let path = path.to_str().unwrap();
+	for engine in ["tree", "bytecode"] {
+		let (code, out, err) = rfalse_stderr(&["--engine", engine, "--profile", "--folded", path, "examples/factorial.false"], "");
+		assert_eq!((code, out.as_str()), (0, "factorial of 5 is 120"), "{}", engine);
+		assert!(err.contains("f@1:1\n"), "{}: {}", engine, err);
+		let folded = std::fs::read_to_string(path).unwrap();
+		assert!(folded.lines().any(|line| line.starts_with("main;f@1:1;")), "{}: {}", engine, folded);
+	}
+	std::fs::remove_file(path).unwrap();
+	let (code, _, err) = rfalse_stderr(&["--profile", "-e", "[1 0/]f: f;!"], "");
+	assert_eq!(code, 1);
+	assert!(err.contains("f@1:1\n") && err.contains("division by zero"), "{}", err);
+}
+
+#[test]
+fn test_coverage() {
+	let path = std::env::temp_dir().join(format!("rfalse-cli-{}.info", std::process::id()));
+	let path = path.to_str().unwrap();
+	for engine in ["tree", "bytecode"] {
+		let (code, out, err) = rfalse_stderr(&["--engine", engine, "--coverage", "--lcov", path, "-e", "1[2]?\n0[3]?"], "");
+		assert_eq!((code, out.as_str()), (0, ""), "{}", engine);
+		assert!(err.contains("       1 | 0[3]?\n         |   ^ never ran\n         |     ^ taken 0, skipped 1\n"), "{}: {}", engine, err);
+		let lcov = std::fs::read_to_string(path).unwrap();
+		assert!(lcov.starts_with("TN:\nSF:-e\n") && lcov.contains("\nFNDA:0,lambda@2:2\n"), "{}: {}", engine, lcov);
+	}
+	std::fs::remove_file(path).unwrap();
+
+	// the optimizer keeps track of the tokens it merges
+	let (_, _, tree) = rfalse_stderr(&["--coverage", "examples/factorial.false"], "");
+	assert!(tree.ends_with("tokens 100.0% (32/32), lambdas 100.0% (3/3), branches 100.0% (4/4)\n"), "{}", tree);
+	for level in ["0", "1", "2"] {
+		let (_, _, err) = rfalse_stderr(&["--engine", "bytecode", "-O", level, "--coverage", "examples/factorial.false"], "");
+		assert_eq!(err, tree, "-O {}", level);
+	}
+	let (code, _, err) = rfalse_stderr(&["--engine", "bytecode", "-O", "3", "--coverage", "examples/factorial.false"], "");
+	assert_eq!(code, 2);
+	assert!(err.contains("--coverage doesn't work with -O 3"), "{}", err);
+}
+
+#[test]
+fn test_trace() {
+	let mut lambdas = vec![];
+	for engine in ["tree", "bytecode"] {
+		let (code, out, err) = rfalse_stderr(&["--engine", engine, "--trace", "-", "--trace-filter", "io", "-e", "[^,]f: f;! 1."], "a");
+		assert_eq!((code, out.as_str()), (0, "a1"), "{}", engine);
+		let lines: Vec<&str> = err.lines().collect();
+		assert_eq!(lines.len(), 3, "{}: {}", engine, err);
+		assert!(lines.iter().all(|line| line.starts_with("{\"step\":") && line.ends_with('}')), "{}: {}", engine, err);
+		lambdas.push(lines.iter().map(|line| line.split("\"lambda\":").nth(1).unwrap().split(',').next().unwrap()).collect::<Vec<_>>().join(" "));
+	}
+	assert_eq!(lambdas, ["\"f@1:1\" \"f@1:1\" \"main\"", "\"f@1:1\" \"f@1:1\" \"main\""]);
+
+	let path = std::env::temp_dir().join(format!("rfalse-cli-{}.jsonl", std::process::id()));
+	let path = path.to_str().unwrap();
+	let (code, _, err) = rfalse_stderr(&["--trace", path, "-e", "1 0/"], "");
+	assert_eq!(code, 1);
+	assert!(err.contains("division by zero"), "{}", err);
+	let trace = std::fs::read_to_string(path).unwrap();
+	assert!(trace.lines().last().unwrap().contains("\"error\":\"runtime error: division by zero\""), "{}", trace);
+	std::fs::remove_file(path).unwrap();
+}
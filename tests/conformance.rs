@@ -0,0 +1,154 @@
+//! Runs every case in `tests/conformance/` against every engine and dialect.
+//!
+//! A case is a `.case` file made of sections that start with a `--- name` line.
+//! Text before the first section is a description. Sections:
+//! - `program` the FALSE code, required
+//! - `stdin` input for `^`
+//! - `stdout` expected output, not checked if missing
+//! - `stack` expected data stack as numbers, bottom first, not checked if missing
+//! - `error` loading or running must fail with a message containing this text, any error if empty
+//! - `engines`, `dialects` names to run the case with, all of them if missing
+//!
+//! The last newline of a section belongs to the section header that follows it,
+//! so add an empty line to expect output that ends with a newline.
+
+use std::{collections::HashMap, panic};
+
+use rfalse::falselang::error::Error;
+use rfalse::falselang::interpreter::{Capture, Engine, Limits};
+use rfalse::falselang::tokenizer::Dialect;
+use rfalse::falselang::vm::StackElement;
+
+
+/// Steps per run, so that a broken engine can't hang the suite.
+const FUEL: u64 = 10_000_000;
+
+const SECTIONS: [&str; 7] = ["program", "stdin", "stdout", "stack", "error", "engines", "dialects"];
+
+fn parse_case(text: &str) -> Result<HashMap<String, String>, String> {
+	let mut sections = HashMap::new();
+	let mut current: Option<(String, String)> = None;
+	for line in text.split_inclusive('\n') {
+		if let Some(name) = line.strip_prefix("--- ") {
+			let name = name.trim().to_string();
+			if !SECTIONS.contains(&name.as_str()) {
+				return Err(format!("unknown section {:?}", name));
+			}
+			if let Some((name, body)) = current.take() {
+				sections.insert(name, body);
+			}
+			current = Some((name, String::new()));
+		} else if let Some((_, body)) = current.as_mut() {
+			body.push_str(line);
+		}
+	}
+	if let Some((name, body)) = current {
+		sections.insert(name, body);
+	}
+	for body in sections.values_mut() {
+		if body.ends_with('\n') {
+			body.pop();
+		}
+	}
+	if !sections.contains_key("program") {
+		return Err("no program section".to_string());
+	}
+	Ok(sections)
+}
+
+fn show_stack(stack: &[StackElement]) -> String {
+	let items: Vec<String> = stack.iter().map(|e| match e {
+		StackElement::Number(n) => n.to_string(),
+		other => format!("{:?}", other),
+	}).collect();
+	items.join(" ")
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+	if let Some(s) = payload.downcast_ref::<&str>() {
+		s.to_string()
+	} else if let Some(s) = payload.downcast_ref::<String>() {
+		s.clone()
+	} else {
+		"unknown panic".to_string()
+	}
+}
+
+/// Returns what went wrong, if anything.
+fn run_case(case: &HashMap<String, String>, engine: Engine, dialect: Dialect) -> Option<String> {
+	let out = Capture::default();
+	let input = case.get("stdin").cloned().unwrap_or_default();
+	let result = panic::catch_unwind(panic::AssertUnwindSafe(|| -> Result<Vec<StackElement>, Error> {
+		let mut vm = engine.create();
+		vm.set_dialect(dialect);
+		vm.set_limits(Limits { steps: Some(FUEL), stack: None });
+		vm.set_io(Box::new(std::io::Cursor::new(input.into_bytes())), Box::new(out.clone()));
+		vm.load(&case["program"], 0)?;
+		vm.run()?;
+		Ok(vm.data_stack())
+	}));
+	let result = match result {
+		Ok(result) => result,
+		Err(e) => return Some(format!("panicked: {}", panic_message(&*e))),
+	};
+	let stdout = out.text();
+
+	let stack = match (result, case.get("error")) {
+		(Ok(stack), None) => stack,
+		(Ok(stack), Some(_)) => return Some(format!("expected an error, finished with stack [{}]", show_stack(&stack))),
+		(Err(e), None) => return Some(format!("failed: {}", e)),
+		(Err(e), Some(expected)) => {
+			let message = e.to_string();
+			if !message.contains(expected.as_str()) {
+				return Some(format!("expected error containing {:?}, got {:?}", expected, message));
+			}
+			return None;
+		}
+	};
+	if let Some(expected) = case.get("stdout") {
+		if *expected != stdout {
+			return Some(format!("expected output {:?}, got {:?}", expected, stdout));
+		}
+	}
+	if let Some(expected) = case.get("stack") {
+		let expected = expected.split_whitespace().collect::<Vec<_>>().join(" ");
+		if expected != show_stack(&stack) {
+			return Some(format!("expected stack [{}], got [{}]", expected, show_stack(&stack)));
+		}
+	}
+	None
+}
+
+#[test]
+fn test_conformance() {
+	let mut paths: Vec<_> = std::fs::read_dir("tests/conformance").unwrap()
+		.map(|e| e.unwrap().path())
+		.filter(|p| p.extension().is_some_and(|e| e == "case"))
+		.collect();
+	paths.sort();
+	assert!(!paths.is_empty());
+
+	let mut failures = Vec::new();
+	let mut runs = 0;
+	for path in paths.iter() {
+		let name = path.file_stem().unwrap().to_string_lossy();
+		let case = match parse_case(&std::fs::read_to_string(path).unwrap()) {
+			Ok(case) => case,
+			Err(e) => {
+				failures.push(format!("{}: {}", name, e));
+				continue;
+			}
+		};
+		let selected = |section: &str, option: &str| case.get(section).is_none_or(|s| s.split_whitespace().any(|x| x == option));
+		for engine in Engine::ALL.iter().filter(|e| selected("engines", e.name())) {
+			for dialect in Dialect::ALL.iter().filter(|d| selected("dialects", d.name())) {
+				runs += 1;
+				if let Some(problem) = run_case(&case, *engine, *dialect) {
+					failures.push(format!("{} [{}, {}]: {}", name, engine.name(), dialect.name(), problem));
+				}
+			}
+		}
+	}
+
+	assert!(failures.is_empty(), "{} of {} runs failed:\n{}", failures.len(), runs, failures.join("\n"));
+}
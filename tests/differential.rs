@@ -0,0 +1,267 @@
+//! Runs the same programs through the tree-walking VM (`vm`) and the bytecode VM (`vm2`)
+//! and compares result, output, final data stack and variables.
+
+use std::collections::HashMap;
+
+use rfalse::falselang::error::Error;
+use rfalse::falselang::formatter::format;
+use rfalse::falselang::generator::Generator;
+use rfalse::falselang::interpreter::{Capture, Interpreter, StepResult};
+use rfalse::falselang::vm::{self, StackElement};
+use rfalse::falselang::vm2::{self, Instr};
+
+
+/// Something a program did that both engines have to agree on.
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+	Output(String),
+	Write(char, StackElement),
+}
+
+const VARIABLES: std::ops::RangeInclusive<char> = 'a'..='z';
+const MAX_STEPS: usize = 10_000_000;
+
+fn setup<T: Interpreter>(mut vm: T, code: &str, input: &str) -> (T, Capture) {
+	let out = Capture::default();
+	vm.set_io(Box::new(std::io::Cursor::new(input.as_bytes().to_vec())), Box::new(out.clone()));
+	vm.load(code, 0).unwrap();
+	(vm, out)
+}
+
+/// vm2 has no types, so lambdas and variable references are compared by what they compile to.
+/// `functions` is vm2's `fn_pointer`.
+fn same_value(functions: &HashMap<usize, usize>, tree: &Option<StackElement>, bytecode: &Option<StackElement>) -> bool {
+	let x = match bytecode {
+		Some(StackElement::Number(x)) => *x,
+		_ => return tree == bytecode,
+	};
+	match tree {
+		None => x == 0, // not initialized
+		Some(StackElement::Number(n)) => *n == x,
+		Some(StackElement::Lambda(n)) => functions.get(n).map(|addr| *addr as i64) == Some(x),
+		Some(StackElement::Variable(c)) => *c as i64 - 'a' as i64 == x,
+	}
+}
+
+/// Steps an engine until the end or the first error.
+fn step_to_end<T: Interpreter>(vm: &mut T) -> Result<(), Error> {
+	loop {
+		if vm.step()? == StepResult::End {
+			return Ok(());
+		}
+	}
+}
+
+/// Runs both engines to the end and returns a description of the first difference. vm2 also
+/// runs a second time by stepping, which has to end the same as its `run`.
+fn compare(code: &str, input: &str) -> Option<String> {
+	let (mut tree, tree_out) = setup(vm::FalseVM::new(), code, input);
+	let (mut bytecode, bytecode_out) = setup(vm2::FalseVM::new(), code, input);
+	let (mut stepped, stepped_out) = setup(vm2::FalseVM::new(), code, input);
+	let tree_result = Interpreter::run(&mut tree);
+	let bytecode_result = Interpreter::run(&mut bytecode);
+	let stepped_result = step_to_end(&mut stepped);
+
+	let mut problems = Vec::new();
+	if tree_result != bytecode_result {
+		problems.push(format!("result differs: tree {:?}, bytecode {:?}", tree_result, bytecode_result));
+	}
+	let stepped_vars: Vec<_> = VARIABLES.map(|v| Interpreter::variable(&stepped, v)).collect();
+	let bytecode_vars: Vec<_> = VARIABLES.map(|v| Interpreter::variable(&bytecode, v)).collect();
+	if (&stepped_result, stepped_out.text(), stepped.data_stack(), stepped_vars, stepped.cursor)
+		!= (&bytecode_result, bytecode_out.text(), bytecode.data_stack(), bytecode_vars, bytecode.cursor) {
+		problems.push(format!("bytecode run and step differ: run {:?} at address {}, step {:?} at address {}",
+			bytecode_result, bytecode.cursor, stepped_result, stepped.cursor));
+	}
+	if tree_out.text() != bytecode_out.text() {
+		problems.push(format!("output differs: tree {:?}, bytecode {:?}", tree_out.text(), bytecode_out.text()));
+	}
+	let (stack1, stack2) = (tree.data_stack(), bytecode.data_stack());
+	let same_stack = stack1.len() == stack2.len()
+		&& stack1.iter().zip(stack2.iter()).all(|(a, b)| same_value(&bytecode.fn_pointer, &Some(a.clone()), &Some(b.clone())));
+	if !same_stack {
+		problems.push(format!("stack differs: tree {:?}, bytecode {:?}", stack1, stack2));
+	}
+	for var in VARIABLES {
+		let (a, b) = (Interpreter::variable(&tree, var), Interpreter::variable(&bytecode, var));
+		if !same_value(&bytecode.fn_pointer, &a, &b) {
+			problems.push(format!("variable {} differs: tree {:?}, bytecode {:?}", var, a, b));
+		}
+	}
+	if problems.is_empty() {
+		return None;
+	}
+	problems.push(locate(code, input));
+	Some(problems.join("\n"))
+}
+
+/// Steps an engine to the end, recording events together with the instruction that caused them.
+fn trace<T: Interpreter>(mut vm: T, out: Capture, describe: impl Fn(&T) -> String) -> Vec<(Event, String)> {
+	let mut events = Vec::new();
+	let mut vars: Vec<Option<StackElement>> = VARIABLES.map(|v| vm.variable(v)).collect();
+	let mut written = 0;
+	for _ in 0..MAX_STEPS {
+		let location = describe(&vm);
+		let result = vm.step();
+
+		let text = out.text();
+		if text.len() > written {
+			events.push((Event::Output(text[written..].to_string()), location.clone()));
+			written = text.len();
+		}
+		for (i, var) in VARIABLES.enumerate() {
+			let value = vm.variable(var);
+			if value != vars[i] {
+				events.push((Event::Write(var, value.clone().unwrap()), location.clone()));
+				vars[i] = value;
+			}
+		}
+		if result != Ok(StepResult::OK) {
+			break;
+		}
+	}
+	events
+}
+
+/// Finds the first event the engines disagree on.
+fn locate(code: &str, input: &str) -> String {
+	let (tree, tree_out) = setup(vm::FalseVM::new(), code, input);
+	let (bytecode, bytecode_out) = setup(vm2::FalseVM::new(), code, input);
+	let functions = bytecode.fn_pointer.clone();
+
+	let events1 = trace(tree, tree_out, |vm| format!("fn {} token {} {:?}", vm.fn_index, vm.cursor, vm.peek_instruction()));
+	let events2 = trace(bytecode, bytecode_out, |vm| {
+		let instr = Instr::try_from(vm.memory[vm.cursor]).map(|i| i.mnemonic()).unwrap_or("?");
+		format!("address {} {}", vm.cursor, instr)
+	});
+
+	let same = |a: &Event, b: &Event| match (a, b) {
+		(Event::Write(v1, x1), Event::Write(v2, x2)) => v1 == v2 && same_value(&functions, &Some(x1.clone()), &Some(x2.clone())),
+		_ => a == b,
+	};
+
+	for i in 0..events1.len().max(events2.len()) {
+		match (events1.get(i), events2.get(i)) {
+			(Some((a, _)), Some((b, _))) if same(a, b) => continue,
+			(a, b) => {
+				let show = |e: Option<&(Event, String)>| match e {
+					Some((event, location)) => format!("{:?} at {}", event, location),
+					None => "nothing".to_string(),
+				};
+				return format!("first divergence at event {}: tree did {}, bytecode did {}", i, show(a), show(b));
+			}
+		}
+	}
+	"no divergence in output or variable writes, only in the final state".to_string()
+}
+
+fn check(code: &str, input: &str) {
+	if let Some(problem) = compare(code, input) {
+		panic!("engines disagree on {:?}:\n{}", code, problem);
+	}
+}
+
+const SNIPPETS: [&str; 25] = [
+	"",
+	"2 2+",
+	"1 2 3 4 5",
+	"'a    'b         'c'd",
+	"2 4$ 1 2 3% 1 2 \\ 0 1 2 3 @",
+	"7 8 9 2ø 7 8 9 0ø 7 8 9 2P",
+	"1 2- 3 4* 9 2/ 7_ 2/ 2 3> 3 2> 4 4= 4 5= 2 3< 3 2<",
+	"7_ 2/ 7 2_/ 7_ 2_/ 3 1& 3 1| 5~ 0~",
+	"1[777]?  0[333]?  2 2+ 4=[777]?",
+	"50 f: 1 f; + a b c",
+	"[1 +]f: 50 f;! f;",
+	"[$1=$[\\%1\\]?~[$1-f;!*]?]f:    6 f;!",
+	"[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 12 f;!",
+	"10 [$5 >][1 -]#",
+	"0[$3 1+>][1 1++]#",
+	"0i:[i;10 2*>][i;1+i:i;$*.\" \"]#",
+	"\"hello\" 10, 2 3+. 'x, \"multi\nline\" 0 1_ .",
+	"3[$0>][$.1 0+-]#%",
+	"0 3[$][1- \\ 2[$][1- \\ 1+ \\]# % \\]#%",
+	"[[[1]!2]!3]! [[4]]!! ",
+	"[$0>[1-g;!]?]f: [$.f;!]g: 5f;!",
+	"^^^ ,,, ^",
+	"[^$1_=~][,]# %",
+	"{comment} 1 {another [one]} 2 ß",
+	"[f;! 1]f: f;!",
+];
+
+/// Programs the engines are known to disagree on, with the value each one leaves on the stack.
+/// The tree VM computes with i64 and vm2 with i32 memory cells, so results outside of the
+/// i32 range only wrap in vm2.
+const DIVERGENCES: [(&str, i64, i64); 3] = [
+	("2147483647 1+", 2147483648, -2147483648),
+	("65536 65536*", 4294967296, 0),
+	("2147483647_ 2-", -2147483649, 2147483647),
+];
+
+const EXAMPLES: [(&str, &str); 10] = [
+	("2plus2", ""),
+	("bottles-of-beer", ""),
+	("factorial", ""),
+	("fibonacci", ""),
+	("fibonacci-iter", "20\n"),
+	("hello", ""),
+	("primes", ""),
+	("ptr", ""),
+	("while-in-while", ""),
+	("while", ""),
+];
+
+#[test]
+fn test_snippets() {
+	for code in SNIPPETS {
+		check(code, "abc");
+	}
+}
+
+#[test]
+fn test_divergences() {
+	for (code, tree, bytecode) in DIVERGENCES {
+		assert!(compare(code, "").is_some(), "engines agree on {:?} now, remove it from DIVERGENCES", code);
+		let (mut vm, _) = setup(vm::FalseVM::new(), code, "");
+		Interpreter::run(&mut vm).unwrap();
+		assert_eq!(vm.data_stack(), vec![StackElement::Number(tree)], "tree engine on {:?}", code);
+		let (mut vm, _) = setup(vm2::FalseVM::new(), code, "");
+		Interpreter::run(&mut vm).unwrap();
+		assert_eq!(vm.data_stack(), vec![StackElement::Number(bytecode)], "bytecode engine on {:?}", code);
+	}
+}
+
+#[test]
+fn test_examples() {
+	let mut names: Vec<String> = std::fs::read_dir("examples").unwrap()
+		.map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+		.filter(|n| n.ends_with(".false"))
+		.collect();
+	names.sort();
+	let mut known: Vec<String> = EXAMPLES.iter().map(|(name, _)| format!("{}.false", name)).collect();
+	known.sort();
+	assert_eq!(names, known, "examples/ changed, update EXAMPLES");
+
+	for (name, input) in EXAMPLES {
+		let code = std::fs::read_to_string(format!("examples/{}.false", name)).unwrap();
+		if let Some(problem) = compare(&code, input) {
+			panic!("engines disagree on examples/{}.false:\n{}", name, problem);
+		}
+	}
+}
+
+#[test]
+fn test_generated() {
+	let mut generator = Generator::new(39);
+	for _ in 0..500 {
+		check(&format(&generator.program()), "abc");
+	}
+}
+
+#[test]
+fn test_reports_divergence() {
+	// `,` of a value above 255 isn't defined, vm2 only writes the low byte
+	let problem = compare("1a: 65, 321, 2a:", "").unwrap();
+	assert!(problem.contains("output differs: tree \"AŁ\", bytecode \"AA\""), "{}", problem);
+	assert!(problem.contains("first divergence at event 2: tree did Output(\"Ł\") at fn 0 token 6 Some(WriteChar), bytecode did Output(\"A\") at address 676 writechar"), "{}", problem);
+}
@@ -0,0 +1,39 @@
+//! Replays the inputs in `fuzz/regressions/<target>/` through the fuzz entry points,
+//! so crashers found by `cargo fuzz run <target>` stay fixed.
+
+use rfalse::falselang::fuzz;
+
+
+fn replay(target: &str, run: fn(&[u8])) {
+	let dir = format!("fuzz/regressions/{}", target);
+	let mut count = 0;
+	for entry in std::fs::read_dir(&dir).unwrap() {
+		let path = entry.unwrap().path();
+		let data = std::fs::read(&path).unwrap();
+		if std::panic::catch_unwind(|| run(&data)).is_err() {
+			panic!("{} panicked on {}", target, path.display());
+		}
+		count += 1;
+	}
+	assert!(count > 0, "{} is empty", dir);
+}
+
+#[test]
+fn test_tokenizer() {
+	replay("tokenizer", fuzz::fuzz_tokenizer);
+}
+
+#[test]
+fn test_parser() {
+	replay("parser", fuzz::fuzz_parser);
+}
+
+#[test]
+fn test_vm() {
+	replay("vm", fuzz::fuzz_vm);
+}
+
+#[test]
+fn test_vm2() {
+	replay("vm2", fuzz::fuzz_vm2);
+}
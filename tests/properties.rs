@@ -0,0 +1,93 @@
+//! Properties that have to hold for any program, checked on programs from `generator`.
+//! vm1 and vm2 agreeing is checked in `differential.rs`.
+
+use std::collections::BTreeMap;
+
+use rfalse::falselang::formatter::format;
+use rfalse::falselang::generator::Generator;
+use rfalse::falselang::interpreter::{Capture, StepResult};
+use rfalse::falselang::threaded::ThreadedCode;
+use rfalse::falselang::{vm, vm2};
+
+
+fn programs(seed: u64, count: usize) -> impl Iterator<Item = String> {
+	let mut generator = Generator::new(seed);
+	(0..count).map(move |_| format(&generator.program()))
+}
+
+/// Output and final stack of vm2 at `opt_level`, run with `engine` (0 step, 1 threaded, 2 cached).
+fn run_vm2(code: &str, opt_level: u8, engine: usize) -> (String, Vec<i32>) {
+	let out = Capture::default();
+	let mut vm = vm2::FalseVM::new();
+	vm.input = Box::new(std::io::Cursor::new(b"abc".to_vec()));
+	vm.output = Box::new(out.clone());
+	vm.load(code, opt_level).unwrap();
+	match engine {
+		0 => vm.run(),
+		1 => ThreadedCode::new(&vm).run(&mut vm),
+		_ => ThreadedCode::new(&vm).run_cached(&mut vm),
+	}.unwrap();
+	(out.text(), vm.memory[vm.stack_pointer..].to_vec())
+}
+
+#[test]
+fn test_optimizer_preserves_output() {
+	for code in programs(1, 100) {
+		let expected = run_vm2(&code, 0, 0);
+		for opt_level in 0..=3 {
+			for engine in 0..3 {
+				assert_eq!(run_vm2(&code, opt_level, engine), expected, "opt level {}, engine {}: {}", opt_level, engine, code);
+			}
+		}
+	}
+}
+
+/// Takes a snapshot after `steps` steps and runs to the end, then restores it and runs to the end
+/// again. Returns output after the snapshot and final state of both runs.
+fn resume_twice<VM, S>(
+	vm: &mut VM,
+	steps: usize,
+	step: impl Fn(&mut VM) -> StepResult,
+	snapshot: impl Fn(&VM) -> S,
+	restore: impl Fn(&mut VM, &S),
+	set_output: impl Fn(&mut VM, Capture),
+	stack: impl Fn(&VM) -> String,
+) -> [(String, String); 2] {
+	let ended = (0..steps).any(|_| step(vm) == StepResult::End);
+	let saved = snapshot(vm);
+	[false, true].map(|again| {
+		if again {
+			restore(vm, &saved);
+		}
+		let out = Capture::default();
+		set_output(vm, out.clone());
+		if !ended {
+			while step(vm) == StepResult::OK {}
+		}
+		(out.text(), stack(vm))
+	})
+}
+
+#[test]
+fn test_snapshot_restore() {
+	for (i, code) in programs(2, 300).enumerate() {
+		// `^` reads the same whatever was read before
+		let steps = i * 7 % 300;
+
+		let mut tree = vm::FalseVM::new();
+		tree.input = Box::new(std::io::repeat(b'x'));
+		tree.output = Box::new(std::io::sink());
+		tree.load(&code).unwrap();
+		let [a, b] = resume_twice(&mut tree, steps, |vm| vm.step().unwrap(), vm::FalseVM::snapshot,
+			vm::FalseVM::restore, |vm, out| vm.output = Box::new(out), |vm| format!("{:?} {:?}", vm.stack, vm.variables.iter().collect::<BTreeMap<_, _>>()));
+		assert_eq!(a, b, "tree, {} steps: {}", steps, code);
+
+		let mut bytecode = vm2::FalseVM::new();
+		bytecode.input = Box::new(std::io::repeat(b'x'));
+		bytecode.output = Box::new(std::io::sink());
+		bytecode.load(&code, 3).unwrap();
+		let [a, b] = resume_twice(&mut bytecode, steps, |vm| vm.step().unwrap(), vm2::FalseVM::snapshot,
+			vm2::FalseVM::restore, |vm, out| vm.output = Box::new(out), |vm| format!("{:?} {:?}", &vm.memory[vm.stack_pointer..], &vm.memory[..26]));
+		assert_eq!(a, b, "bytecode, {} steps: {}", steps, code);
+	}
+}